@@ -4,10 +4,10 @@ use copperline::Copperline;
 
 fn main() {
 
-    let cfg = copperline::Config {
-        encoding: copperline::Encoding::Utf8,
-        mode: copperline::EditMode::Vi
-    };
+    let cfg = copperline::Config::builder()
+        .encoding(copperline::Encoding::Utf8)
+        .mode(copperline::EditMode::Vi)
+        .build();
 
     let mut cl = Copperline::new();
     while let Ok(line) = cl.read_line(">> ", &cfg) {
@@ -6,55 +6,272 @@ use unicode_width::UnicodeWidthStr;
 use unicode_segmentation::UnicodeSegmentation;
 
 use builder::Builder;
+use edit::WrapMode;
 
 #[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
 pub struct Position {
     byte_pos: usize,
-    char_pos: usize
+    /// The number of graphemes before the position.
+    char_pos: usize,
+    /// The number of columns the text before the position takes up on
+    /// the terminal, which is two for each wide character.
+    col: usize
 }
 
 impl Position {
     pub fn new() -> Position {
         Position {
             byte_pos: 0,
-            char_pos: 0
+            char_pos: 0,
+            col: 0
+        }
+    }
+
+    /// The position at the byte offset byte_pos of buf.
+    fn at(buf: &str, byte_pos: usize) -> Position {
+        let before = &buf[..byte_pos];
+        Position {
+            byte_pos: byte_pos,
+            char_pos: before.graphemes(true).count(),
+            col: UnicodeWidthStr::width(before)
+        }
+    }
+
+    /// The position after the grapheme g, which follows this position.
+    fn after(&self, g: &str) -> Position {
+        Position {
+            byte_pos: self.byte_pos + g.len(),
+            char_pos: self.char_pos + 1,
+            col: self.col + UnicodeWidthStr::width(g)
+        }
+    }
+
+    /// The position before the grapheme g, which precedes this position.
+    fn before(&self, g: &str) -> Position {
+        Position {
+            byte_pos: self.byte_pos - g.len(),
+            char_pos: self.char_pos - 1,
+            col: self.col - UnicodeWidthStr::width(g)
         }
     }
 
     pub fn reset(&mut self) {
-        self.byte_pos = 0;
-        self.char_pos = 0;
+        *self = Position::new();
     }
 
     pub fn set_to_end_of_str(&mut self, buf: &str) {
-        self.byte_pos = buf.len();
-        self.char_pos = UnicodeWidthStr::width(buf);
+        *self = Position::at(buf, buf.len());
+    }
+
+}
+
+/// The part of a line that is shown after the prompt when the line is
+/// scrolled horizontally.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Window {
+    /// The column of the line that is shown first.
+    pub offset: usize,
+    /// The number of columns that are available to the line.
+    columns: usize,
+    /// The byte range of the part of the line that is shown.
+    start: usize,
+    end: usize,
+    /// The column of the cursor within the window.
+    cursor: usize
+}
+
+/// The rows that a line is shown on when it is wrapped at the edge of the
+/// terminal, rather than scrolled horizontally.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Wrapped {
+    /// The byte range of the line that is shown on each row.
+    rows: Vec<(usize, usize)>,
+    /// The row and the column of the cursor, counting from the row of the
+    /// prompt.
+    pub cursor: (usize, usize),
+    /// The column that the line ends at on its last row.
+    end: usize
+}
+
+impl Wrapped {
+
+    /// The row that the line ends on, counting from the row of the prompt.
+    pub fn last_row(&self) -> usize {
+        self.rows.len() - 1
+    }
+
+    /// Move the cursor from where the line left it back up to the row of
+    /// the prompt, from which the line can be redrawn.
+    pub fn get_first_row(&self) -> Vec<u8> {
+        let mut line = Builder::new();
+        if self.cursor.0 > 0 {
+            line.move_up(self.cursor.0);
+        }
+        line.build()
+    }
+
+    /// Show text on the row below the line, leaving the cursor where it
+    /// is. Empty text erases that row.
+    pub fn get_info(&self, info: &str) -> Vec<u8> {
+        let below = self.last_row() - self.cursor.0;
+        let mut line = Builder::new();
+        if below > 0 {
+            line.move_down(below);
+        }
+        line.newline();
+        line.append(info);
+        line.erase_to_right();
+        line.move_up(below + 1);
+        set_col(&mut line, self.cursor.1);
+        line.build()
     }
 
-    pub fn increase_by_char(&mut self, c: char) {
-        self.byte_pos += c.len_utf8();
-        self.char_pos += 1;
+    /// Note that the cursor was moved to the end of the line, as by
+    /// `get_trailer`.
+    pub fn move_to_end(&mut self) {
+        self.cursor = (self.last_row(), self.end);
+    }
+
+    /// Show text after the end of the line, leaving the cursor after it.
+    pub fn get_trailer(&self, text: &str) -> Vec<u8> {
+        let below = self.last_row() - self.cursor.0;
+        let mut line = Builder::new();
+        if below > 0 {
+            line.move_down(below);
+        }
+        set_col(&mut line, self.end);
+        line.append(text);
+        line.build()
+    }
+
+}
+
+/// The row and the column that the text at byte_pos is shown at, where
+/// a position at the edge of a row is shown at the start of the next.
+fn row_col(text: &str, rows: &[(usize, usize)], prompt_width: usize, width: usize, byte_pos: usize) -> (usize, usize) {
+    let row = rows.iter().rposition(|&(start, _)| start <= byte_pos).unwrap_or(0);
+    let (start, end) = rows[row];
+    let indent = if row == 0 { prompt_width } else { 0 };
+    let col = indent + UnicodeWidthStr::width(&text[start..byte_pos.min(end)]);
+    if col >= width {
+        (row + 1, 0)
+    } else {
+        (row, col)
+    }
+}
+
+/// Move the cursor to a column of the row it is on.
+fn set_col(line: &mut Builder, col: usize) {
+    if col == 0 {
+        line.carriage_return();
+    } else {
+        line.set_cursor_pos(col);
+    }
+}
+
+/// The number of columns a prompt takes up on the terminal.
+///
+/// SGR sequences (`\x1b[...m`), as used for colors, take up no space.
+pub fn visible_width(prompt: &str) -> usize {
+    let mut visible = String::with_capacity(prompt.len());
+    let mut rest = prompt;
+    while let Some(start) = rest.find("\x1b[") {
+        visible.push_str(&rest[..start]);
+        let params = &rest[start + 2..];
+        match params.find(|c: char| !(c.is_digit(10) || c == ';')) {
+            Some(end) if params[end..].starts_with('m') => rest = &params[end + 1..],
+            _ => {
+                visible.push_str(&rest[start..start + 2]);
+                rest = params;
+            }
+        }
     }
+    visible.push_str(rest);
+    UnicodeWidthStr::width(visible.as_str())
+}
 
-    pub fn decrease_by_str(&mut self, buf: &str) {
-        self.byte_pos -= buf.len();
-        self.char_pos -= UnicodeWidthStr::width(buf);
+/// Split a prompt into the lines shown above the input, and the line
+/// the input is on.
+pub fn split_prompt(prompt: &str) -> (&str, &str) {
+    match prompt.rfind('\n') {
+        Some(idx) => (&prompt[..idx + 1], &prompt[idx + 1..]),
+        None => ("", prompt)
     }
+}
 
+/// A copy of the buffer contents and cursor position, used to undo changes.
+pub struct Snapshot {
+    text: String,
+    pos: Position
+}
+
+impl Snapshot {
+    pub fn as_str(&self) -> &str {
+        self.text.as_str()
+    }
 }
 
+#[derive(Clone)]
 pub struct Buffer {
     front_buf: String,
     back_buf: String,
-    pos: Position
+    pos: Position,
+    /// Characters that are part of keywords in addition to the default ones.
+    word_chars: String
 }
 
 impl Buffer {
     pub fn new() -> Buffer {
+        Buffer::with_capacity(0)
+    }
+
+    /// Create a buffer that holds a line of capacity bytes without reallocating.
+    pub fn with_capacity(capacity: usize) -> Buffer {
         Buffer {
-            front_buf: String::new(),
-            back_buf: String::new(),
-            pos: Position::new()
+            front_buf: String::with_capacity(capacity),
+            back_buf: String::with_capacity(capacity),
+            pos: Position::new(),
+            word_chars: String::new()
+        }
+    }
+
+    /// Treat the characters of extra as keyword characters for word movements.
+    pub fn set_word_chars(&mut self, extra: &str) {
+        self.word_chars = extra.to_owned();
+    }
+
+    fn is_keyword(&self, c: char) -> bool {
+        is_vi_keyword(c) || self.word_chars.contains(c)
+    }
+
+    /// Whether the grapheme left of the cursor is part of a keyword.
+    pub fn is_after_keyword(&self) -> bool {
+        match self.front_buf[..self.pos.byte_pos].graphemes(true).next_back() {
+            Some(g) => g.chars().next().map_or(false, |c| self.is_keyword(c)),
+            None => false
+        }
+    }
+
+    /// The byte range and text of the word the cursor is on, for example
+    /// to know what to complete.
+    ///
+    /// A word is a run of keyword characters. If the cursor is not on one,
+    /// the word that ends directly before the cursor is returned, so the
+    /// word being typed is found at the end of it. Otherwise, such as in
+    /// whitespace, there is no current word.
+    pub fn current_word(&self) -> Option<(usize, usize, &str)> {
+        let is_keyword = |g: &str| g.chars().next().map_or(false, |c| self.is_keyword(c));
+        let cursor = self.pos.byte_pos;
+        let start = self.front_buf[..cursor].grapheme_indices(true).rev()
+            .take_while(|&(_, g)| is_keyword(g))
+            .last().map_or(cursor, |(idx, _)| idx);
+        let end = self.front_buf[cursor..].grapheme_indices(true)
+            .find(|&(_, g)| !is_keyword(g))
+            .map_or(self.front_buf.len(), |(idx, _)| cursor + idx);
+        if start == end {
+            None
+        } else {
+            Some((start, end, &self.front_buf[start..end]))
         }
     }
 
@@ -69,26 +286,114 @@ impl Buffer {
         self.pos.set_to_end_of_str(s);
     }
 
+    /// A copy of the line with every grapheme replaced by the mask, or
+    /// without any text if there is no mask, to show input that must not
+    /// be echoed.
+    pub fn masked(&self, mask: Option<char>) -> Buffer {
+        let mut buf = Buffer::new();
+        if let Some(mask) = mask {
+            let before = self.front_buf[..self.pos.byte_pos].graphemes(true).count();
+            let after = self.front_buf[self.pos.byte_pos..].graphemes(true).count();
+            let masks = |n: usize| (0..n).map(|_| mask).collect::<String>();
+            buf.insert_chars_at_cursor(&masks(before + after));
+            buf.move_start();
+            for _ in 0..before {
+                buf.move_right();
+            }
+        }
+        buf
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            text: self.front_buf.clone(),
+            pos: self.pos
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.front_buf = snapshot.text;
+        self.pos = snapshot.pos;
+    }
+
     pub fn insert_char_at_cursor(&mut self, c: char) {
         self.front_buf.insert(self.pos.byte_pos, c);
-        self.pos.increase_by_char(c);
+        self.pos = Position::at(&self.front_buf, self.pos.byte_pos + c.len_utf8());
     }
 
     pub fn insert_chars_at_cursor(&mut self, s: &str) {
-        for c in s.chars() {
-            self.insert_char_at_cursor(c);
-        }
+        self.front_buf.insert_str(self.pos.byte_pos, s);
+        self.pos = Position::at(&self.front_buf, self.pos.byte_pos + s.len());
+    }
+
+    /// The number of graphemes from the cursor to the end of the line.
+    pub fn graphemes_right_of_cursor(&self) -> usize {
+        self.front_buf[self.pos.byte_pos..].graphemes(true).count()
     }
 
     pub fn replace_chars_at_cursor(&mut self, s: &str) {
         self.delete_char_right_of_cursor();
+        let pos = self.pos.byte_pos;
         self.insert_chars_at_cursor(s);
-        self.pos.decrease_by_str(s);
+        self.pos = Position::at(&self.front_buf, pos);
     }
 
+    /// Swap the case of the grapheme under the cursor and move past it.
+    ///
+    /// Returns false if there is no grapheme under the cursor.
+    pub fn toggle_case_at_cursor(&mut self) -> bool {
+        let end = match self.next_pos() {
+            Some(pos) => pos.byte_pos,
+            None => return false
+        };
+        let mut toggled = String::new();
+        for c in self.front_buf[self.pos.byte_pos..end].chars() {
+            if c.is_uppercase() {
+                toggled.extend(c.to_lowercase());
+            } else if c.is_lowercase() {
+                toggled.extend(c.to_uppercase());
+            } else {
+                toggled.push(c);
+            }
+        }
+        let start = self.pos.byte_pos;
+        self.front_buf.drain(start..end);
+        self.front_buf.insert_str(start, &toggled);
+        self.pos = Position::at(&self.front_buf, start + toggled.len());
+        true
+    }
+
+    /// Convert the whole line to uppercase.
+    pub fn uppercase_line(&mut self) {
+        let text = self.front_buf.to_uppercase();
+        self.replace_keeping_cursor(text);
+    }
+
+    /// Convert the whole line to lowercase.
+    pub fn lowercase_line(&mut self) {
+        let text = self.front_buf.to_lowercase();
+        self.replace_keeping_cursor(text);
+    }
+
+    /// Replace the line, keeping the cursor after the same number of
+    /// graphemes, or at the end if the line got shorter.
+    fn replace_keeping_cursor(&mut self, text: String) {
+        let graphemes = self.front_buf[..self.pos.byte_pos].graphemes(true).count();
+        self.front_buf = text;
+        self.pos.reset();
+        for _ in 0..graphemes {
+            if !self.move_right() {
+                break;
+            }
+        }
+    }
+
+    /// Delete the grapheme left of the cursor, including any combining
+    /// characters.
     pub fn delete_char_left_of_cursor(&mut self) -> bool {
+        let end = self.pos.byte_pos;
         if self.move_left() {
-            self.front_buf.remove(self.pos.byte_pos);
+            self.front_buf.drain(self.pos.byte_pos..end);
             true
         }
         else {
@@ -96,12 +401,15 @@ impl Buffer {
         }
     }
 
+    /// Delete the grapheme right of the cursor, including any combining
+    /// characters.
     pub fn delete_char_right_of_cursor(&mut self) -> bool {
-        if self.pos.byte_pos < self.front_buf.len() {
-            self.front_buf.remove(self.pos.byte_pos);
-            return true;
-        } else {
-            return false;
+        match self.next_pos() {
+            Some(next) => {
+                self.front_buf.drain(self.pos.byte_pos..next.byte_pos);
+                true
+            }
+            None => false
         }
     }
 
@@ -110,10 +418,7 @@ impl Buffer {
             None
         } else {
             match UnicodeSegmentation::graphemes(self.front_buf.as_str(), true).nth(self.pos.char_pos - 1) {
-                Some(prev) => Some(Position {
-                    byte_pos: self.pos.byte_pos - prev.len(),
-                    char_pos: self.pos.char_pos - 1
-                }),
+                Some(prev) => Some(self.pos.before(prev)),
                 None => None
             }
         }
@@ -131,14 +436,15 @@ impl Buffer {
 
     fn next_pos(&self) -> Option<Position> {
         match UnicodeSegmentation::graphemes(self.front_buf.as_str(), true).nth(self.pos.char_pos) {
-            Some(next) => Some(Position {
-                byte_pos: self.pos.byte_pos + next.len(),
-                char_pos: self.pos.char_pos + 1
-            }),
+            Some(next) => Some(self.pos.after(next)),
             None => None
         }
     }
 
+    fn grapheme_after(&self) -> Option<&str> {
+        UnicodeSegmentation::graphemes(self.front_buf.as_str(), true).nth(self.pos.char_pos)
+    }
+
     fn cp_after(&self) -> Option<char> {
         match UnicodeSegmentation::graphemes(self.front_buf.as_str(), true).nth(self.pos.char_pos) {
             Some(next) => next.chars().next(),
@@ -194,7 +500,7 @@ impl Buffer {
             None => return false,
             Some(c) => match c {
                 c if c.is_whitespace() => State::Whitespace,
-                c if is_vi_keyword(c) => State::Keyword,
+                c if self.is_keyword(c) => State::Keyword,
                 _ => State::NonKeyword,
             },
         };
@@ -213,14 +519,14 @@ impl Buffer {
                 State::Keyword => match c {
                     c if c.is_whitespace() => state = State::Whitespace,
                     c if move_mode == ViMoveMode::Keyword
-                        && !is_vi_keyword(c)
+                        && !self.is_keyword(c)
                     => return true,
                     _ => {}
                 },
                 State::NonKeyword => match c {
                     c if c.is_whitespace() => state = State::Whitespace,
                     c if move_mode == ViMoveMode::Keyword
-                        && is_vi_keyword(c)
+                        && self.is_keyword(c)
                     => return true,
                     _ => {}
                 },
@@ -233,6 +539,101 @@ impl Buffer {
         self.pos.set_to_end_of_str(self.front_buf.as_str());
     }
 
+    /// Whether a character is part of a word for the Emacs word movements,
+    /// which unlike vi keywords do not include punctuation such as `_`.
+    fn is_emacs_word(&self, c: char) -> bool {
+        c.is_alphanumeric() || self.word_chars.contains(c)
+    }
+
+    fn cp_before(&self) -> Option<char> {
+        self.front_buf[..self.pos.byte_pos].graphemes(true).next_back().and_then(|g| g.chars().next())
+    }
+
+    /// Move to the end of the next word, as readline's `forward-word`.
+    ///
+    /// Anything that is not a word character is skipped first, then the
+    /// word itself. Returns whether the cursor moved.
+    pub fn emacs_move_word_forward(&mut self) -> bool {
+        let start = self.pos;
+        while self.cp_after().map_or(false, |c| !self.is_emacs_word(c)) {
+            self.move_right();
+        }
+        while self.cp_after().map_or(false, |c| self.is_emacs_word(c)) {
+            self.move_right();
+        }
+        self.pos != start
+    }
+
+    /// Move to the start of the previous word, as readline's `backward-word`.
+    ///
+    /// Returns whether the cursor moved.
+    pub fn emacs_move_word_backward(&mut self) -> bool {
+        let start = self.pos;
+        while self.cp_before().map_or(false, |c| !self.is_emacs_word(c)) {
+            self.move_left();
+        }
+        while self.cp_before().map_or(false, |c| self.is_emacs_word(c)) {
+            self.move_left();
+        }
+        self.pos != start
+    }
+
+    /// Move to the last character that is not whitespace.
+    pub fn move_last_non_blank(&mut self) {
+        self.move_end();
+        while self.move_left() {
+            match self.cp_after() {
+                Some(c) if c.is_whitespace() => {}
+                _ => break
+            }
+        }
+    }
+
+    /// The span of the word under the cursor, as covered by the vi text
+    /// objects `iw` and `aw`, or `iW` and `aW` if `ws` is set.
+    ///
+    /// A word is a run of keyword characters, of other non-blank
+    /// characters or of whitespace. With `around`, the whitespace after
+    /// the word is included, or if there is none the whitespace before
+    /// it. On whitespace, the word after it is included instead.
+    pub fn word_object(&self, around: bool, ws: bool) -> Option<(Position, Position)> {
+        let graphemes: Vec<(usize, &str)> =
+            UnicodeSegmentation::grapheme_indices(self.front_buf.as_str(), true).collect();
+        let class = |i: usize| match graphemes[i].1.chars().next() {
+            Some(c) if c.is_whitespace() => 0,
+            Some(c) if ws || self.is_keyword(c) => 1,
+            _ => 2,
+        };
+        let len = graphemes.len();
+        let i = self.pos.char_pos;
+        if i >= len {
+            return None;
+        }
+        let cur = class(i);
+        let mut start = i;
+        while start > 0 && class(start - 1) == cur {
+            start -= 1;
+        }
+        let mut end = i + 1;
+        while end < len && class(end) == cur {
+            end += 1;
+        }
+        if around {
+            if end < len && (cur == 0 || class(end) == 0) {
+                let next = class(end);
+                while end < len && class(end) == next {
+                    end += 1;
+                }
+            } else if cur != 0 {
+                while start > 0 && class(start - 1) == 0 {
+                    start -= 1;
+                }
+            }
+        }
+        let position = |i: usize| Position::at(&self.front_buf, graphemes.get(i).map_or(self.front_buf.len(), |g| g.0));
+        Some((position(start), position(end)))
+    }
+
     pub fn move_to_end_of_word(&mut self) -> bool {
         self.vi_move_word_end(ViMoveMode::Keyword, ViMoveDir::Right)
     }
@@ -274,7 +675,7 @@ impl Buffer {
                     c if c.is_whitespace() => {},
                     // if we are in keyword mode and found a keyword, stop on word
                     c if move_mode == ViMoveMode::Keyword
-                        && is_vi_keyword(c) =>
+                        && self.is_keyword(c) =>
                     {
                         state = State::EndOnWord;
                     },
@@ -287,7 +688,7 @@ impl Buffer {
                         state = State::EndOnOther;
                     }
                 },
-                State::EndOnWord if !is_vi_keyword(c) => {
+                State::EndOnWord if !self.is_keyword(c) => {
                     direction.go_back(self);
                     return true;
                 },
@@ -295,7 +696,7 @@ impl Buffer {
                     direction.go_back(self);
                     return true;
                 },
-                State::EndOnOther if c.is_whitespace() || is_vi_keyword(c) => {
+                State::EndOnOther if c.is_whitespace() || self.is_keyword(c) => {
                     direction.go_back(self);
                     return true;
                 },
@@ -308,10 +709,10 @@ impl Buffer {
     /// Move count characters to the right.
     ///
     /// If count characters are not found, the position will not be changed.
-    pub fn move_to_char_right(&mut self, target_c: char, count: u32) -> bool {
+    pub fn move_to_grapheme_right(&mut self, target: &str, count: u32) -> bool {
         let pos = self.pos;
         for _ in 0..count {
-            if !self.move_to_char(target_c, ViMoveDir::Right) {
+            if !self.move_to_grapheme(target, ViMoveDir::Right) {
                 self.move_to_pos(pos);
                 return false;
             }
@@ -322,10 +723,10 @@ impl Buffer {
     /// Move count characters to the left.
     ///
     /// If count characters are not found, the position will not be changed.
-    pub fn move_to_char_left(&mut self, target_c: char, count: u32) -> bool {
+    pub fn move_to_grapheme_left(&mut self, target: &str, count: u32) -> bool {
         let pos = self.pos;
         for _ in 0..count {
-            if !self.move_to_char(target_c, ViMoveDir::Left) {
+            if !self.move_to_grapheme(target, ViMoveDir::Left) {
                 self.move_to_pos(pos);
                 return false;
             }
@@ -333,12 +734,14 @@ impl Buffer {
         return true;
     }
 
-    fn move_to_char(&mut self, target_c: char, direction: ViMoveDir) -> bool {
+    /// Move to the next occurrence of a grapheme, which may consist of
+    /// several code points.
+    fn move_to_grapheme(&mut self, target: &str, direction: ViMoveDir) -> bool {
         // XXX this code is very similar to code in move_word_end(), should be replaced with some
         // sort of iterator over the internal buffer starting at the current position
         while direction.advance(self) {
-            match self.cp_after() {
-                Some(c) if c == target_c => return true,
+            match self.grapheme_after() {
+                Some(g) if g == target => return true,
                 Some(_) => {}
                 None => return false,
             }
@@ -346,15 +749,32 @@ impl Buffer {
         return false;
     }
 
+    pub fn pos(&self) -> Position {
+        self.pos
+    }
+
+    #[cfg(test)]
     fn char_pos(&self) -> usize {
         self.pos.char_pos
     }
 
-    fn byte_pos(&self) -> usize {
+    pub fn byte_pos(&self) -> usize {
         self.pos.byte_pos
     }
 
-    fn move_to_pos(&mut self, pos: Position) -> bool {
+    /// Replace the text from the byte offset start up to the cursor.
+    ///
+    /// An offset that does not lie before the cursor on a character
+    /// boundary is treated as the cursor itself.
+    pub fn replace_before_cursor(&mut self, start: usize, text: &str) {
+        if start < self.pos.byte_pos && self.front_buf.is_char_boundary(start) {
+            self.front_buf.drain(start..self.pos.byte_pos);
+            self.pos = Position::at(&self.front_buf, start);
+        }
+        self.insert_chars_at_cursor(text);
+    }
+
+    pub fn move_to_pos(&mut self, pos: Position) -> bool {
         if pos.byte_pos > self.front_buf.len() {
             self.move_end();
             false
@@ -365,38 +785,341 @@ impl Buffer {
         }
     }
 
-    fn delete_to_pos(&mut self, pos: Position) {
+    /// Delete the text between the cursor and pos, returning the deleted text.
+    pub fn delete_to_pos(&mut self, pos: Position) -> String {
         // the idea here is to start at the right most position and delete moving to the left until
         // the left most position
         let (start_pos, end_pos) = match self.pos.cmp(&pos) {
             // char_pos() is less than pos, start at pos and delete back to char_pos()
             Ordering::Less => (pos, self.pos),
             // char_pos() and pos are the same, nothing to do
-            Ordering::Equal => return,
+            Ordering::Equal => return String::new(),
             // char_pos() is greater than pos, start at char_pos() and delete back to pos
             Ordering::Greater => (self.pos, pos),
         };
 
-        self.move_to_pos(start_pos);
-        while self.pos > end_pos {
-            self.delete_char_left_of_cursor();
-        }
+        self.move_to_pos(end_pos);
+        self.front_buf.drain(end_pos.byte_pos..start_pos.byte_pos).collect()
     }
 
     pub fn start_delete(&mut self) -> DeleteContext {
         DeleteContext::new(self)
     }
 
-    pub fn get_line(&self, prompt: &str, clear: bool) -> Vec<u8> {
+    /// The span between anchor and the cursor, including the character
+    /// at the rightmost of the two.
+    pub fn selection(&self, anchor: Position) -> (Position, Position) {
+        let (start, end) = if anchor < self.pos {
+            (anchor, self.pos)
+        } else {
+            (self.pos, anchor)
+        };
+        let end = match UnicodeSegmentation::graphemes(self.front_buf.as_str(), true).nth(end.char_pos) {
+            Some(next) => end.after(next),
+            None => end
+        };
+        (start, end)
+    }
+
+    /// Render the line, highlighting the selection between the given
+    /// positions if there is one.
+    ///
+    /// Only the last line of the prompt is redrawn, unless `show_prompt`
+    /// is set or the screen is cleared. Trailing whitespace is shown inverted
+    /// if `mark_trailing_space` is set, unless there is a selection.
+    ///
+    /// If a window is given, only the part of the line within it is shown.
+    pub fn get_line(&self, prompt: &str, clear: bool, show_prompt: bool, selection: Option<(Position, Position)>,
+                    mark_trailing_space: bool, window: Option<Window>) -> Vec<u8> {
+        let (head, prompt) = split_prompt(prompt);
         let mut line = Builder::new();
         if clear {
             line.clear_screen();
         }
+        if clear || show_prompt {
+            for head_line in head.lines() {
+                line.append(head_line);
+                line.newline();
+            }
+        }
         line.carriage_return();
         line.append(prompt);
-        line.append(&self.front_buf);
+        let prompt_width = visible_width(prompt);
+        let (start, end, cursor) = match window {
+            Some(window) => (window.start, window.end, window.cursor),
+            None => (0, self.front_buf.len(), self.pos.col)
+        };
+        let highlight = self.highlight(selection, mark_trailing_space);
+        self.append_part(&mut line, start, end, highlight);
+        line.erase_to_right();
+        line.set_cursor_pos(prompt_width + cursor);
+        line.build()
+    }
+
+    /// Render a line that is wrapped onto several rows, starting from the
+    /// row of the prompt, see `Wrapped::get_first_row`.
+    ///
+    /// The rows below the line are erased, so that none of a longer line
+    /// that was shown before are left over.
+    pub fn get_wrapped_line(&self, prompt: &str, clear: bool, show_prompt: bool, selection: Option<(Position, Position)>,
+                            mark_trailing_space: bool, wrapped: &Wrapped) -> Vec<u8> {
+        let (head, prompt) = split_prompt(prompt);
+        let mut line = Builder::new();
+        if clear {
+            line.clear_screen();
+        }
+        if clear || show_prompt {
+            for head_line in head.lines() {
+                line.append(head_line);
+                line.newline();
+            }
+        }
+        line.carriage_return();
+        line.erase_below();
+        line.append(prompt);
+        let highlight = self.highlight(selection, mark_trailing_space);
+        for (row, &(start, end)) in wrapped.rows.iter().enumerate() {
+            if row > 0 {
+                line.newline();
+            }
+            self.append_part(&mut line, start, end, highlight);
+        }
+        let up = wrapped.last_row() - wrapped.cursor.0;
+        if up > 0 {
+            line.move_up(up);
+        }
+        set_col(&mut line, wrapped.cursor.1);
+        line.build()
+    }
+
+    /// The byte range of the line that is shown inverted, which is the
+    /// selection or else the marked trailing whitespace.
+    fn highlight(&self, selection: Option<(Position, Position)>, mark_trailing_space: bool) -> (usize, usize) {
+        match selection {
+            Some((from, to)) => (from.byte_pos, to.byte_pos),
+            None if mark_trailing_space => (self.front_buf.trim_end_matches(char::is_whitespace).len(), self.front_buf.len()),
+            None => (self.front_buf.len(), self.front_buf.len())
+        }
+    }
+
+    /// Append the part of the line from start to end, inverting the
+    /// highlighted part of it.
+    fn append_part(&self, line: &mut Builder, start: usize, end: usize, highlight: (usize, usize)) {
+        let clamp = |pos: usize| pos.max(start).min(end);
+        let (from, to) = (clamp(highlight.0), clamp(highlight.1));
+        line.append(&self.front_buf[start..from]);
+        if from < to {
+            line.invert_color();
+            line.append(&self.front_buf[from..to]);
+            line.reset_color();
+        }
+        line.append(&self.front_buf[to..end]);
+    }
+
+    /// The rows the line takes up on a terminal that is width columns
+    /// wide, starting on the row of the prompt, when it is wrapped in the
+    /// given mode.
+    pub fn wrap(&self, prompt: &str, width: usize, mode: WrapMode) -> Wrapped {
+        let width = width.max(1);
+        let prompt_width = visible_width(split_prompt(prompt).1);
+        let text = self.front_buf.as_str();
+        let mut rows = vec![];
+        let (mut start, mut col) = (0, prompt_width);
+        // where the row can be broken in word mode, after the last space
+        let mut after_space = None;
+        for (idx, g) in text.grapheme_indices(true) {
+            let g_width = UnicodeWidthStr::width(g);
+            if col + g_width > width {
+                match after_space {
+                    // a space at the edge is absorbed by the break
+                    _ if mode == WrapMode::Word && g == " " => {
+                        rows.push((start, idx));
+                        start = idx + g.len();
+                        col = 0;
+                        after_space = None;
+                        continue;
+                    }
+                    Some(after) if mode == WrapMode::Word => {
+                        rows.push((start, after));
+                        col = UnicodeWidthStr::width(&text[after..idx]);
+                        start = after;
+                    }
+                    _ => {
+                        rows.push((start, idx));
+                        col = 0;
+                        start = idx;
+                    }
+                }
+                after_space = None;
+            }
+            col += g_width;
+            if g == " " {
+                after_space = Some(idx + g.len());
+            }
+        }
+        rows.push((start, text.len()));
+        let cursor = row_col(text, &rows, prompt_width, width, self.pos.byte_pos);
+        // the cursor after a full last row starts a row of its own
+        if cursor.0 == rows.len() {
+            rows.push((text.len(), text.len()));
+        }
+        let end = row_col(text, &rows, prompt_width, usize::MAX, text.len()).1;
+        Wrapped { rows, cursor, end }
+    }
+
+    /// The column that `get_line` places the cursor at, counting from
+    /// the left edge of the terminal.
+    pub fn cursor_col(&self, prompt: &str, window: Option<Window>) -> usize {
+        visible_width(split_prompt(prompt).1) + self.shown_cols(window).1
+    }
+
+    /// The part of the line that fits into a terminal that is width
+    /// columns wide after the prompt, while showing the cursor.
+    ///
+    /// The window starts at the column offset of the previous one, and is
+    /// only scrolled as far as needed to bring the cursor back into it, so
+    /// that the line does not shift while the cursor moves within it.
+    pub fn window(&self, prompt: &str, width: usize, offset: usize) -> Window {
+        let columns = width.saturating_sub(visible_width(split_prompt(prompt).1)).max(1);
+        let cursor = self.pos.col;
+        // leave room for the cursor after the last character, but no more
+        let offset = offset.min((UnicodeWidthStr::width(self.front_buf.as_str()) + 1).saturating_sub(columns));
+        let offset = if cursor < offset {
+            cursor
+        } else if cursor >= offset + columns {
+            cursor + 1 - columns
+        } else {
+            offset
+        };
+        let (mut start, mut end) = (None, self.front_buf.len());
+        let mut col = 0;
+        for (idx, g) in self.front_buf.grapheme_indices(true) {
+            let next = col + UnicodeWidthStr::width(g);
+            if start.is_none() && col >= offset {
+                start = Some(idx);
+            }
+            if next > offset + columns {
+                end = idx;
+                break;
+            }
+            col = next;
+        }
+        let start = start.unwrap_or(end).min(end);
+        Window {
+            offset,
+            columns,
+            start,
+            end,
+            cursor: cursor - UnicodeWidthStr::width(&self.front_buf[..start])
+        }
+    }
+
+    /// The columns after the prompt where the shown part of the line ends
+    /// and where the cursor is.
+    fn shown_cols(&self, window: Option<Window>) -> (usize, usize) {
+        match window {
+            Some(window) => (UnicodeWidthStr::width(&self.front_buf[window.start..window.end]), window.cursor),
+            None => (UnicodeWidthStr::width(self.front_buf.as_str()), self.pos.col)
+        }
+    }
+
+    /// The part of a hint that fits into the window after the line, which
+    /// is nothing unless the end of the line is shown.
+    fn shown_hint<'h>(&self, hint: &'h str, window: Option<Window>) -> &'h str {
+        let window = match window {
+            Some(window) => window,
+            None => return hint
+        };
+        if window.end < self.front_buf.len() {
+            return "";
+        }
+        let mut col = UnicodeWidthStr::width(&self.front_buf[window.start..window.end]);
+        for (idx, g) in hint.grapheme_indices(true) {
+            col += UnicodeWidthStr::width(g);
+            if col > window.columns {
+                return &hint[..idx];
+            }
+        }
+        hint
+    }
+
+    /// Show a marker after the end of the line, leaving the cursor where it is.
+    ///
+    /// The marker is erased by the next call to `get_line`.
+    pub fn get_marker(&self, prompt: &str, marker: &str, window: Option<Window>) -> Vec<u8> {
+        let width = visible_width(split_prompt(prompt).1);
+        let (end, cursor) = self.shown_cols(window);
+        let mut line = Builder::new();
+        line.set_cursor_pos(width + end);
+        line.append(" ");
+        line.append(marker);
+        line.set_cursor_pos(width + cursor);
+        line.build()
+    }
+
+    /// Show text on the line below the input, leaving the cursor where
+    /// it is. Empty text erases that line.
+    pub fn get_info(&self, prompt: &str, info: &str, window: Option<Window>) -> Vec<u8> {
+        let width = visible_width(split_prompt(prompt).1);
+        let mut line = Builder::new();
+        line.newline();
+        line.append(info);
         line.erase_to_right();
-        line.set_cursor_pos(prompt.len() + self.char_pos());
+        line.move_up(1);
+        line.set_cursor_pos(width + self.shown_cols(window).1);
+        line.build()
+    }
+
+    /// Show text after the end of the line, leaving the cursor after it.
+    pub fn get_trailer(&self, prompt: &str, text: &str, window: Option<Window>) -> Vec<u8> {
+        let width = visible_width(split_prompt(prompt).1);
+        let mut line = Builder::new();
+        line.set_cursor_pos(width + self.shown_cols(window).0);
+        line.append(text);
+        line.build()
+    }
+
+    /// Show a hint dimmed after the end of the line, leaving the cursor
+    /// where it is. Only the part of the hint that fits into the window
+    /// is shown.
+    ///
+    /// The hint is erased by the next call to `get_line`.
+    pub fn get_hint(&self, prompt: &str, hint: &str, window: Option<Window>) -> Vec<u8> {
+        let width = visible_width(split_prompt(prompt).1);
+        let (end, cursor) = self.shown_cols(window);
+        let mut line = Builder::new();
+        let hint = self.shown_hint(hint, window);
+        if hint.is_empty() {
+            return line.build();
+        }
+        line.set_cursor_pos(width + end);
+        line.dim_color();
+        line.append(hint);
+        line.reset_color();
+        line.set_cursor_pos(width + cursor);
+        line.build()
+    }
+
+    /// Show a prompt flush to the right edge of a terminal that is width
+    /// columns wide, leaving the cursor where it is.
+    ///
+    /// Nothing is shown if the prompt would overlap the line, or the hint
+    /// shown after it. The last column is left empty, so that the
+    /// terminal does not wrap.
+    pub fn get_right_prompt(&self, prompt: &str, right_prompt: &str, hint: &str, width: usize,
+                            window: Option<Window>) -> Vec<u8> {
+        let prompt_width = visible_width(split_prompt(prompt).1);
+        let (end, cursor) = self.shown_cols(window);
+        let end = prompt_width + end + visible_width(self.shown_hint(hint, window));
+        let right_width = visible_width(right_prompt);
+        let mut line = Builder::new();
+        if end + right_width + 2 > width {
+            return line.build();
+        }
+        line.set_cursor_pos(end);
+        line.append(&" ".repeat(width - 1 - right_width - end));
+        line.append(right_prompt);
+        line.set_cursor_pos(prompt_width + cursor);
         line.build()
     }
 
@@ -416,7 +1139,17 @@ impl Buffer {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.front_buf.is_empty()
+        self.byte_len() == 0
+    }
+
+    /// The length of the line in bytes.
+    pub fn byte_len(&self) -> usize {
+        self.front_buf.len()
+    }
+
+    /// The length of the line in graphemes.
+    pub fn grapheme_len(&self) -> usize {
+        UnicodeSegmentation::graphemes(self.front_buf.as_str(), true).count()
     }
 }
 
@@ -443,9 +1176,21 @@ impl<'a> DeleteContext<'a> {
         self.was_on_whitespace
     }
 
-    pub fn delete(mut self) {
+    pub fn delete(self) -> String {
         self.buf.delete_to_pos(self.start_pos)
     }
+
+    /// Copy the text between the start and the current position, leaving
+    /// the cursor at the leftmost of the two.
+    pub fn yank(self) -> String {
+        let (start, end) = if self.start_pos < self.buf.pos {
+            (self.start_pos, self.buf.pos)
+        } else {
+            (self.buf.pos, self.start_pos)
+        };
+        self.buf.pos = start;
+        self.buf.front_buf[start.byte_pos..end.byte_pos].to_owned()
+    }
 }
 
 impl<'a> Deref for DeleteContext<'a> {
@@ -554,10 +1299,103 @@ fn move_and_insert_cjk() {
     buf.move_end();
     buf.insert_char_at_cursor('憛');
     assert_eq!(buf.as_str(), "乫䨻䩖䦴憛");
+    assert_eq!(buf.pos.col, 10);
     buf.move_left();
     buf.move_left();
+    assert_eq!(buf.pos.col, 6);
     buf.delete_char_left_of_cursor();
     assert_eq!(buf.as_str(), "乫䨻䦴憛");
+    // each character takes up two columns
+    assert_eq!(buf.pos.col, 4);
+    let line = String::from_utf8(buf.get_line("> ", false, false, None, false, None)).unwrap();
+    assert_eq!(line, "\r> 乫䨻䦴憛\x1b[0K\r\x1b[6C");
+    buf.move_start();
+    buf.move_right();
+    assert_eq!(buf.pos.col, 2);
+}
+
+#[test]
+fn visible_width_of_prompt() {
+    assert_eq!(visible_width("foo> "), 5);
+    assert_eq!(visible_width("\x1b[1;32mfoo\x1b[0m> "), 5);
+    assert_eq!(visible_width("\x1b[mλ\x1b[31m> "), 3);
+    assert_eq!(visible_width("日本> "), 6);
+}
+
+#[test]
+fn multi_line_prompt() {
+    assert_eq!(split_prompt("> "), ("", "> "));
+    assert_eq!(split_prompt("a\nb\n> "), ("a\nb\n", "> "));
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("ab");
+    let line = String::from_utf8(buf.get_line("a\nb\n> ", false, true, None, false, None)).unwrap();
+    assert_eq!(line, "a\r\nb\r\n\r> ab\x1b[0K\r\x1b[4C");
+    let line = String::from_utf8(buf.get_line("a\nb\n> ", false, false, None, false, None)).unwrap();
+    assert_eq!(line, "\r> ab\x1b[0K\r\x1b[4C");
+}
+
+#[test]
+fn move_last_non_blank() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("foo bar  ");
+    buf.move_start();
+    buf.move_last_non_blank();
+    assert_eq!(buf.pos.byte_pos, 6);
+    buf.replace("   ");
+    buf.move_last_non_blank();
+    assert_eq!(buf.pos.byte_pos, 0);
+}
+
+#[test]
+fn byte_and_grapheme_len() {
+    let mut buf = Buffer::new();
+    assert_eq!((buf.byte_len(), buf.grapheme_len()), (0, 0));
+    buf.insert_chars_at_cursor("aЙ䩖");
+    assert_eq!((buf.byte_len(), buf.grapheme_len()), (6, 3));
+    // a base character with a combining mark is a single grapheme
+    buf.insert_chars_at_cursor("e\u{301}");
+    assert_eq!((buf.byte_len(), buf.grapheme_len()), (9, 4));
+}
+
+#[test]
+fn toggle_case() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("aB1ß");
+    buf.move_start();
+    assert!(buf.toggle_case_at_cursor());
+    assert!(buf.toggle_case_at_cursor());
+    assert!(buf.toggle_case_at_cursor());
+    assert!(buf.toggle_case_at_cursor());
+    assert!(!buf.toggle_case_at_cursor());
+    assert_eq!(buf.as_str(), "Ab1SS");
+    assert_eq!(buf.pos.byte_pos, 5);
+}
+
+#[test]
+fn selection() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("foo bar");
+    buf.move_start();
+    buf.move_right();
+    let anchor = buf.pos;
+    buf.move_left();
+    let (start, end) = buf.selection(anchor);
+    assert_eq!(&buf.as_str()[start.byte_pos..end.byte_pos], "fo");
+    let line = String::from_utf8(buf.get_line("> ", false, false, Some((start, end)), false, None)).unwrap();
+    assert!(line.contains("> \x1b[7mfo\x1b[0mo bar"));
+}
+
+#[test]
+fn snapshot_and_restore() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("text");
+    buf.move_left();
+    let snapshot = buf.snapshot();
+    let pos = buf.pos;
+    buf.insert_chars_at_cursor(" more");
+    buf.restore(snapshot);
+    assert_eq!(buf.as_str(), "text");
+    assert_eq!(buf.pos, pos);
 }
 
 #[test]
@@ -929,19 +1767,49 @@ fn move_to_char() {
     buf.insert_chars_at_cursor("ds");
 
     buf.move_start();
-    assert!(buf.move_to_char_right(' ', 1));
+    assert!(buf.move_to_grapheme_right(" ", 1));
     assert_eq!(buf.char_pos(), pos1);
     buf.move_end();
-    assert!(buf.move_to_char_left(' ', 1));
+    assert!(buf.move_to_grapheme_left(" ", 1));
     assert_eq!(buf.char_pos(), pos1);
     buf.move_start();
-    assert_eq!(buf.move_to_char_right('z', 1), false);
+    assert_eq!(buf.move_to_grapheme_right("z", 1), false);
     assert_eq!(buf.char_pos(), 0);
     buf.move_start();
-    assert!(buf.move_to_char_right('d', 2));
+    assert!(buf.move_to_grapheme_right("d", 2));
     assert_eq!(buf.char_pos(), d_pos);
 }
 
+#[test]
+fn delete_combining_sequence() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("cafe");
+    buf.insert_char_at_cursor('\u{301}');
+    assert_eq!(buf.pos.col, 4);
+    assert!(buf.delete_char_left_of_cursor());
+    assert_eq!(buf.as_str(), "caf");
+    buf.insert_chars_at_cursor("e\u{301}s");
+    buf.move_start();
+    buf.move_right();
+    buf.move_right();
+    buf.move_right();
+    assert!(buf.delete_char_right_of_cursor());
+    assert_eq!(buf.as_str(), "cafs");
+    assert_eq!(buf.pos.col, 3);
+}
+
+#[test]
+fn move_to_multi_code_point_grapheme() {
+    let mut buf = Buffer::new();
+    // an e without and with a combining acute accent
+    buf.insert_chars_at_cursor("ae be\u{301} c");
+    buf.move_start();
+    assert!(buf.move_to_grapheme_right("e\u{301}", 1));
+    assert_eq!(buf.char_pos(), 4);
+    assert!(buf.move_to_grapheme_left("e", 1));
+    assert_eq!(buf.char_pos(), 1);
+}
+
 #[test]
 fn move_and_delete1() {
     // test a simple move
@@ -969,6 +1837,35 @@ fn move_and_delete2() {
     assert_eq!(buf.to_string(), "".to_string());
 }
 
+#[test]
+fn move_and_delete_returns_text() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("some words here");
+    buf.move_start();
+    buf.move_word();
+    let text = {
+        let mut dc = buf.start_delete();
+        dc.move_word();
+        dc.delete()
+    };
+    assert_eq!(text, "words ".to_string());
+    assert_eq!(buf.to_string(), "some here".to_string());
+}
+
+#[test]
+fn move_and_yank() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("some words here");
+    let text = {
+        let mut dc = buf.start_delete();
+        dc.move_word_back();
+        dc.yank()
+    };
+    assert_eq!(text, "here".to_string());
+    assert_eq!(buf.char_pos(), 11);
+    assert_eq!(buf.to_string(), "some words here".to_string());
+}
+
 #[test]
 fn move_and_delete3() {
     // test deleting from the end to the beginning
@@ -981,3 +1878,306 @@ fn move_and_delete3() {
     }
     assert_eq!(buf.to_string(), "".to_string());
 }
+
+#[test]
+fn replace_before_cursor() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("cat fo bar");
+    buf.move_start();
+    for _ in 0..6 {
+        buf.move_right();
+    }
+    buf.replace_before_cursor(4, "foo.txt");
+    assert_eq!(buf.as_str(), "cat foo.txt bar");
+    assert_eq!(buf.byte_pos(), 11);
+    // an offset past the cursor only inserts
+    buf.replace_before_cursor(12, "!");
+    assert_eq!(buf.as_str(), "cat foo.txt! bar");
+}
+
+#[test]
+fn mark_trailing_space() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("a b  ");
+    let line = String::from_utf8(buf.get_line("> ", false, false, None, true, None)).unwrap();
+    assert_eq!(line, "\r> a b\x1b[7m  \x1b[0m\x1b[0K\r\x1b[7C");
+    let line = String::from_utf8(buf.get_line("> ", false, false, None, false, None)).unwrap();
+    assert_eq!(line, "\r> a b  \x1b[0K\r\x1b[7C");
+    // tabs are whitespace too
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("a \t");
+    let line = String::from_utf8(buf.get_line("> ", false, false, None, true, None)).unwrap();
+    assert!(line.starts_with("\r> a\x1b[7m \t\x1b[0m"));
+}
+
+#[test]
+fn word_object() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("foo.bar  baz");
+    buf.move_start();
+    buf.move_right();
+    let text = |buf: &Buffer, around, ws| {
+        let (start, end) = buf.word_object(around, ws).unwrap();
+        buf.as_str()[start.byte_pos..end.byte_pos].to_owned()
+    };
+    assert_eq!(text(&buf, false, false), "foo");
+    assert_eq!(text(&buf, true, false), "foo");
+    assert_eq!(text(&buf, false, true), "foo.bar");
+    assert_eq!(text(&buf, true, true), "foo.bar  ");
+    // on whitespace, the following word is included
+    buf.move_to_grapheme_right(" ", 1);
+    assert_eq!(text(&buf, false, false), "  ");
+    assert_eq!(text(&buf, true, false), "  baz");
+    // without whitespace after the word, the whitespace before it is included
+    buf.move_end();
+    buf.move_left();
+    assert_eq!(text(&buf, true, false), "  baz");
+    buf.move_end();
+    assert_eq!(buf.word_object(false, false), None);
+}
+
+#[test]
+fn move_word_with_extra_word_chars() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("foo-bar/baz qux");
+    buf.move_start();
+    assert!(buf.move_word());
+    assert_eq!(buf.pos.byte_pos, 3);
+    buf.set_word_chars("-/");
+    buf.move_start();
+    assert!(buf.move_word());
+    assert_eq!(buf.pos.byte_pos, 12);
+    buf.move_start();
+    assert!(buf.move_to_end_of_word());
+    assert_eq!(buf.pos.byte_pos, 10);
+}
+
+#[test]
+fn scroll_horizontally() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("0123456789");
+    // the end of the line is shown, with room for the cursor
+    let window = buf.window("> ", 8, 0);
+    assert_eq!(window.offset, 5);
+    let line = String::from_utf8(buf.get_line("> ", false, false, None, false, Some(window))).unwrap();
+    assert_eq!(line, "\r> 56789\x1b[0K\r\x1b[7C");
+    // moving within the window does not scroll
+    buf.move_left();
+    buf.move_left();
+    let window = buf.window("> ", 8, window.offset);
+    let line = String::from_utf8(buf.get_line("> ", false, false, None, false, Some(window))).unwrap();
+    assert_eq!(line, "\r> 56789\x1b[0K\r\x1b[5C");
+    // moving out of it scrolls only as far as needed
+    buf.move_to_pos(Position::at(buf.as_str(), 3));
+    let window = buf.window("> ", 8, window.offset);
+    assert_eq!(window.offset, 3);
+    let line = String::from_utf8(buf.get_line("> ", false, false, None, false, Some(window))).unwrap();
+    assert_eq!(line, "\r> 345678\x1b[0K\r\x1b[2C");
+    // lines that fit are not scrolled
+    let window = buf.window("> ", 20, window.offset);
+    assert_eq!(window.offset, 0);
+    let line = String::from_utf8(buf.get_line("> ", false, false, None, false, Some(window))).unwrap();
+    assert_eq!(line, "\r> 0123456789\x1b[0K\r\x1b[5C");
+}
+
+#[cfg(test)]
+fn row_texts<'b>(buf: &'b Buffer, wrapped: &Wrapped) -> Vec<&'b str> {
+    wrapped.rows.iter().map(|&(start, end)| &buf.as_str()[start..end]).collect()
+}
+
+#[test]
+fn wrap_modes() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("the quick brown fox");
+    // the same text breaks anywhere, or only after spaces
+    let chars = buf.wrap("> ", 10, WrapMode::Char);
+    assert_eq!(row_texts(&buf, &chars), vec!["the quic", "k brown fo", "x"]);
+    assert_eq!(chars.cursor, (2, 1));
+    let words = buf.wrap("> ", 10, WrapMode::Word);
+    assert_eq!(row_texts(&buf, &words), vec!["the ", "quick ", "brown fox"]);
+    assert_eq!(words.cursor, (2, 9));
+    // so a position maps to different rows and columns
+    buf.move_to_pos(Position::at(buf.as_str(), 8));
+    assert_eq!(buf.wrap("> ", 10, WrapMode::Char).cursor, (1, 0));
+    assert_eq!(buf.wrap("> ", 10, WrapMode::Word).cursor, (1, 4));
+    // a line that fits is not broken in either mode
+    assert_eq!(buf.wrap("> ", 30, WrapMode::Char).last_row(), 0);
+    assert_eq!(buf.wrap("> ", 30, WrapMode::Word).last_row(), 0);
+}
+
+#[test]
+fn wrap_at_space() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("abcdefgh ij");
+    let chars = buf.wrap("> ", 10, WrapMode::Char);
+    assert_eq!(row_texts(&buf, &chars), vec!["abcdefgh", " ij"]);
+    // a space at the edge is absorbed by the break, and the cursor on it
+    // is shown at the start of the next row
+    let words = buf.wrap("> ", 10, WrapMode::Word);
+    assert_eq!(row_texts(&buf, &words), vec!["abcdefgh", "ij"]);
+    buf.move_to_pos(Position::at(buf.as_str(), 8));
+    assert_eq!(buf.wrap("> ", 10, WrapMode::Word).cursor, (1, 0));
+    assert_eq!(buf.wrap("> ", 10, WrapMode::Char).cursor, (1, 0));
+    // a word that takes up a whole row is broken anywhere
+    buf.replace("abcdefghijkl");
+    let words = buf.wrap("> ", 10, WrapMode::Word);
+    assert_eq!(row_texts(&buf, &words), vec!["abcdefgh", "ijkl"]);
+    // and the cursor after a full row starts a row of its own
+    buf.replace("abcdefgh");
+    let words = buf.wrap("> ", 10, WrapMode::Word);
+    assert_eq!(row_texts(&buf, &words), vec!["abcdefgh", ""]);
+    assert_eq!(words.cursor, (1, 0));
+}
+
+#[test]
+fn wrapped_line() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("the quick brown fox");
+    buf.move_start();
+    let wrapped = buf.wrap("> ", 10, WrapMode::Word);
+    // the line is redrawn from its first row, and the cursor moved back up
+    let line = String::from_utf8(buf.get_wrapped_line("> ", false, false, None, false, &wrapped)).unwrap();
+    assert_eq!(line, "\r\x1b[0J> the \r\nquick \r\nbrown fox\x1b[2A\r\x1b[2C");
+    let info = String::from_utf8(wrapped.get_info("info")).unwrap();
+    assert_eq!(info, "\x1b[2B\r\ninfo\x1b[0K\x1b[3A\r\x1b[2C");
+    let trailer = String::from_utf8(wrapped.get_trailer("^C")).unwrap();
+    assert_eq!(trailer, "\x1b[2B\r\x1b[9C^C");
+    // the next render starts from the row of the prompt
+    assert_eq!(wrapped.get_first_row(), vec![]);
+    buf.move_end();
+    assert_eq!(buf.wrap("> ", 10, WrapMode::Word).get_first_row(), b"\x1b[2A".to_vec());
+}
+
+#[test]
+fn with_capacity() {
+    let buf = Buffer::with_capacity(4096);
+    assert!(buf.front_buf.capacity() >= 4096);
+    assert!(buf.back_buf.capacity() >= 4096);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn after_keyword() {
+    let mut buf = Buffer::new();
+    assert!(!buf.is_after_keyword());
+    buf.insert_chars_at_cursor("ls foo ");
+    assert!(!buf.is_after_keyword());
+    buf.move_left();
+    assert!(buf.is_after_keyword());
+    buf.move_start();
+    assert!(!buf.is_after_keyword());
+}
+
+#[test]
+fn right_prompt() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("ab");
+    buf.move_left();
+    let line = String::from_utf8(buf.get_right_prompt("> ", "[git]", "", 12, None)).unwrap();
+    assert_eq!(line, "\r\x1b[4C  [git]\r\x1b[3C");
+    // the hint takes precedence
+    assert_eq!(buf.get_right_prompt("> ", "[git]", "cd", 12, None), vec![]);
+    buf.insert_chars_at_cursor("cd");
+    assert_eq!(buf.get_right_prompt("> ", "[git]", "", 12, None), vec![]);
+}
+
+#[test]
+fn change_case_of_line() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("Straße");
+    buf.move_left();
+    buf.move_left();
+    buf.uppercase_line();
+    // ß becomes two graphemes, the cursor stays after the fourth one
+    assert_eq!(buf.as_str(), "STRASSE");
+    assert_eq!(buf.byte_pos(), 4);
+    buf.lowercase_line();
+    assert_eq!(buf.as_str(), "strasse");
+    assert_eq!(buf.byte_pos(), 4);
+
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("ÄİX");
+    buf.lowercase_line();
+    // İ becomes i followed by a combining dot, which is longer in bytes
+    assert_eq!(buf.as_str(), "äi\u{307}x");
+    assert_eq!(buf.byte_pos(), buf.as_str().len());
+    buf.move_start();
+    buf.move_right();
+    buf.uppercase_line();
+    assert_eq!(buf.as_str(), "ÄI\u{307}X");
+    assert_eq!(buf.byte_pos(), "Ä".len());
+}
+
+#[test]
+fn current_word() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("ls foo-bar  baz");
+    // at the end of a word
+    assert_eq!(buf.current_word(), Some((12, 15, "baz")));
+    // within a word
+    buf.move_to_pos(Position::at(&buf.front_buf, 4));
+    assert_eq!(buf.current_word(), Some((3, 6, "foo")));
+    // at the start of a word
+    buf.move_to_pos(Position::at(&buf.front_buf, 7));
+    assert_eq!(buf.current_word(), Some((7, 10, "bar")));
+    // directly after a word, the word before the cursor is returned
+    buf.move_to_pos(Position::at(&buf.front_buf, 6));
+    assert_eq!(buf.current_word(), Some((3, 6, "foo")));
+    // in whitespace, there is no current word
+    buf.move_to_pos(Position::at(&buf.front_buf, 11));
+    assert_eq!(buf.current_word(), None);
+    // unless the word characters are extended
+    buf.set_word_chars("-");
+    buf.move_to_pos(Position::at(&buf.front_buf, 4));
+    assert_eq!(buf.current_word(), Some((3, 10, "foo-bar")));
+    buf.replace("");
+    assert_eq!(buf.current_word(), None);
+}
+
+#[test]
+fn emacs_move_word() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("foo.bar baz_qux");
+    buf.move_start();
+    let mut stops = vec![];
+    while buf.emacs_move_word_forward() {
+        stops.push(buf.pos().char_pos);
+    }
+    assert_eq!(stops, vec![3, 7, 11, 15]);
+    stops.clear();
+    while buf.emacs_move_word_backward() {
+        stops.push(buf.pos().char_pos);
+    }
+    assert_eq!(stops, vec![12, 8, 4, 0]);
+}
+
+#[test]
+fn emacs_move_word_punctuation() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("  --foo..  ");
+    buf.move_start();
+    assert!(buf.emacs_move_word_forward());
+    assert_eq!(buf.pos().char_pos, 7);
+    // trailing punctuation is skipped to the end of the line
+    assert!(buf.emacs_move_word_forward());
+    assert_eq!(buf.pos().char_pos, 11);
+    assert!(!buf.emacs_move_word_forward());
+    assert!(buf.emacs_move_word_backward());
+    assert_eq!(buf.pos().char_pos, 4);
+    assert!(buf.emacs_move_word_backward());
+    assert_eq!(buf.pos().char_pos, 0);
+}
+
+#[test]
+fn masked() {
+    let mut buf = Buffer::new();
+    buf.insert_chars_at_cursor("se\u{301}cr\u{e9}t");
+    buf.move_left();
+    buf.move_left();
+    let masked = buf.masked(Some('*'));
+    assert_eq!(masked.as_str(), "******");
+    assert_eq!(masked.pos().char_pos, 4);
+    let hidden = buf.masked(None);
+    assert_eq!(hidden.as_str(), "");
+    assert_eq!(hidden.pos().char_pos, 0);
+}
@@ -1,3 +1,15 @@
+/// The shape of the terminal cursor, as set by `DECSCUSR`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CursorShape {
+    Default,
+    BlinkingBlock,
+    Block,
+    BlinkingUnderline,
+    Underline,
+    BlinkingBar,
+    Bar
+}
+
 pub struct Builder {
     seq: Vec<u8>
 }
@@ -20,10 +32,26 @@ impl Builder {
         self.seq.extend(b"\r");
     }
 
+    pub fn newline(&mut self) {
+        self.seq.extend(b"\r\n");
+    }
+
     pub fn erase_to_right(&mut self) {
         self.seq.extend(b"\x1b[0K");
     }
 
+    pub fn move_up(&mut self, lines: usize) {
+        self.seq.extend(&format!("\x1b[{}A", lines).into_bytes());
+    }
+
+    pub fn move_down(&mut self, lines: usize) {
+        self.seq.extend(&format!("\x1b[{}B", lines).into_bytes());
+    }
+
+    pub fn erase_below(&mut self) {
+        self.seq.extend(b"\x1b[0J");
+    }
+
     pub fn set_cursor_pos(&mut self, pos: usize) {
         self.seq.extend(&format!("\r\x1b[{}C", pos).into_bytes());
     }
@@ -40,6 +68,35 @@ impl Builder {
         self.seq.extend(b"\x1b[7m")
     }
 
+    pub fn dim_color(&mut self) {
+        self.seq.extend(b"\x1b[2m")
+    }
+
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        let n = match shape {
+            CursorShape::Default => 0,
+            CursorShape::BlinkingBlock => 1,
+            CursorShape::Block => 2,
+            CursorShape::BlinkingUnderline => 3,
+            CursorShape::Underline => 4,
+            CursorShape::BlinkingBar => 5,
+            CursorShape::Bar => 6
+        };
+        self.seq.extend(&format!("\x1b[{} q", n).into_bytes());
+    }
+
+    pub fn enable_bracketed_paste(&mut self) {
+        self.seq.extend(b"\x1b[?2004h")
+    }
+
+    pub fn disable_bracketed_paste(&mut self) {
+        self.seq.extend(b"\x1b[?2004l")
+    }
+
+    pub fn bell(&mut self) {
+        self.seq.extend(b"\x07")
+    }
+
     pub fn ask_cursor_pos(&mut self) {
         self.seq.extend(b"\x1b[6n")
     }
@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+
+/// A piece of text that was cut or copied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clip {
+    pub text: String,
+    /// Whether the text was yanked or deleted as a whole line in vi mode.
+    pub linewise: bool
+}
+
+/// Holds the most recently killed strings of Emacs mode, newest first,
+/// and the unnamed vi register, so that text cut in either mode can be
+/// pasted in the other one.
+///
+/// The register always holds the newest text cut in either mode, while
+/// only Emacs kills are retained in the ring, so that deleting in vi mode
+/// does not push them out.
+pub struct Clipboard {
+    ring: VecDeque<String>,
+    max: usize,
+    register: Clip,
+    /// Whether the register holds the newest entry of the ring.
+    from_ring: bool,
+    /// The entry of the ring that was pasted last, if any.
+    idx: Option<usize>
+}
+
+impl Clipboard {
+
+    /// Creates a clipboard whose ring retains max killed strings, where
+    /// 0 disables killing.
+    pub fn new(max: usize) -> Clipboard {
+        Clipboard {
+            ring: VecDeque::new(),
+            max: max,
+            register: Clip { text: String::new(), linewise: false },
+            from_ring: false,
+            idx: None
+        }
+    }
+
+    /// Adds a string killed in Emacs mode, dropping the oldest one if the
+    /// ring is full.
+    pub fn kill(&mut self, text: String) {
+        if text.is_empty() || self.max == 0 {
+            return;
+        }
+        self.ring.push_front(text.clone());
+        self.ring.truncate(self.max);
+        self.register = Clip { text: text, linewise: false };
+        self.from_ring = true;
+        self.idx = None;
+    }
+
+    /// Replaces the register by text deleted or yanked in vi mode, which
+    /// may be empty.
+    pub fn set(&mut self, text: String, linewise: bool) {
+        self.register = Clip { text: text, linewise: linewise };
+        self.from_ring = false;
+        self.idx = None;
+    }
+
+    /// Returns the most recently cut string.
+    pub fn get(&mut self) -> Option<&Clip> {
+        self.idx = if self.from_ring { Some(0) } else { None };
+        if self.register.text.is_empty() {
+            return None;
+        }
+        Some(&self.register)
+    }
+
+    /// Moves on to the next older killed string after the one returned
+    /// last, wrapping around at the end of the ring, and returns it.
+    pub fn rotate(&mut self) -> Option<&str> {
+        if self.ring.is_empty() {
+            return None;
+        }
+        let idx = self.idx.map_or(0, |idx| (idx + 1) % self.ring.len());
+        self.idx = Some(idx);
+        self.ring.get(idx).map(|text| text.as_str())
+    }
+
+}
+
+#[cfg(test)]
+fn text(clip: Option<&Clip>) -> Option<&str> {
+    clip.map(|clip| clip.text.as_str())
+}
+
+#[test]
+fn get_and_rotate() {
+    let mut clipboard = Clipboard::new(10);
+    assert_eq!(clipboard.get(), None);
+    assert_eq!(clipboard.rotate(), None);
+    clipboard.kill("one".to_string());
+    clipboard.kill("two".to_string());
+    assert_eq!(clipboard.get(), Some(&Clip { text: "two".to_string(), linewise: false }));
+    assert_eq!(clipboard.rotate(), Some("one"));
+    assert_eq!(clipboard.rotate(), Some("two"));
+    assert_eq!(text(clipboard.get()), Some("two"));
+}
+
+#[test]
+fn ignore_empty_kill() {
+    let mut clipboard = Clipboard::new(10);
+    clipboard.kill("".to_string());
+    assert_eq!(clipboard.get(), None);
+}
+
+#[test]
+fn drop_oldest() {
+    let mut clipboard = Clipboard::new(2);
+    clipboard.kill("one".to_string());
+    clipboard.kill("two".to_string());
+    clipboard.kill("three".to_string());
+    assert_eq!(text(clipboard.get()), Some("three"));
+    assert_eq!(clipboard.rotate(), Some("two"));
+    assert_eq!(clipboard.rotate(), Some("three"));
+}
+
+#[test]
+fn mix_vi_and_emacs() {
+    let mut clipboard = Clipboard::new(2);
+    clipboard.kill("one".to_string());
+    clipboard.set("x".to_string(), false);
+    clipboard.kill("two".to_string());
+    clipboard.set("line".to_string(), true);
+    clipboard.set("y".to_string(), false);
+    // the newest text from either mode is pasted
+    assert_eq!(clipboard.get(), Some(&Clip { text: "y".to_string(), linewise: false }));
+    // but only the kills are in the ring, none pushed out by vi
+    assert_eq!(clipboard.rotate(), Some("two"));
+    assert_eq!(clipboard.rotate(), Some("one"));
+    assert_eq!(clipboard.rotate(), Some("two"));
+    // an empty vi delete clears the register
+    clipboard.set("".to_string(), false);
+    assert_eq!(clipboard.get(), None);
+    assert_eq!(clipboard.rotate(), Some("two"));
+}
+
+#[test]
+fn disable_killing() {
+    let mut clipboard = Clipboard::new(0);
+    clipboard.kill("one".to_string());
+    assert_eq!(clipboard.get(), None);
+    assert_eq!(clipboard.rotate(), None);
+    // the vi register works regardless
+    clipboard.set("x".to_string(), false);
+    assert_eq!(text(clipboard.get()), Some("x"));
+}
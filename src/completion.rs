@@ -0,0 +1,78 @@
+/// A candidate for completing the text before the cursor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Completion {
+    /// The text that replaces the text being completed.
+    pub replacement: String,
+    /// A delimiter inserted after the replacement, such as a space after
+    /// a command or a `/` after a directory.
+    ///
+    /// It is only inserted once the candidate is chosen, not when the
+    /// candidates are completed as far as they agree.
+    pub append: Option<char>
+}
+
+impl Completion {
+    pub fn new(replacement: String) -> Completion {
+        Completion {
+            replacement: replacement,
+            append: None
+        }
+    }
+
+    pub fn with_delimiter(replacement: String, append: char) -> Completion {
+        Completion {
+            replacement: replacement,
+            append: Some(append)
+        }
+    }
+}
+
+/// Provides completions for the text before the cursor.
+pub trait Completer {
+    /// Returns the byte offset where the text to be completed starts,
+    /// along with the candidates to replace it with.
+    ///
+    /// `pos` is the byte offset of the cursor in `line`.
+    fn complete(&self, line: &str, pos: usize) -> (usize, Vec<Completion>);
+}
+
+/// What `Tab` does when there is nothing to complete.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NoMatchHandling {
+    /// Ring the bell.
+    Bell,
+    /// Do nothing.
+    Ignore,
+    /// Insert a tab character.
+    InsertTab
+}
+
+/// The longest prefix shared by the replacements of all candidates.
+pub fn common_prefix(candidates: &[Completion]) -> &str {
+    let first = match candidates.first() {
+        Some(first) => &first.replacement,
+        None => return ""
+    };
+    let mut len = first.len();
+    for candidate in &candidates[1..] {
+        let candidate = &candidate.replacement;
+        len = first[..len].char_indices()
+            .zip(candidate.chars())
+            .find(|&((_, a), b)| a != b)
+            .map(|((i, _), _)| i)
+            .unwrap_or(len.min(candidate.len()));
+    }
+    &first[..len]
+}
+
+#[test]
+fn common_prefix_of_candidates() {
+    let candidates: Vec<Completion> = vec!["help", "hello", "helm"].into_iter()
+        .map(|s| Completion::new(s.to_owned()))
+        .collect();
+    assert_eq!(common_prefix(&candidates), "hel");
+    assert_eq!(common_prefix(&candidates[..1]), "help");
+    let candidates = vec![Completion::new("über".to_owned()), Completion::with_delimiter("übel".to_owned(), ' ')];
+    assert_eq!(common_prefix(&candidates), "übe");
+    assert_eq!(common_prefix(&[]), "");
+}
@@ -1,11 +1,22 @@
 use encoding::types::EncodingRef;
 
+use std::borrow::Cow;
+use std::mem;
 use std::u32;
+use std::collections::VecDeque;
+use enc;
 use error::Error;
 use history::{Cursor, History};
-use buffer::Buffer;
-use parser::{parse, ParseError, ParseSuccess};
+use buffer::{Buffer, DeleteContext, Position, Snapshot, Window, Wrapped};
+use parser::{parse, ParseError, ParseSuccess, Token, PASTE_START, PASTE_END};
+use builder::{Builder, CursorShape};
+use undo::UndoStack;
+use completion;
+use hint::Hinter;
+use validate::Validator;
+use clipboard::Clipboard;
 use instr;
+use Config;
 
 #[derive(Copy,Clone)]
 pub enum EditMode {
@@ -13,18 +24,135 @@ pub enum EditMode {
     Vi,
 }
 
+/// What `Backspace` does when the line is already empty.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EmptyBackspace {
+    /// Do nothing.
+    Ignore,
+    /// Ring the bell.
+    Bell,
+    /// Perform an instruction instead, such as cancelling the prompt.
+    /// An instruction that leads back to `Backspace` does nothing.
+    Run(instr::Instr)
+}
+
+/// What happens to control characters within typed or pasted text, as
+/// opposed to keys like `C-a` that are interpreted on their own.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ControlCharPolicy {
+    /// Remove them, turning tabs into spaces.
+    Strip,
+    /// Ignore text that contains any, ringing the bell.
+    Reject,
+    /// Insert them like any other character.
+    Keep
+}
+
+/// What the interrupt key `C-c` does.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InterruptAction {
+    /// Stop editing with `Error::Cancel`.
+    Cancel,
+    /// Abandon the line and continue with an empty one below it, showing
+    /// the cancel marker, like bash.
+    ClearLine,
+    /// Do nothing.
+    Ignore
+}
+
+/// What the end-of-file key `C-d` does.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EofAction {
+    /// End the input with `Error::EndOfFile` if the line is empty. On a
+    /// line with text, Emacs mode deletes the character under the cursor
+    /// and vi mode accepts the line.
+    Eof,
+    /// Delete the character under the cursor, so that the input is never
+    /// ended by a key.
+    Delete
+}
+
+/// How the typed text is shown.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EchoMode {
+    /// Show the text as it is.
+    Normal,
+    /// Show nothing, leaving the cursor after the prompt, as for passwords.
+    None,
+    /// Show the character in place of every character of the text.
+    Mask(char)
+}
+
+/// Where a line that does not fit into the terminal is broken onto the
+/// next row, once the width of the terminal is known.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WrapMode {
+    /// Break the line at the edge of the terminal, like the terminal does.
+    Char,
+    /// Break the line after the last space that fits on the row, unless a
+    /// word takes up the whole row. A space at the edge is not shown.
+    Word
+}
+
+/// The buffer as it is shown in the echo mode.
+fn echoed<'b>(buf: &'b Buffer, echo: EchoMode) -> Cow<'b, Buffer> {
+    match echo {
+        EchoMode::Normal => Cow::Borrowed(buf),
+        EchoMode::None => Cow::Owned(buf.masked(None)),
+        EchoMode::Mask(mask) => Cow::Owned(buf.masked(Some(mask)))
+    }
+}
+
+/// Replaces the line by a history entry.
+///
+/// An entry of continued input holds several lines, which are joined by
+/// spaces, as the line is edited on a single row of the terminal.
+fn recall(buf: &mut Buffer, entry: &str) {
+    buf.replace(&entry.replace('\n', " "));
+}
+
+/// The prompt with the input on the line below it, if the config puts
+/// the prompt on a line of its own.
+fn separate_prompt(cfg: &Config, prompt: &str) -> Option<String> {
+    if cfg.prompt_on_separate_line {
+        Some(format!("{}\n", prompt))
+    } else {
+        None
+    }
+}
+
+/// Apply the policy to the text of a token, returning `None` if the
+/// token is rejected.
+fn filter_control_chars(token: Token, policy: ControlCharPolicy) -> Option<Token> {
+    let filter = |text: String| match policy {
+        ControlCharPolicy::Strip => Some(text.chars().filter_map(|c| match c {
+            '\t' => Some(' '),
+            c if c.is_control() => None,
+            c => Some(c)
+        }).collect()),
+        ControlCharPolicy::Reject if text.chars().any(char::is_control) => None,
+        _ => Some(text)
+    };
+    match token {
+        Token::Text(text) => filter(text).map(Token::Text),
+        Token::Paste(text) => filter(text).map(Token::Paste),
+        token => Some(token)
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum ModeState {
-    Emacs,
+    Emacs(EmacsMode, u32),
     Vi(ViMode, u32),
 }
 
 impl ModeState {
     pub fn new(mode: EditMode) -> Self {
         match mode {
-            EditMode::Emacs => ModeState::Emacs,
+            EditMode::Emacs => ModeState::Emacs(EmacsMode::Normal, 0),
             // vi mode should start in insert mode
-            EditMode::Vi => ModeState::Vi(ViMode::Insert, 0),
+            EditMode::Vi if cfg!(feature = "vi-mode") => ModeState::Vi(ViMode::Insert, 0),
+            EditMode::Vi => ModeState::Emacs(EmacsMode::Normal, 0),
         }
     }
 
@@ -37,17 +165,33 @@ impl ModeState {
         }
     }
 
-    fn with_vi_count(&self, count: u32) -> Self {
-        if let ModeState::Vi(mode, _) = *self {
-            ModeState::Vi(mode, count)
+    fn with_count(&self, count: u32) -> Self {
+        match *self {
+            ModeState::Emacs(mode, _) => ModeState::Emacs(mode, count),
+            ModeState::Vi(mode, _) => ModeState::Vi(mode, count),
         }
-        else {
-            *self
+    }
+
+    /// The vi count or Emacs numeric argument, which is 0 if none was given.
+    fn count(&self) -> u32 {
+        match *self {
+            ModeState::Emacs(_, count) | ModeState::Vi(_, count) => count,
         }
     }
 
 }
 
+#[derive(Copy, Clone, PartialEq)]
+pub enum EmacsMode {
+    Normal,
+    CtrlX,
+    Search,
+    /// A numeric argument is being entered.
+    Argument,
+    /// `C-u` was given as the numeric argument, which digits replace.
+    UniversalArgument,
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum ViMode {
     Insert,
@@ -56,8 +200,20 @@ pub enum ViMode {
     MoveChar(instr::CharMoveType),
     DeleteMoveChar(instr::CharMoveType),
     ChangeMoveChar(instr::CharMoveType),
+    YankMoveChar(instr::CharMoveType),
     Delete,
     Change,
+    Yank,
+    Visual,
+    VisualMoveChar(instr::CharMoveType),
+    G,
+    DeleteG,
+    ChangeG,
+    YankG,
+    VisualG,
+    DeleteTextObject(instr::TextObjectScope),
+    ChangeTextObject(instr::TextObjectScope),
+    YankTextObject(instr::TextObjectScope),
 }
 
 /// Set a new vi mode based on the current vi mode.
@@ -69,42 +225,723 @@ fn next_vi_mode(mode_state: ModeState) -> ModeState {
         ModeState::Vi(ViMode::Change, _) => ModeState::Vi(ViMode::Insert, 0),
         ModeState::Vi(ViMode::DeleteMoveChar(_), _) => ModeState::Vi(ViMode::Normal, 0),
         ModeState::Vi(ViMode::ChangeMoveChar(_), _) => ModeState::Vi(ViMode::Insert, 0),
+        ModeState::Vi(ViMode::Yank, _) => ModeState::Vi(ViMode::Normal, 0),
+        ModeState::Vi(ViMode::YankMoveChar(_), _) => ModeState::Vi(ViMode::Normal, 0),
+        // movements extend the selection in visual mode
+        ModeState::Vi(ViMode::Visual, _) => ModeState::Vi(ViMode::Visual, 0),
+        ModeState::Vi(ViMode::VisualMoveChar(_), _) => ModeState::Vi(ViMode::Visual, 0),
+        ModeState::Vi(ViMode::DeleteG, _) => ModeState::Vi(ViMode::Normal, 0),
+        ModeState::Vi(ViMode::ChangeG, _) => ModeState::Vi(ViMode::Insert, 0),
+        ModeState::Vi(ViMode::YankG, _) => ModeState::Vi(ViMode::Normal, 0),
+        ModeState::Vi(ViMode::VisualG, _) => ModeState::Vi(ViMode::Visual, 0),
+        ModeState::Vi(ViMode::DeleteTextObject(_), _) => ModeState::Vi(ViMode::Normal, 0),
+        ModeState::Vi(ViMode::ChangeTextObject(_), _) => ModeState::Vi(ViMode::Insert, 0),
+        ModeState::Vi(ViMode::YankTextObject(_), _) => ModeState::Vi(ViMode::Normal, 0),
+        // movement keys like the arrows are available in insert mode
+        ModeState::Vi(ViMode::Insert, _) => ModeState::Vi(ViMode::Insert, 0),
         ModeState::Vi(_, _) => ModeState::Vi(ViMode::Normal, 0),
         // emacs mode is always emacs mode
-        ModeState::Emacs => ModeState::Emacs,
+        ModeState::Emacs(_, _) => ModeState::Emacs(EmacsMode::Normal, 0),
     }
 }
 
+/// An incremental search through the history.
+struct Search {
+    query: String,
+    /// The index of the history entry that matched last.
+    found: Option<usize>,
+    /// Whether the query matches no entry from the last match on.
+    failed: bool,
+    /// The line as it was before searching.
+    before: Snapshot,
+}
+
+/// A change made from vi normal mode, recorded so it can be repeated.
+#[derive(Clone)]
+struct Change {
+    /// The keys that made the change, one entry per token.
+    keys: Vec<Vec<u8>>,
+    count: u32,
+    /// The buffer contents before the change.
+    before: String,
+}
+
 pub struct EditCtx<'a> {
-    buf: Buffer,
+    state: LineState,
     history_cursor: Cursor<'a>,
     prompt: &'a str,
-    seq: Vec<u8>,
+    cfg: &'a Config,
     enc: EncodingRef,
+    hinter: Option<&'a Hinter>,
+    validator: Option<&'a Validator>,
+    /// Provides the prompt anew for every render.
+    prompt_fn: Option<&'a mut FnMut() -> String>,
+    /// The prompt that replaces the one the line was started with.
+    refreshed_prompt: Option<String>,
+    /// Called with the input that is dropped because it cannot be parsed.
+    on_parse_error: Option<&'a mut FnMut(&[u8])>,
+    /// The output that leaves a discarded or continued line, shown before
+    /// the next render.
+    discarded: Vec<u8>,
+    /// Whether the instruction for `Backspace` on an empty line is being
+    /// handled, so that it cannot run itself again.
+    in_empty_backspace: bool,
+}
+
+/// The state of the line being edited, which does not borrow anything
+/// and is kept by `EditState` while no input is available.
+struct LineState {
+    buf: Buffer,
+    seq: VecDeque<u8>,
     mode_state: ModeState,
+    undo: UndoStack,
+    clipboard: Clipboard,
+    /// Where the text inserted by the last yank starts, if the previous
+    /// instruction was a yank.
+    yanked: Option<Position>,
+    recording: Option<Change>,
+    last_change: Option<Change>,
+    /// The last `f`, `F`, `t` or `T` movement, repeated by `;` and `,`.
+    last_char_move: Option<instr::Instr>,
+    /// The cursor shape that was last set.
+    cursor_shape: Option<CursorShape>,
+    /// Where the selection started when visual mode was entered.
+    visual_anchor: Position,
+    /// Where the selection started when Shift-Left or Shift-Right was
+    /// pressed in Emacs mode, for as long as they are pressed.
+    mark: Option<Position>,
+    /// Whether the lines of the prompt above the input have been shown.
+    prompt_shown: bool,
+    /// Whether the last instruction had no effect.
+    bell: bool,
+    /// Whether the busy indicator is shown for a pending completion.
+    busy: bool,
+    /// The key that accepted the line.
+    accepted_by: Option<Token>,
+    search: Option<Search>,
+    /// The width of the terminal, if known.
+    width: Option<usize>,
+    /// The column of the line that was shown first when it was last
+    /// scrolled horizontally.
+    scroll: usize,
+    /// The rows the line was shown on by the last render, if it did not
+    /// fit on the row of the prompt.
+    wrapped: Option<Wrapped>,
+    /// Whether the process should be suspended before the next render is shown.
+    suspend_requested: bool,
+    /// Whether the line should be edited in an external editor before the
+    /// next render is shown.
+    external_edit_requested: bool,
+    /// The lines of the input that were finished while it was incomplete.
+    continued: Vec<String>,
+    /// The text to show on the line below the input.
+    info: Option<String>,
+    /// Whether the line below the input shows text that has to be erased.
+    info_shown: bool,
+    /// Whether editing was finished by the end of the input.
+    input_ended: bool,
+    /// Pasted text that is inserted once the user confirms it.
+    pending_paste: Option<String>,
+    /// Whether the next key is inserted literally.
+    quoted_insert: bool,
+    /// The line `Config::on_change` was last called with.
+    observed: String,
+}
+
+impl LineState {
+    fn new(cfg: &Config) -> Self {
+        let mut buf = Buffer::with_capacity(cfg.initial_capacity);
+        buf.set_word_chars(&cfg.word_chars);
+        LineState {
+            buf: buf,
+            seq: VecDeque::new(),
+            mode_state: ModeState::new(cfg.mode),
+            undo: UndoStack::new(),
+            clipboard: Clipboard::new(cfg.kill_ring_size),
+            yanked: None,
+            recording: None,
+            last_change: None,
+            last_char_move: None,
+            cursor_shape: None,
+            visual_anchor: Position::new(),
+            mark: None,
+            prompt_shown: false,
+            bell: false,
+            busy: false,
+            accepted_by: None,
+            search: None,
+            width: None,
+            scroll: 0,
+            wrapped: None,
+            suspend_requested: false,
+            external_edit_requested: false,
+            continued: vec![],
+            info: None,
+            info_shown: false,
+            input_ended: false,
+            pending_paste: None,
+            quoted_insert: false,
+            observed: String::new(),
+        }
+    }
+}
+
+/// The state of an `EditCtx`, kept while no input is available.
+pub struct EditState {
+    state: LineState,
+    history_pos: Option<usize>,
 }
 
 impl<'a> EditCtx<'a> {
 
-    pub fn new(prompt: &'a str, history: &'a History, enc: EncodingRef, mode: EditMode) -> Self {
+    pub fn new(prompt: &'a str, history: &'a History, cfg: &'a Config) -> Self {
+        EditCtx::with_state(LineState::new(cfg), Cursor::new(history), prompt, cfg)
+    }
+
+    /// Continue editing from a state returned by `suspend`.
+    pub fn resume(state: EditState, prompt: &'a str, history: &'a History, cfg: &'a Config) -> Self {
+        EditCtx::with_state(state.state, Cursor::at(history, state.history_pos), prompt, cfg)
+    }
+
+    fn with_state(state: LineState, history_cursor: Cursor<'a>, prompt: &'a str, cfg: &'a Config) -> Self {
         EditCtx {
-            buf: Buffer::new(),
-            history_cursor: Cursor::new(history),
+            state: state,
+            history_cursor: history_cursor,
             prompt: prompt,
-            seq: Vec::new(),
-            enc: enc,
-            mode_state: ModeState::new(mode),
+            cfg: cfg,
+            enc: enc::to_encoding_ref(&cfg.encoding),
+            hinter: None,
+            validator: None,
+            prompt_fn: None,
+            refreshed_prompt: separate_prompt(cfg, prompt),
+            on_parse_error: None,
+            discarded: vec![],
+            in_empty_backspace: false,
+        }
+    }
+
+    /// Release the borrowed history and config, keeping the state of the
+    /// line being edited.
+    pub fn suspend(self) -> EditState {
+        EditState {
+            state: self.state,
+            history_pos: self.history_cursor.position(),
         }
     }
 
+    /// The length of the input that has not been handled yet.
+    pub fn pending_input(&self) -> usize {
+        self.state.seq.len()
+    }
+
+    /// Take the input that has not been handled yet.
+    pub fn take_input(&mut self) -> Vec<u8> {
+        ::std::mem::replace(&mut self.state.seq, VecDeque::new()).into_iter().collect()
+    }
+
+    /// Delete the text covered by a movement and add it to the kill ring.
+    fn kill<F>(&mut self, movement: F) where F: FnOnce(&mut Buffer) {
+        let text = {
+            let mut dc = self.state.buf.start_delete();
+            movement(&mut dc);
+            dc.delete()
+        };
+        self.state.clipboard.kill(text);
+    }
+
+    pub fn config(&self) -> &'a Config {
+        self.cfg
+    }
+
+    /// The key that accepted the line, if it was accepted by a key
+    /// rather than the end of the input.
+    pub fn accepted_by(&self) -> Option<&Token> {
+        self.state.accepted_by.as_ref()
+    }
+
+    /// Whether editing was finished by the end of the input, rather than
+    /// by a key.
+    pub fn input_ended(&self) -> bool {
+        self.state.input_ended
+    }
+
+    /// Inserts text at the cursor, such as to start with a line that is
+    /// already filled in.
+    pub fn insert(&mut self, text: &str) {
+        self.state.buf.insert_chars_at_cursor(text);
+    }
+
+    /// The byte range and text of the word the cursor is on, see
+    /// `Buffer::current_word`.
+    pub fn current_word(&self) -> Option<(usize, usize, &str)> {
+        self.state.buf.current_word()
+    }
+
+    /// The length of the line in bytes.
+    pub fn byte_len(&self) -> usize {
+        self.state.buf.byte_len()
+    }
+
+    /// The length of the line in graphemes.
+    pub fn grapheme_len(&self) -> usize {
+        self.state.buf.grapheme_len()
+    }
+
+    /// The column of the terminal that the cursor is placed at when the
+    /// line is rendered, counting from 0.
+    pub fn cursor_col(&self) -> usize {
+        let prompt = self.search_prompt().map_or(self.prompt(), Cow::Owned);
+        let prompt = prompt.as_ref();
+        match self.wrap(prompt) {
+            Some(wrapped) => wrapped.cursor.1,
+            None => self.shown().cursor_col(prompt, self.window(prompt)),
+        }
+    }
+
+    /// The rows the line is shown on, if it is wrapped rather than
+    /// scrolled and does not fit on the row of the prompt, or did not
+    /// when it was last shown.
+    fn wrap(&self, prompt: &str) -> Option<Wrapped> {
+        match self.state.width {
+            Some(width) if !self.cfg.horizontal_scroll => {
+                let wrapped = self.shown().wrap(prompt, width, self.cfg.wrap_mode);
+                if wrapped.last_row() > 0 || self.state.wrapped.is_some() { Some(wrapped) } else { None }
+            }
+            _ => None,
+        }
+    }
+
+    /// Show text after the end of the line as it was last shown, leaving
+    /// the cursor after it.
+    fn trailer(&self, text: &str) -> Vec<u8> {
+        match self.state.wrapped {
+            Some(ref wrapped) => wrapped.get_trailer(text),
+            None => self.shown().get_trailer(&self.prompt(), text, self.window(&self.prompt())),
+        }
+    }
+
+    /// Returns the sequence that moves the cursor to the end of a line
+    /// that was wrapped onto several rows, where the output that follows
+    /// the line has to start.
+    pub fn leave_rows(&mut self) -> Vec<u8> {
+        let seq = self.trailer("");
+        if let Some(ref mut wrapped) = self.state.wrapped {
+            wrapped.move_to_end();
+            return seq;
+        }
+        vec![]
+    }
+
+    /// The part of the line that is shown after the prompt, if the line
+    /// is scrolled horizontally.
+    fn window(&self, prompt: &str) -> Option<Window> {
+        match self.state.width {
+            Some(width) if self.cfg.horizontal_scroll => Some(self.shown().window(prompt, width, self.state.scroll)),
+            _ => None,
+        }
+    }
+
+    /// The line as it is shown, hiding the text unless it is echoed.
+    fn shown<'b>(&'b self) -> Cow<'b, Buffer> {
+        echoed(&self.state.buf, self.cfg.echo)
+    }
+
+    /// Sets the width of the terminal, which is needed to scroll long lines.
+    pub fn set_width(&mut self, width: Option<usize>) {
+        self.state.width = width;
+    }
+
+    /// Sets the hinter that provides the hint shown after the line.
+    pub fn set_hinter(&mut self, hinter: Option<&'a Hinter>) {
+        self.hinter = hinter;
+    }
+
+    /// Sets the validator that decides whether the input is complete when
+    /// it is accepted.
+    pub fn set_validator(&mut self, validator: Option<&'a Validator>) {
+        self.validator = validator;
+    }
+
+    /// Sets the function that provides the prompt for every render.
+    pub fn set_prompt_fn(&mut self, prompt_fn: Option<&'a mut FnMut() -> String>) {
+        self.prompt_fn = prompt_fn;
+    }
+
+    /// Sets the function that is called with the input that is dropped
+    /// because it cannot be parsed.
+    pub fn set_on_parse_error(&mut self, on_parse_error: Option<&'a mut FnMut(&[u8])>) {
+        self.on_parse_error = on_parse_error;
+    }
+
+    /// Call `Config::on_change` if the line changed since it was last
+    /// called.
+    fn notify_change(&mut self) {
+        if let Some(ref on_change) = self.cfg.on_change {
+            let line = self.state.buf.as_str();
+            if line != self.state.observed {
+                (*on_change.borrow_mut())(line, self.state.buf.byte_pos());
+                self.state.observed = line.to_owned();
+            }
+        }
+    }
+
+    /// Replaces the prompt from the next render on.
+    pub fn set_prompt(&mut self, prompt: String) {
+        self.refreshed_prompt = Some(separate_prompt(self.cfg, &prompt).unwrap_or(prompt));
+    }
+
+    /// The prompt that is currently shown before the line, followed by
+    /// the number of the line if enabled.
+    ///
+    /// Line numbers are right-aligned to two columns, as lines that are
+    /// already finished are not redrawn to make room for wider ones.
+    fn prompt<'b>(&'b self) -> Cow<'b, str> {
+        let prompt = if !self.state.continued.is_empty() {
+            &self.cfg.continuation_prompt
+        } else {
+            self.refreshed_prompt.as_ref().map_or(self.prompt, |p| p.as_str())
+        };
+        if self.cfg.show_line_numbers {
+            Cow::Owned(format!("{}{:>2} ", prompt, self.state.continued.len() + 1))
+        } else {
+            Cow::Borrowed(prompt)
+        }
+    }
+
+    /// Accept the input, unless the validator finds it incomplete, in
+    /// which case the line is finished and editing continues on the next.
+    fn accept(&mut self) -> EditResult<bool> {
+        let input = self.input();
+        if !cfg!(feature = "validation") || self.validator.map_or(true, |validator| validator.is_complete(&input)) {
+            self.state.buf.drain();
+            return EditResult::Halt(Ok(input));
+        }
+        let mut seq = self.clear_info();
+        seq.extend(self.trailer(""));
+        let mut line = Builder::new();
+        line.erase_to_right();
+        line.newline();
+        seq.extend(line.build());
+        self.discarded.extend(seq);
+        self.state.continued.push(self.state.buf.drain());
+        self.state.mode_state = ModeState::new(self.cfg.mode);
+        self.state.undo = UndoStack::new();
+        self.state.prompt_shown = false;
+        self.state.wrapped = None;
+        EditResult::Cont(false)
+    }
+
+    /// The continued lines and the line being edited, joined by newlines.
+    fn input(&self) -> String {
+        let mut lines = self.state.continued.clone();
+        lines.push(self.state.buf.as_str().to_owned());
+        lines.join("\n")
+    }
+
+    /// Ends the input without validating it, keeping continued lines
+    /// but not an empty line they are followed by.
+    fn end(&mut self) -> Result<String, Error> {
+        if self.state.continued.is_empty() && self.state.buf.is_empty() {
+            return Err(Error::EndOfFile);
+        }
+        let input = if self.state.buf.is_empty() { self.state.continued.join("\n") } else { self.input() };
+        self.state.buf.drain();
+        Ok(input)
+    }
+
+    /// Whether the process should be suspended, resetting the request.
+    pub fn take_suspend_request(&mut self) -> bool {
+        let requested = self.state.suspend_requested;
+        self.state.suspend_requested = false;
+        requested
+    }
+
+    /// The line to edit in an external editor, if that was requested,
+    /// resetting the request.
+    pub fn take_external_edit_request(&mut self) -> Option<String> {
+        if !self.state.external_edit_requested || self.cfg.echo != EchoMode::Normal {
+            return None;
+        }
+        self.state.external_edit_requested = false;
+        Some(self.state.buf.as_str().to_owned())
+    }
+
+    /// Replaces the line with the text from the external editor, as a
+    /// change that can be undone. Only the first line of it is kept.
+    pub fn finish_external_edit(&mut self, text: &str) {
+        let before = self.state.buf.snapshot();
+        self.state.buf.replace(text.lines().next().unwrap_or(""));
+        if before.as_str() != self.state.buf.as_str() {
+            self.state.undo.push(before, false);
+        }
+    }
+
+    /// Whether a slow instruction is about to be handled, so the output
+    /// should be shown before waiting for more input.
+    pub fn is_busy(&self) -> bool {
+        self.state.busy
+    }
+
+    /// Find the query of the search in the history, starting at the
+    /// entry with index from, and show the match.
+    fn search(&mut self, from: usize) -> bool {
+        let history = self.history_cursor.history();
+        let search = match self.state.search {
+            Some(ref mut search) => search,
+            None => return false,
+        };
+        match history.find(&search.query, from) {
+            Some(idx) => {
+                let entry = history.get(idx).unwrap();
+                let offset = entry.find(search.query.as_str()).unwrap_or(0);
+                recall(&mut self.state.buf, entry);
+                self.state.buf.move_start();
+                while self.state.buf.byte_pos() < offset && self.state.buf.move_right() {}
+                search.found = Some(idx);
+                search.failed = false;
+                true
+            }
+            None => {
+                search.failed = true;
+                false
+            }
+        }
+    }
+
+    /// The prompt shown while searching, which counts the matches.
+    fn search_prompt(&self) -> Option<String> {
+        self.state.search.as_ref().map(|search| {
+            let history = self.history_cursor.history();
+            let count = match search.found {
+                Some(idx) if !search.query.is_empty() => {
+                    let current = history.search(&search.query).take_while(|&i| i <= idx).count();
+                    format!("({}/{}) ", current, history.count_matches(&search.query))
+                }
+                _ => String::new(),
+            };
+            let failed = if search.failed { "failed " } else { "" };
+            format!("{}({}reverse-i-search)`{}': ", count, failed, search.query)
+        })
+    }
+
+    /// Complete the text before the cursor.
+    ///
+    /// A single candidate is inserted as a whole, followed by its
+    /// delimiter, while several are only completed as far as they agree.
+    fn complete(&mut self) {
+        let completer = match self.cfg.completer {
+            Some(ref completer) if cfg!(feature = "completion") => completer,
+            _ => return self.no_match(),
+        };
+        let (start, candidates) = completer.complete(self.state.buf.as_str(), self.state.buf.byte_pos());
+        if candidates.is_empty() {
+            return self.no_match();
+        }
+        let prefix = completion::common_prefix(&candidates);
+        let typed = self.state.buf.as_str().get(start..self.state.buf.byte_pos()).unwrap_or("");
+        if candidates.len() > 1 && prefix.len() <= typed.len() {
+            self.state.bell = true;
+            return;
+        }
+        self.state.buf.replace_before_cursor(start, prefix);
+        if candidates.len() == 1 {
+            if let Some(c) = candidates[0].append {
+                self.state.buf.insert_char_at_cursor(c);
+            }
+        }
+    }
+
+    /// Handle `Tab` when there is nothing to complete.
+    fn no_match(&mut self) {
+        match self.cfg.no_match {
+            completion::NoMatchHandling::Bell => self.state.bell = true,
+            completion::NoMatchHandling::Ignore => {}
+            completion::NoMatchHandling::InsertTab => self.state.buf.insert_char_at_cursor('\t'),
+        }
+    }
+
+    /// List the completions of the text before the cursor on the info line.
+    fn show_candidates(&mut self) {
+        self.state.info = match self.cfg.completer {
+            Some(ref completer) if !self.state.buf.is_empty() => {
+                let (_, candidates) = completer.complete(self.state.buf.as_str(), self.state.buf.byte_pos());
+                let names: Vec<&str> = candidates.iter().map(|c| c.replacement.as_str()).collect();
+                Some(names.join("  "))
+            }
+            _ => None,
+        };
+    }
+
+    /// Returns the sequence that erases the info line, if it is shown.
+    pub fn clear_info(&mut self) -> Vec<u8> {
+        if self.state.info_shown {
+            self.state.info_shown = false;
+            match self.state.wrapped {
+                Some(ref wrapped) => wrapped.get_info(""),
+                None => self.shown().get_info(&self.prompt(), "", self.window(&self.prompt())),
+            }
+        } else {
+            vec![]
+        }
+    }
+
+    /// Adds the bytes of a read to the input.
+    ///
+    /// If large pastes are to be confirmed, a read of more characters than
+    /// the threshold that holds no escape sequences is taken for text
+    /// pasted without bracketed paste, and is handled like a bracketed
+    /// paste, rather than as keys.
     pub fn fill<I>(&mut self, it: I) where I: IntoIterator<Item=u8> {
-        self.seq.extend(it)
+        let bytes: Vec<u8> = it.into_iter().collect();
+        if self.state.seq.is_empty() && !bytes.contains(&0x1b) && self.is_burst(&bytes) {
+            self.state.seq.extend(PASTE_START);
+            self.state.seq.extend(bytes);
+            self.state.seq.extend(PASTE_END);
+        } else {
+            self.state.seq.extend(bytes);
+        }
     }
 
-    /// Ignore one past the end of the line in vi normal mode.
+    /// Whether the bytes hold more characters than the paste threshold.
+    fn is_burst(&self, bytes: &[u8]) -> bool {
+        let threshold = match self.cfg.paste_warn_threshold {
+            Some(threshold) if bytes.len() > threshold => threshold,
+            _ => return false,
+        };
+        let mut text = String::new();
+        self.enc.raw_decoder().raw_feed(bytes, &mut text);
+        text.chars().count() > threshold
+    }
+
+    /// Ignore one past the end of the line in vi normal and visual mode.
     fn exclude_eol(&mut self) {
-        if let ModeState::Vi(ViMode::Normal, _) = self.mode_state {
-            self.buf.exclude_eol();
+        match self.state.mode_state {
+            ModeState::Vi(ViMode::Normal, _) | ModeState::Vi(ViMode::Visual, _) => {
+                self.state.buf.exclude_eol();
+            }
+            _ => {}
+        }
+    }
+
+    /// The start and end of the selection, if there is one.
+    ///
+    /// The selection of vi mode includes the character under the cursor,
+    /// while the one of Emacs mode ends before it.
+    fn selection(&self) -> Option<(Position, Position)> {
+        match self.state.mode_state {
+            ModeState::Vi(ViMode::Visual, _)
+            | ModeState::Vi(ViMode::VisualMoveChar(_), _)
+            | ModeState::Vi(ViMode::VisualG, _) => Some(self.state.buf.selection(self.state.visual_anchor)),
+            ModeState::Emacs(_, _) => self.state.mark.map(|mark| {
+                let pos = self.state.buf.pos();
+                if mark < pos { (mark, pos) } else { (pos, mark) }
+            }),
+            _ => None,
+        }
+    }
+
+    /// Set the cursor shape configured for the current vi mode, if it changed.
+    fn update_cursor_shape(&mut self, line: &mut Builder) {
+        let shape = match self.state.mode_state {
+            ModeState::Vi(ViMode::Insert, _) => self.cfg.vi_insert_cursor,
+            ModeState::Vi(_, _) => self.cfg.vi_normal_cursor,
+            ModeState::Emacs(_, _) => None,
+        };
+        if shape != self.state.cursor_shape {
+            line.set_cursor_shape(shape.unwrap_or(CursorShape::Default));
+            self.state.cursor_shape = shape;
+        }
+    }
+
+    /// Returns the sequence that renders the accepted line once more
+    /// without hints or a right prompt, if enabled, so that only the line
+    /// itself is left on the screen.
+    pub fn final_line(&self, line: &str) -> Vec<u8> {
+        if !self.cfg.redraw_on_accept {
+            return vec![];
+        }
+        // only the last line of continued input is shown
+        let line = if self.state.continued.is_empty() { line } else { line.rsplit('\n').next().unwrap_or(line) };
+        let mut buf = Buffer::new();
+        buf.replace(line);
+        let shown = echoed(&buf, self.cfg.echo);
+        match (self.state.width, self.state.wrapped.as_ref()) {
+            (Some(width), Some(wrapped)) => {
+                let rows = shown.wrap(&self.prompt(), width, self.cfg.wrap_mode);
+                let mut seq = wrapped.get_first_row();
+                seq.extend(shown.get_wrapped_line(&self.prompt(), false, false, None, self.cfg.mark_trailing_space, &rows));
+                seq
+            }
+            _ => shown.get_line(&self.prompt(), false, false, None, self.cfg.mark_trailing_space, None),
+        }
+    }
+
+    /// Returns the sequence that shows the cancel marker after the line,
+    /// if enabled.
+    pub fn cancel_marker(&self) -> Vec<u8> {
+        if self.cfg.echo_cancel {
+            self.trailer(&self.cfg.cancel_marker)
+        } else {
+            vec![]
+        }
+    }
+
+    /// Returns the sequence that restores the terminal cursor shape.
+    pub fn restore_cursor_shape(&self) -> Vec<u8> {
+        let mut line = Builder::new();
+        if self.state.cursor_shape.is_some() {
+            line.set_cursor_shape(CursorShape::Default);
+        }
+        line.build()
+    }
+
+    /// The instruction that repeats the last character movement, possibly
+    /// in the opposite direction.
+    ///
+    /// Without one, a pending operator is cancelled.
+    fn repeat_char_move(&self, reverse: bool) -> instr::Instr {
+        let ins = match self.state.last_char_move {
+            Some(ref ins) if reverse => instr::reverse_char_move(ins),
+            Some(ref ins) => Some(ins.clone()),
+            None => None,
+        };
+        match ins {
+            Some(ins) => ins,
+            None if vi_operator_pending(self.state.mode_state) => instr::Instr::NormalMode,
+            None => instr::Instr::Common(instr::CommonInstr::Noop),
+        }
+    }
+
+    /// Record the keys of an instruction that may be part of a change.
+    ///
+    /// Every instruction issued from vi normal mode starts a new
+    /// recording, which is kept as the last change once vi returns to
+    /// normal mode with a modified buffer.
+    fn record(&mut self, keys: &[u8], ins: &instr::Instr) {
+        if let ModeState::Vi(ViMode::Normal, count) = self.state.mode_state {
+            self.state.recording = match *ins {
+                instr::Instr::RepeatLastChange
+                | instr::Instr::Undo
+                | instr::Instr::History(_) => None,
+                _ => Some(Change {
+                    keys: vec![],
+                    count: count,
+                    before: self.state.buf.as_str().to_owned(),
+                }),
+            };
+        }
+        if let Some(ref mut change) = self.state.recording {
+            change.keys.push(keys.to_vec());
+        }
+    }
+
+    fn finish_recording(&mut self) {
+        if let ModeState::Vi(ViMode::Normal, _) = self.state.mode_state {
+            if let Some(change) = self.state.recording.take() {
+                if change.before != self.state.buf.as_str() {
+                    self.state.last_change = Some(change);
+                }
+            }
         }
     }
 }
@@ -114,106 +951,222 @@ pub enum EditResult<C> {
     Halt(Result<String, Error>)
 }
 
-macro_rules! vi_repeat {
+macro_rules! repeat {
     ( $ctx:ident, $x:expr ) => {
-        match $ctx.mode_state {
-            ModeState::Emacs => { $x; }
-            ModeState::Vi(mode, count) => {
-                match count {
-                    0 => { $x; }
-                    _ => for _ in 0..count {
-                        if !$x {
-                            break;
-                        }
-                    },
+        match $ctx.state.mode_state.count() {
+            0 => { $x; }
+            count => for _ in 0..count {
+                if !$x {
+                    break;
                 }
-                $ctx.mode_state = ModeState::Vi(mode, 0);
-            }
+            },
         }
+        $ctx.state.mode_state = $ctx.state.mode_state.with_count(0);
     };
 }
 
 macro_rules! vi_delete {
     ( $ctx:ident with $dc:ident $x:expr ) => {
-        vi_repeat!($ctx, $x);
-        match $ctx.mode_state {
-            ModeState::Vi(ViMode::Delete, _)
-            | ModeState::Vi(ViMode::Change, _) => {
-                $dc.delete();
-            }
-            _ => {}
-        }
-        $ctx.mode_state = next_vi_mode($ctx.mode_state);
+        repeat!($ctx, $x);
+        vi_operate($ctx.state.mode_state, $dc, &mut $ctx.state.clipboard);
+        $ctx.state.mode_state = next_vi_mode($ctx.state.mode_state);
     };
 }
 
+/// Apply the pending vi operator, if any, to the text covered by a movement.
+///
+/// Deleted and yanked text is stored in the clipboard.
+fn vi_operate(mode_state: ModeState, dc: DeleteContext, clipboard: &mut Clipboard) {
+    match mode_state {
+        ModeState::Vi(ViMode::Delete, _)
+        | ModeState::Vi(ViMode::Change, _)
+        | ModeState::Vi(ViMode::DeleteMoveChar(_), _)
+        | ModeState::Vi(ViMode::ChangeMoveChar(_), _)
+        | ModeState::Vi(ViMode::DeleteG, _)
+        | ModeState::Vi(ViMode::ChangeG, _)
+        | ModeState::Vi(ViMode::DeleteTextObject(_), _)
+        | ModeState::Vi(ViMode::ChangeTextObject(_), _) => {
+            clipboard.set(dc.delete(), false);
+        }
+        ModeState::Vi(ViMode::Yank, _)
+        | ModeState::Vi(ViMode::YankMoveChar(_), _)
+        | ModeState::Vi(ViMode::YankG, _)
+        | ModeState::Vi(ViMode::YankTextObject(_), _) => {
+            clipboard.set(dc.yank(), false);
+        }
+        _ => {}
+    }
+}
+
+/// Whether an operator is waiting for a movement.
+fn vi_operator_pending(mode_state: ModeState) -> bool {
+    match mode_state {
+        ModeState::Vi(ViMode::Delete, _)
+        | ModeState::Vi(ViMode::Change, _)
+        | ModeState::Vi(ViMode::Yank, _)
+        | ModeState::Vi(ViMode::DeleteMoveChar(_), _)
+        | ModeState::Vi(ViMode::ChangeMoveChar(_), _)
+        | ModeState::Vi(ViMode::YankMoveChar(_), _)
+        | ModeState::Vi(ViMode::DeleteG, _)
+        | ModeState::Vi(ViMode::ChangeG, _)
+        | ModeState::Vi(ViMode::YankG, _)
+        | ModeState::Vi(ViMode::DeleteTextObject(_), _)
+        | ModeState::Vi(ViMode::ChangeTextObject(_), _)
+        | ModeState::Vi(ViMode::YankTextObject(_), _) => true,
+        _ => false,
+    }
+}
+
+/// Whether an instruction only moves the cursor within the line.
+fn is_movement(ins: &instr::Instr) -> bool {
+    match *ins {
+        instr::Instr::MoveCursor(instr::MoveCursorInstr::Left)
+        | instr::Instr::MoveCursor(instr::MoveCursorInstr::Right)
+        | instr::Instr::MoveEndOfWordRight
+        | instr::Instr::MoveEndOfWordWsRight
+        | instr::Instr::MoveWordRight
+        | instr::Instr::MoveWordWsRight
+        | instr::Instr::MoveWordLeft
+        | instr::Instr::MoveWordWsLeft
+        | instr::Instr::EmacsMoveWordRight
+        | instr::Instr::EmacsMoveWordLeft
+        | instr::Instr::MoveCharRight(_)
+        | instr::Instr::MoveCharLeft(_)
+        | instr::Instr::MoveBeforeCharRight(_)
+        | instr::Instr::MoveBeforeCharLeft(_)
+        | instr::Instr::RepeatCharMove
+        | instr::Instr::RepeatCharMoveReverse => true,
+        _ => false,
+    }
+}
+
 fn handle_common<'a>(ctx: &mut EditCtx<'a>, cinstr: instr::CommonInstr) -> EditResult<bool> {
     match cinstr {
-        instr::CommonInstr::Done => EditResult::Halt(Ok(ctx.buf.drain())),
-        instr::CommonInstr::Noop => EditResult::Cont(false),
+        instr::CommonInstr::Done => ctx.accept(),
+        instr::CommonInstr::Noop => {
+            ctx.state.bell = true;
+            EditResult::Cont(false)
+        },
+        instr::CommonInstr::Ignore => EditResult::Cont(false),
         instr::CommonInstr::Cancel => EditResult::Halt(Err(Error::Cancel)),
-        instr::CommonInstr::Clear => EditResult::Cont(true)
+        instr::CommonInstr::Clear => EditResult::Cont(true),
+        instr::CommonInstr::DiscardLine => {
+            let mut seq = ctx.clear_info();
+            seq.extend(ctx.trailer(&ctx.cfg.cancel_marker));
+            let mut line = Builder::new();
+            line.erase_to_right();
+            line.newline();
+            seq.extend(line.build());
+            ctx.discarded.extend(seq);
+            ctx.state.buf.drain();
+            ctx.state.continued.clear();
+            ctx.history_cursor = Cursor::new(ctx.history_cursor.history());
+            ctx.state.mode_state = ModeState::new(ctx.cfg.mode);
+            ctx.state.undo = UndoStack::new();
+            ctx.state.prompt_shown = false;
+            ctx.state.wrapped = None;
+            EditResult::Cont(false)
+        }
+        instr::CommonInstr::Suspend => {
+            ctx.state.suspend_requested = true;
+            // the prompt is drawn anew once the process is resumed
+            ctx.state.prompt_shown = false;
+            EditResult::Cont(false)
+        }
     }
 }
 
 fn handle_history<'a>(ctx: &mut EditCtx<'a>, hinstr: instr::HistoryInstr) -> EditResult<bool> {
     match hinstr {
         instr::HistoryInstr::Prev => {
-            vi_repeat!(ctx, {
+            if ctx.history_cursor.is_empty() && ctx.cfg.bell_on_empty_history {
+                ctx.state.bell = true;
+            }
+            repeat!(ctx, {
                 let end = ctx.history_cursor.incr();
                 if end {
-                    ctx.buf.swap()
+                    ctx.state.buf.swap()
                 }
-                ctx.history_cursor.get().map(|s| ctx.buf.replace(s));
+                ctx.history_cursor.get().map(|s| recall(&mut ctx.state.buf, s));
                 end
             });
             EditResult::Cont(false)
         }
         instr::HistoryInstr::Next => {
-            vi_repeat!(ctx, {
+            repeat!(ctx, {
                 let end = ctx.history_cursor.decr();
                 if end {
-                    ctx.buf.swap()
+                    ctx.state.buf.swap()
                 }
-                ctx.history_cursor.get().map(|s| ctx.buf.replace(s));
+                ctx.history_cursor.get().map(|s| recall(&mut ctx.state.buf, s));
                 end
             });
             EditResult::Cont(false)
         }
+        instr::HistoryInstr::First => {
+            if ctx.history_cursor.to_oldest() {
+                ctx.state.buf.swap()
+            }
+            ctx.history_cursor.get().map(|s| recall(&mut ctx.state.buf, s));
+            // gg
+            if let ModeState::Vi(ViMode::G, _) = ctx.state.mode_state {
+                ctx.state.mode_state = ModeState::Vi(ViMode::Normal, 0);
+            }
+            EditResult::Cont(false)
+        }
+        instr::HistoryInstr::Goto => {
+            let count = match ctx.state.mode_state {
+                ModeState::Vi(_, count) => count as usize,
+                ModeState::Emacs(_, _) => 0,
+            };
+            let len = ctx.history_cursor.history().len();
+            if count == 0 {
+                if ctx.history_cursor.to_newest() {
+                    ctx.state.buf.swap()
+                }
+            } else if count <= len {
+                if ctx.history_cursor.to_index(len - count) {
+                    ctx.state.buf.swap()
+                }
+                ctx.history_cursor.get().map(|s| recall(&mut ctx.state.buf, s));
+            }
+            ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
+            EditResult::Cont(false)
+        }
+        instr::HistoryInstr::Last => {
+            if ctx.history_cursor.to_newest() {
+                ctx.state.buf.swap()
+            }
+            EditResult::Cont(false)
+        }
     }
 }
 
 fn handle_move_cursor<'a>(ctx: &mut EditCtx<'a>, mcinstr: instr::MoveCursorInstr) -> EditResult<bool> {
     match mcinstr {
         instr::MoveCursorInstr::Left => {
-            let mut dc = ctx.buf.start_delete();
+            let mut dc = ctx.state.buf.start_delete();
             vi_delete!(ctx with dc { dc.move_left() });
             EditResult::Cont(false)
         },
         instr::MoveCursorInstr::Right => {
             {
-                let mut dc = ctx.buf.start_delete();
+                let mut dc = ctx.state.buf.start_delete();
                 vi_delete!(ctx with dc { dc.move_right() });
             }
             ctx.exclude_eol();
             EditResult::Cont(false)
         },
         instr::MoveCursorInstr::Start => {
-            let mut dc = ctx.buf.start_delete();
+            let mut dc = ctx.state.buf.start_delete();
             dc.move_start();
-            match ctx.mode_state {
-                ModeState::Vi(ViMode::Delete, _)
-                | ModeState::Vi(ViMode::Change, _) => {
-                    dc.delete();
-                }
-                _ => {}
-            }
-            ctx.mode_state = next_vi_mode(ctx.mode_state);
+            vi_operate(ctx.state.mode_state, dc, &mut ctx.state.clipboard);
+            ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
             EditResult::Cont(false)
         },
         instr::MoveCursorInstr::End => {
             {
-                let mut dc = ctx.buf.start_delete();
+                let mut dc = ctx.state.buf.start_delete();
                 vi_delete!(ctx with dc { dc.move_end(); false });
             }
             ctx.exclude_eol();
@@ -225,55 +1178,139 @@ fn handle_move_cursor<'a>(ctx: &mut EditCtx<'a>, mcinstr: instr::MoveCursorInstr
 fn handle<'a>(ctx: &mut EditCtx<'a>, ins: instr::Instr) -> EditResult<bool> {
     use self::EditResult::*;
 
+    // the C-x prefix only applies to the instruction directly following it
+    if let ModeState::Emacs(EmacsMode::CtrlX, _) = ctx.state.mode_state {
+        ctx.state.mode_state = ModeState::Emacs(EmacsMode::Normal, 0);
+    }
+    // so does the numeric argument, which is kept for that instruction only
+    if let ModeState::Emacs(mode, count) = ctx.state.mode_state {
+        match (mode, &ins) {
+            (_, &instr::Instr::Digit(_))
+            | (_, &instr::Instr::UniversalArgument) => {}
+            (EmacsMode::Argument, _)
+            | (EmacsMode::UniversalArgument, _) => ctx.state.mode_state = ModeState::Emacs(EmacsMode::Normal, count),
+            (_, _) => ctx.state.mode_state = ctx.state.mode_state.with_count(0),
+        }
+    }
+    // any other instruction ends the search, keeping the match
+    if let ModeState::Emacs(EmacsMode::Search, _) = ctx.state.mode_state {
+        match ins {
+            instr::Instr::SearchInsert(_)
+            | instr::Instr::SearchBackspace
+            | instr::Instr::SearchOlder
+            | instr::Instr::SearchCancel => {}
+            _ => {
+                ctx.state.search = None;
+                ctx.state.mode_state = ModeState::Emacs(EmacsMode::Normal, 0);
+            }
+        }
+    }
+    // a yank can only be rotated directly after it happened
+    let yanked = ctx.state.yanked.take();
+    // a selection in Emacs mode only lasts while it is extended, unless
+    // it is deleted or killed
+    let mark = match ins {
+        instr::Instr::SelectLeft | instr::Instr::SelectRight => ctx.state.mark,
+        _ => ctx.state.mark.take(),
+    };
+    if let Some(mark) = mark.filter(|&mark| mark != ctx.state.buf.pos()) {
+        match ins {
+            instr::Instr::DeleteCharLeftOfCursor
+            | instr::Instr::DeleteCharRightOfCursor
+            | instr::Instr::DeleteCharRightOfCursorOrEOF => {
+                ctx.state.buf.delete_to_pos(mark);
+                return Cont(false);
+            }
+            instr::Instr::KillWordLeft => {
+                ctx.kill(|buf| { buf.move_to_pos(mark); });
+                return Cont(false);
+            }
+            _ => {}
+        }
+    }
+
     match ins {
         instr::Instr::Common(cinstr) => handle_common(ctx, cinstr),
         instr::Instr::DoneOrEof => {
-            if ctx.buf.is_empty() {
-                Halt(Err(Error::EndOfFile))
+            if ctx.state.buf.is_empty() || ctx.state.input_ended {
+                Halt(ctx.end())
             }
             else {
-                Halt(Ok(ctx.buf.drain()))
+                ctx.accept()
             }
         }
         instr::Instr::DeleteCharLeftOfCursor => {
-            vi_repeat!(ctx, ctx.buf.delete_char_left_of_cursor());
+            if ctx.state.buf.is_empty() {
+                match ctx.cfg.empty_backspace {
+                    EmptyBackspace::Ignore => {}
+                    EmptyBackspace::Bell => ctx.state.bell = true,
+                    // an instruction that leads back here, like backspace
+                    // itself, does nothing the second time
+                    EmptyBackspace::Run(_) if ctx.in_empty_backspace => {}
+                    EmptyBackspace::Run(ref ins) => {
+                        ctx.in_empty_backspace = true;
+                        let res = handle(ctx, ins.clone());
+                        ctx.in_empty_backspace = false;
+                        return res;
+                    }
+                }
+                return Cont(false);
+            }
+            repeat!(ctx, ctx.state.buf.delete_char_left_of_cursor());
             Cont(false)
         },
         instr::Instr::DeleteCharRightOfCursor => {
-            vi_repeat!(ctx, ctx.buf.delete_char_right_of_cursor());
+            let text = {
+                let mut dc = ctx.state.buf.start_delete();
+                repeat!(ctx, dc.move_right());
+                dc.delete()
+            };
+            if let ModeState::Vi(_, _) = ctx.state.mode_state {
+                ctx.state.clipboard.set(text, false);
+            }
             ctx.exclude_eol();
             Cont(false)
         },
         instr::Instr::DeleteCharRightOfCursorOrEOF => {
-            if !ctx.buf.delete_char_right_of_cursor() {
-                Halt(Err(Error::EndOfFile))
+            // like bash, only an empty line ends the input
+            if ctx.state.buf.is_empty() {
+                Halt(ctx.end())
             } else {
+                let mut deleted = false;
+                repeat!(ctx, {
+                    let res = ctx.state.buf.delete_char_right_of_cursor();
+                    deleted |= res;
+                    res
+                });
+                if !deleted {
+                    ctx.state.bell = true;
+                }
                 Cont(false)
             }
         },
         instr::Instr::DeleteLine => {
-            ctx.buf.drain();
-            ctx.mode_state = ModeState::Vi(ViMode::Normal, 0);
+            ctx.state.clipboard.set(ctx.state.buf.drain(), true);
+            ctx.state.mode_state = ModeState::Vi(ViMode::Normal, 0);
             Cont(false)
         }
         instr::Instr::DeleteToEnd => {
             {
-                ctx.mode_state = ModeState::Vi(ViMode::Delete, 0);
-                let mut dc = ctx.buf.start_delete();
+                ctx.state.mode_state = ModeState::Vi(ViMode::Delete, 0);
+                let mut dc = ctx.state.buf.start_delete();
                 vi_delete!(ctx with dc { dc.move_end(); false });
             }
             ctx.exclude_eol();
             Cont(false)
         }
         instr::Instr::ChangeLine => {
-            ctx.buf.drain();
-            ctx.mode_state = ModeState::Vi(ViMode::Insert, 0);
+            ctx.state.clipboard.set(ctx.state.buf.drain(), true);
+            ctx.state.mode_state = ModeState::Vi(ViMode::Insert, 0);
             Cont(false)
         }
         instr::Instr::ChangeToEnd => {
             {
-                ctx.mode_state = ModeState::Vi(ViMode::Change, 0);
-                let mut dc = ctx.buf.start_delete();
+                ctx.state.mode_state = ModeState::Vi(ViMode::Change, 0);
+                let mut dc = ctx.state.buf.start_delete();
                 vi_delete!(ctx with dc { dc.move_end(); false });
             }
             ctx.exclude_eol();
@@ -282,84 +1319,191 @@ fn handle<'a>(ctx: &mut EditCtx<'a>, ins: instr::Instr) -> EditResult<bool> {
         instr::Instr::MoveCursor(mcinstr) => handle_move_cursor(ctx, mcinstr),
         instr::Instr::History(hinstr) => handle_history(ctx, hinstr),
         instr::Instr::NormalMode => {
-            if let ModeState::Vi(ViMode::Insert, _) = ctx.mode_state {
+            if let ModeState::Vi(ViMode::Insert, _) = ctx.state.mode_state {
                 // cursor moves left when leaving insert mode
-                ctx.buf.move_left();
+                ctx.state.buf.move_left();
             }
-            ctx.mode_state = ModeState::Vi(ViMode::Normal, 0);
+            ctx.state.mode_state = ModeState::Vi(ViMode::Normal, 0);
             Cont(false)
         }
         instr::Instr::ReplaceMode => {
-            ctx.mode_state = ctx.mode_state.with_vi_mode(ViMode::Replace);
+            ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(ViMode::Replace);
             Cont(false)
         }
         instr::Instr::MoveCharMode(mode) => {
-            if let ModeState::Vi(vi_mode, _) = ctx.mode_state {
+            if let ModeState::Vi(vi_mode, _) = ctx.state.mode_state {
                 let vi_mode = match vi_mode {
                     ViMode::Delete => ViMode::DeleteMoveChar(mode),
                     ViMode::Change => ViMode::ChangeMoveChar(mode),
+                    ViMode::Yank   => ViMode::YankMoveChar(mode),
+                    ViMode::Visual => ViMode::VisualMoveChar(mode),
                     _              => ViMode::MoveChar(mode),
                 };
-                ctx.mode_state = ctx.mode_state.with_vi_mode(vi_mode);
+                ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(vi_mode);
+            }
+            Cont(false)
+        }
+        instr::Instr::RepeatCharMove => {
+            let ins = ctx.repeat_char_move(false);
+            handle(ctx, ins)
+        }
+        instr::Instr::RepeatCharMoveReverse => {
+            let ins = ctx.repeat_char_move(true);
+            handle(ctx, ins)
+        }
+        instr::Instr::GMode => {
+            if let ModeState::Vi(vi_mode, _) = ctx.state.mode_state {
+                let vi_mode = match vi_mode {
+                    ViMode::Delete => ViMode::DeleteG,
+                    ViMode::Change => ViMode::ChangeG,
+                    ViMode::Yank   => ViMode::YankG,
+                    ViMode::Visual => ViMode::VisualG,
+                    _              => ViMode::G,
+                };
+                ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(vi_mode);
+            }
+            Cont(false)
+        }
+        instr::Instr::TextObjectMode(_) if !cfg!(feature = "vi-text-objects") => {
+            ctx.state.mode_state = ModeState::Vi(ViMode::Normal, 0);
+            ctx.state.bell = true;
+            Cont(false)
+        }
+        instr::Instr::TextObjectMode(scope) => {
+            if let ModeState::Vi(vi_mode, _) = ctx.state.mode_state {
+                let vi_mode = match vi_mode {
+                    ViMode::Delete => ViMode::DeleteTextObject(scope),
+                    ViMode::Change => ViMode::ChangeTextObject(scope),
+                    _              => ViMode::YankTextObject(scope),
+                };
+                ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(vi_mode);
+            }
+            Cont(false)
+        }
+        instr::Instr::TextObject(scope, object) => {
+            let around = scope == instr::TextObjectScope::Around;
+            let ws = object == instr::TextObject::WsWord;
+            if let Some((start, end)) = ctx.state.buf.word_object(around, ws) {
+                ctx.state.buf.move_to_pos(end);
+                let mut dc = ctx.state.buf.start_delete();
+                dc.move_to_pos(start);
+                vi_operate(ctx.state.mode_state, dc, &mut ctx.state.clipboard);
+            }
+            ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
+            ctx.exclude_eol();
+            Cont(false)
+        }
+        instr::Instr::MoveLastNonBlank => {
+            {
+                let mut dc = ctx.state.buf.start_delete();
+                dc.move_last_non_blank();
+                if vi_operator_pending(ctx.state.mode_state) {
+                    dc.move_right(); // make deletion inclusive
+                    vi_operate(ctx.state.mode_state, dc, &mut ctx.state.clipboard);
+                }
             }
+            ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
+            ctx.exclude_eol();
             Cont(false)
         }
         instr::Instr::DeleteMode => {
-            ctx.mode_state = ctx.mode_state.with_vi_mode(ViMode::Delete);
+            ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(ViMode::Delete);
             Cont(false)
         }
         instr::Instr::ChangeMode => {
-            ctx.mode_state = ctx.mode_state.with_vi_mode(ViMode::Change);
+            ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(ViMode::Change);
+            Cont(false)
+        }
+        instr::Instr::YankMode => {
+            ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(ViMode::Yank);
+            Cont(false)
+        }
+        instr::Instr::YankLine => {
+            ctx.state.clipboard.set(ctx.state.buf.as_str().to_owned(), true);
+            ctx.state.mode_state = ModeState::Vi(ViMode::Normal, 0);
+            Cont(false)
+        }
+        instr::Instr::PasteAfter | instr::Instr::PasteBefore => {
+            let clip = match ctx.state.clipboard.get() {
+                Some(clip) => clip.clone(),
+                None => return Cont(false)
+            };
+            let after = match ins {
+                instr::Instr::PasteAfter => true,
+                _ => false,
+            };
+            if clip.linewise {
+                // there is only a single line, so linewise text goes to its start or end
+                if after {
+                    ctx.state.buf.move_end();
+                } else {
+                    ctx.state.buf.move_start();
+                }
+            } else if after {
+                ctx.state.buf.move_right();
+            }
+            let start = ctx.state.buf.pos();
+            repeat!(ctx, {
+                ctx.state.buf.insert_chars_at_cursor(clip.text.as_str());
+                true
+            });
+            if clip.linewise {
+                ctx.state.buf.move_to_pos(start);
+            } else {
+                ctx.state.buf.move_left();
+            }
             Cont(false)
         }
         instr::Instr::Insert => {
-            ctx.mode_state = ctx.mode_state.with_vi_mode(ViMode::Insert);
+            ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(ViMode::Insert);
             Cont(false)
         }
         instr::Instr::InsertStart => {
-            ctx.mode_state = ctx.mode_state.with_vi_mode(ViMode::Insert);
-            ctx.buf.move_start();
+            ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(ViMode::Insert);
+            ctx.state.buf.move_start();
             Cont(false)
         }
         instr::Instr::Append => {
-            ctx.mode_state = ctx.mode_state.with_vi_mode(ViMode::Insert);
-            ctx.buf.move_right();
+            ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(ViMode::Insert);
+            ctx.state.buf.move_right();
             Cont(false)
         }
         instr::Instr::AppendEnd => {
-            ctx.mode_state = ctx.mode_state.with_vi_mode(ViMode::Insert);
-            ctx.buf.move_end();
+            ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(ViMode::Insert);
+            ctx.state.buf.move_end();
             Cont(false)
         }
         instr::Instr::Digit(i) => {
-            match (ctx.mode_state, i) {
+            match (ctx.state.mode_state, i) {
                 // if count is 0, then 0 moves to the start of a line
                 (ModeState::Vi(_, 0), 0) => {
-                    let mut dc = ctx.buf.start_delete();
+                    let mut dc = ctx.state.buf.start_delete();
                     vi_delete!(ctx with dc { dc.move_start(); false });
                 }
                 // otherwise add a digit to the count
                 (ModeState::Vi(_, count), i) => {
                     if count <= (u32::MAX - i) / 10 {
-                        ctx.mode_state = ctx.mode_state.with_vi_count(count * 10 + i);
+                        ctx.state.mode_state = ctx.state.mode_state.with_count(count * 10 + i);
                     }
                 }
-                (ModeState::Emacs, _) => {} // unreachable!()?
+                (ModeState::Emacs(EmacsMode::Argument, count), i) => {
+                    if count <= (u32::MAX - i) / 10 {
+                        ctx.state.mode_state = ModeState::Emacs(EmacsMode::Argument, count * 10 + i);
+                    }
+                }
+                // the first digit replaces the argument given by C-u
+                (ModeState::Emacs(_, _), i) => ctx.state.mode_state = ModeState::Emacs(EmacsMode::Argument, i),
             }
             Cont(false)
         }
         instr::Instr::MoveEndOfWordRight => {
             {
-                let mut dc = ctx.buf.start_delete();
-                vi_repeat!(ctx, dc.move_to_end_of_word());
-                match ctx.mode_state {
-                    ModeState::Vi(ViMode::Delete, _)
-                    | ModeState::Vi(ViMode::Change, _) => {
-                        dc.move_right(); // vi deletes an extra character
-                        dc.delete();
-                        ctx.mode_state = next_vi_mode(ctx.mode_state);
-                    }
-                    _ => {}
+                let mut dc = ctx.state.buf.start_delete();
+                repeat!(ctx, dc.move_to_end_of_word());
+                if vi_operator_pending(ctx.state.mode_state) {
+                    dc.move_right(); // vi deletes an extra character
+                    vi_operate(ctx.state.mode_state, dc, &mut ctx.state.clipboard);
+                    ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
                 }
             }
             ctx.exclude_eol();
@@ -367,16 +1511,12 @@ fn handle<'a>(ctx: &mut EditCtx<'a>, ins: instr::Instr) -> EditResult<bool> {
         }
         instr::Instr::MoveEndOfWordWsRight => {
             {
-                let mut dc = ctx.buf.start_delete();
-                vi_repeat!(ctx, dc.move_to_end_of_word_ws());
-                match ctx.mode_state {
-                    ModeState::Vi(ViMode::Delete, _)
-                    | ModeState::Vi(ViMode::Change, _) => {
-                        dc.move_right(); // vi deletes an extra character
-                        dc.delete();
-                        ctx.mode_state = next_vi_mode(ctx.mode_state);
-                    }
-                    _ => {}
+                let mut dc = ctx.state.buf.start_delete();
+                repeat!(ctx, dc.move_to_end_of_word_ws());
+                if vi_operator_pending(ctx.state.mode_state) {
+                    dc.move_right(); // vi deletes an extra character
+                    vi_operate(ctx.state.mode_state, dc, &mut ctx.state.clipboard);
+                    ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
                 }
             }
             ctx.exclude_eol();
@@ -384,174 +1524,601 @@ fn handle<'a>(ctx: &mut EditCtx<'a>, ins: instr::Instr) -> EditResult<bool> {
         }
         instr::Instr::MoveWordRight => {
             {
-                let mut dc = ctx.buf.start_delete();
-                vi_repeat!(ctx, dc.move_word());
-                match ctx.mode_state {
-                    ModeState::Vi(ViMode::Delete, _) => dc.delete(),
-                    ModeState::Vi(ViMode::Change, _) => {
-                        // move word right has special behavior in change mode
-                        if !dc.started_on_whitespace() && dc.move_right() {
-                            dc.move_to_end_of_word_back();
-                            dc.move_right();
-                        }
-                        dc.delete();
+                let mut dc = ctx.state.buf.start_delete();
+                repeat!(ctx, dc.move_word());
+                if let ModeState::Vi(ViMode::Change, _) = ctx.state.mode_state {
+                    // move word right has special behavior in change mode
+                    if !dc.started_on_whitespace() && dc.move_right() {
+                        dc.move_to_end_of_word_back();
+                        dc.move_right();
                     }
-                    _ => {}
                 }
-                ctx.mode_state = next_vi_mode(ctx.mode_state);
+                vi_operate(ctx.state.mode_state, dc, &mut ctx.state.clipboard);
+                ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
             }
             ctx.exclude_eol();
             Cont(false)
         }
         instr::Instr::MoveWordWsRight => {
             {
-                let mut dc = ctx.buf.start_delete();
-                vi_repeat!(ctx, dc.move_word_ws());
-                match ctx.mode_state {
-                    ModeState::Vi(ViMode::Delete, _) => dc.delete(),
-                    ModeState::Vi(ViMode::Change, _) => {
-                        // move word right has special behavior in change mode
-                        if !dc.started_on_whitespace() && dc.move_right() {
-                            dc.move_to_end_of_word_ws_back();
-                            dc.move_right();
-                        }
-                        dc.delete();
+                let mut dc = ctx.state.buf.start_delete();
+                repeat!(ctx, dc.move_word_ws());
+                if let ModeState::Vi(ViMode::Change, _) = ctx.state.mode_state {
+                    // move word right has special behavior in change mode
+                    if !dc.started_on_whitespace() && dc.move_right() {
+                        dc.move_to_end_of_word_ws_back();
+                        dc.move_right();
                     }
-                    _ => {}
                 }
-                ctx.mode_state = next_vi_mode(ctx.mode_state);
+                vi_operate(ctx.state.mode_state, dc, &mut ctx.state.clipboard);
+                ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
             }
             ctx.exclude_eol();
             Cont(false)
         }
         instr::Instr::MoveWordLeft => {
-            let mut dc = ctx.buf.start_delete();
+            let mut dc = ctx.state.buf.start_delete();
             vi_delete!(ctx with dc { dc.move_word_back() });
             Cont(false)
         }
         instr::Instr::MoveWordWsLeft => {
-            let mut dc = ctx.buf.start_delete();
+            let mut dc = ctx.state.buf.start_delete();
             vi_delete!(ctx with dc { dc.move_word_ws_back() });
             Cont(false)
         }
+        instr::Instr::EmacsMoveWordRight => {
+            repeat!(ctx, ctx.state.buf.emacs_move_word_forward());
+            Cont(false)
+        }
+        instr::Instr::EmacsMoveWordLeft => {
+            repeat!(ctx, ctx.state.buf.emacs_move_word_backward());
+            Cont(false)
+        }
         instr::Instr::MoveCharRight(c) => {
             {
-                let mut dc = ctx.buf.start_delete();
-                if let ModeState::Vi(mode, count) = ctx.mode_state {
-                    dc.move_to_char_right(c, match count {
+                let mut dc = ctx.state.buf.start_delete();
+                if let ModeState::Vi(_, count) = ctx.state.mode_state {
+                    dc.move_to_grapheme_right(&c, match count {
                         0 => 1,
                         n => n,
                     });
-                    match mode {
-                        ViMode::DeleteMoveChar(_) | ViMode::ChangeMoveChar(_) => {
-                            dc.move_right(); // make deletion inclusive
-                            dc.delete();
-                        }
-                        _ => {},
+                    if vi_operator_pending(ctx.state.mode_state) {
+                        dc.move_right(); // make deletion inclusive
+                        vi_operate(ctx.state.mode_state, dc, &mut ctx.state.clipboard);
                     }
                 }
             }
-            ctx.mode_state = next_vi_mode(ctx.mode_state);
+            ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
             ctx.exclude_eol();
             Cont(false)
         }
         instr::Instr::MoveCharLeft(c) => {
-            let mut dc = ctx.buf.start_delete();
-            if let ModeState::Vi(mode, count) = ctx.mode_state {
-                dc.move_to_char_left(c, match count {
+            let mut dc = ctx.state.buf.start_delete();
+            if let ModeState::Vi(_, count) = ctx.state.mode_state {
+                dc.move_to_grapheme_left(&c, match count {
                     0 => 1,
                     n => n,
                 });
-                match mode {
-                    ViMode::DeleteMoveChar(_) | ViMode::ChangeMoveChar(_) => dc.delete(),
-                    _ => {},
-                }
+                vi_operate(ctx.state.mode_state, dc, &mut ctx.state.clipboard);
             }
-            ctx.mode_state = next_vi_mode(ctx.mode_state);
+            ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
             Cont(false)
         }
         instr::Instr::MoveBeforeCharRight(c) => {
-            if let ModeState::Vi(mode, count) = ctx.mode_state {
+            if let ModeState::Vi(_, count) = ctx.state.mode_state {
                 let count = match count {
                     0 => 1,
                     n => n,
                 };
 
-                let mut dc = ctx.buf.start_delete();
-                if dc.move_to_char_right(c, count) {
+                let mut dc = ctx.state.buf.start_delete();
+                if dc.move_to_grapheme_right(&c, count) {
                     dc.move_left();
-                    match mode {
-                        ViMode::DeleteMoveChar(_) | ViMode::ChangeMoveChar(_) => {
-                            dc.move_right(); // make deletion inclusive
-                            dc.delete();
-                        }
-                        _ => {},
+                    if vi_operator_pending(ctx.state.mode_state) {
+                        dc.move_right(); // make deletion inclusive
+                        vi_operate(ctx.state.mode_state, dc, &mut ctx.state.clipboard);
                     }
                 }
             }
-            ctx.mode_state = next_vi_mode(ctx.mode_state);
+            ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
             Cont(false)
         }
         instr::Instr::MoveBeforeCharLeft(c) => {
-            if let ModeState::Vi(mode, count) = ctx.mode_state {
+            if let ModeState::Vi(_, count) = ctx.state.mode_state {
                 let count = match count {
                     0 => 1,
                     n => n,
                 };
 
-                let mut dc = ctx.buf.start_delete();
-                if dc.move_to_char_left(c, count) {
+                let mut dc = ctx.state.buf.start_delete();
+                if dc.move_to_grapheme_left(&c, count) {
                     dc.move_right();
-                    match mode {
-                        ViMode::DeleteMoveChar(_) | ViMode::ChangeMoveChar(_) => dc.delete(),
-                        _ => {},
-                    }
+                    vi_operate(ctx.state.mode_state, dc, &mut ctx.state.clipboard);
                 }
             }
-            ctx.mode_state = next_vi_mode(ctx.mode_state);
+            ctx.state.mode_state = next_vi_mode(ctx.state.mode_state);
             Cont(false)
         }
         instr::Instr::Substitute => {
-            vi_repeat!(ctx, ctx.buf.delete_char_right_of_cursor());
-            ctx.mode_state = ctx.mode_state.with_vi_mode(ViMode::Insert);
+            repeat!(ctx, ctx.state.buf.delete_char_right_of_cursor());
+            ctx.state.mode_state = ctx.state.mode_state.with_vi_mode(ViMode::Insert);
+            Cont(false)
+        }
+        instr::Instr::ToggleCase => {
+            repeat!(ctx, ctx.state.buf.toggle_case_at_cursor());
+            ctx.exclude_eol();
+            Cont(false)
+        }
+        instr::Instr::UppercaseLine => {
+            ctx.state.buf.uppercase_line();
+            ctx.exclude_eol();
+            Cont(false)
+        }
+        instr::Instr::LowercaseLine => {
+            ctx.state.buf.lowercase_line();
+            ctx.exclude_eol();
             Cont(false)
         }
         instr::Instr::InsertAtCursor(text) => {
-            ctx.buf.insert_chars_at_cursor(text.as_str());
+            let times = match ctx.state.mode_state {
+                ModeState::Emacs(_, count) if count > 0 => count,
+                _ => 1,
+            };
+            for _ in 0..times {
+                ctx.state.buf.insert_chars_at_cursor(text.as_str());
+            }
             Cont(false)
         }
         instr::Instr::ReplaceAtCursor(text) => {
-            vi_repeat!(ctx, {
-                ctx.buf.replace_chars_at_cursor(text.as_str());
-                ctx.buf.move_right();
-                ctx.buf.exclude_eol()
+            // like vim, nothing is replaced unless there are enough characters
+            let count = ctx.state.mode_state.count().max(1) as usize;
+            if ctx.state.buf.graphemes_right_of_cursor() < count {
+                ctx.state.bell = true;
+            } else {
+                for i in 0..count {
+                    if i > 0 {
+                        ctx.state.buf.move_right();
+                    }
+                    ctx.state.buf.replace_chars_at_cursor(text.as_str());
+                }
+            }
+            ctx.state.mode_state = ModeState::Vi(ViMode::Normal, 0);
+            Cont(false)
+        }
+        instr::Instr::Undo => {
+            if let Some(snapshot) = ctx.state.undo.pop() {
+                ctx.state.buf.restore(snapshot);
+            }
+            Cont(false)
+        }
+        instr::Instr::RepeatLastChange => {
+            if let Some(change) = ctx.state.last_change.clone() {
+                // a count given to . replaces the count of the change
+                let count = match ctx.state.mode_state {
+                    ModeState::Vi(_, 0) => change.count,
+                    ModeState::Vi(_, count) => count,
+                    ModeState::Emacs(_, _) => 0,
+                };
+                ctx.state.mode_state = ModeState::Vi(ViMode::Normal, count);
+                for keys in change.keys.iter() {
+                    if let Ok(ParseSuccess(token, _)) = parse(keys, ctx.enc) {
+                        let ins = instr::interpret_token(token, ctx.state.mode_state, ctx.cfg);
+                        handle(ctx, ins);
+                    }
+                }
+            }
+            Cont(false)
+        }
+        instr::Instr::VisualMode => {
+            ctx.state.visual_anchor = ctx.state.buf.pos();
+            ctx.state.mode_state = ModeState::Vi(ViMode::Visual, 0);
+            Cont(false)
+        }
+        instr::Instr::SelectLeft | instr::Instr::SelectRight => {
+            let pos = ctx.state.buf.pos();
+            match ctx.state.mode_state {
+                ModeState::Vi(ViMode::Visual, _) => {}
+                ModeState::Vi(_, count) => {
+                    ctx.state.visual_anchor = pos;
+                    ctx.state.mode_state = ModeState::Vi(ViMode::Visual, count);
+                }
+                ModeState::Emacs(_, _) => {
+                    ctx.state.mark = Some(mark.unwrap_or(pos));
+                }
+            }
+            handle_move_cursor(ctx, match ins {
+                instr::Instr::SelectLeft => instr::MoveCursorInstr::Left,
+                _ => instr::MoveCursorInstr::Right,
+            })
+        }
+        instr::Instr::VisualDelete | instr::Instr::VisualChange | instr::Instr::VisualYank => {
+            let (start, end) = ctx.state.buf.selection(ctx.state.visual_anchor);
+            ctx.state.buf.move_to_pos(end);
+            let text = {
+                let mut dc = ctx.state.buf.start_delete();
+                dc.move_to_pos(start);
+                match ins {
+                    instr::Instr::VisualYank => dc.yank(),
+                    _ => dc.delete(),
+                }
+            };
+            ctx.state.clipboard.set(text, false);
+            ctx.state.mode_state = match ins {
+                instr::Instr::VisualChange => ModeState::Vi(ViMode::Insert, 0),
+                _ => ModeState::Vi(ViMode::Normal, 0),
+            };
+            ctx.exclude_eol();
+            Cont(false)
+        }
+        instr::Instr::Complete => {
+            ctx.state.busy = false;
+            ctx.complete();
+            Cont(false)
+        }
+        instr::Instr::SearchMode if !cfg!(feature = "history-search") => {
+            ctx.state.bell = true;
+            Cont(false)
+        }
+        instr::Instr::SearchMode => {
+            ctx.state.search = Some(Search {
+                query: String::new(),
+                found: None,
+                failed: false,
+                before: ctx.state.buf.snapshot(),
             });
-            ctx.mode_state = ctx.mode_state.with_vi_mode(ViMode::Normal);
+            ctx.state.mode_state = ModeState::Emacs(EmacsMode::Search, 0);
+            Cont(false)
+        }
+        instr::Instr::SearchInsert(text) => {
+            let from = match ctx.state.search {
+                Some(ref mut search) => {
+                    search.query.push_str(&text);
+                    search.found.unwrap_or(0)
+                }
+                None => 0,
+            };
+            if !ctx.search(from) {
+                ctx.state.bell = true;
+            }
+            Cont(false)
+        }
+        instr::Instr::SearchBackspace => {
+            let empty = match ctx.state.search {
+                Some(ref mut search) => {
+                    search.query.pop();
+                    search.query.is_empty()
+                }
+                None => true,
+            };
+            if !empty {
+                ctx.search(0);
+            }
+            Cont(false)
+        }
+        instr::Instr::SearchOlder => {
+            let from = ctx.state.search.as_ref().and_then(|search| search.found).map_or(0, |idx| idx + 1);
+            let searchable = ctx.state.search.as_ref().map_or(false, |search| !search.query.is_empty());
+            if !searchable || !ctx.search(from) {
+                ctx.state.bell = true;
+            }
+            Cont(false)
+        }
+        instr::Instr::SearchCancel => {
+            if let Some(search) = ctx.state.search.take() {
+                ctx.state.buf.restore(search.before);
+            }
+            ctx.state.mode_state = ModeState::Emacs(EmacsMode::Normal, 0);
+            Cont(false)
+        }
+        // text that is not echoed, such as a password, must not be written
+        // to a file and shown in the editor
+        instr::Instr::EditExternally if ctx.cfg.echo != EchoMode::Normal => Cont(false),
+        instr::Instr::EditExternally => {
+            ctx.state.external_edit_requested = true;
+            // the editor takes over the terminal, so the prompt is drawn anew
+            ctx.state.prompt_shown = false;
+            Cont(false)
+        }
+        instr::Instr::CtrlXMode => {
+            ctx.state.mode_state = ModeState::Emacs(EmacsMode::CtrlX, 0);
+            Cont(false)
+        }
+        instr::Instr::QuotedInsert => {
+            ctx.state.quoted_insert = true;
+            Cont(false)
+        }
+        instr::Instr::UniversalArgument => {
+            ctx.state.mode_state = match ctx.state.mode_state {
+                ModeState::Emacs(EmacsMode::Normal, _) => ModeState::Emacs(EmacsMode::UniversalArgument, 4),
+                ModeState::Emacs(mode, count) => ModeState::Emacs(mode, count.saturating_mul(4)),
+                mode_state => mode_state,
+            };
+            Cont(false)
+        }
+        instr::Instr::KillToEnd => {
+            ctx.kill(|buf| buf.move_end());
+            Cont(false)
+        }
+        instr::Instr::KillToStart => {
+            ctx.kill(|buf| buf.move_start());
+            Cont(false)
+        }
+        instr::Instr::KillLine => {
+            ctx.state.buf.move_start();
+            ctx.kill(|buf| buf.move_end());
+            Cont(false)
+        }
+        instr::Instr::KillWordLeft => {
+            ctx.kill(|buf| { buf.move_word_ws_back(); });
+            Cont(false)
+        }
+        instr::Instr::Yank => {
+            let start = ctx.state.buf.pos();
+            if let Some(clip) = ctx.state.clipboard.get() {
+                ctx.state.buf.insert_chars_at_cursor(&clip.text);
+                ctx.state.yanked = Some(start);
+            }
+            Cont(false)
+        }
+        instr::Instr::YankPop => {
+            if let Some(start) = yanked {
+                if let Some(text) = ctx.state.clipboard.rotate() {
+                    ctx.state.buf.delete_to_pos(start);
+                    ctx.state.buf.insert_chars_at_cursor(text);
+                }
+                ctx.state.yanked = Some(start);
+            }
             Cont(false)
         }
     }
 }
 
+/// Handle an instruction, recording the previous buffer state for undo
+/// if the instruction changed the buffer.
+fn handle_undoable<'a>(ctx: &mut EditCtx<'a>, ins: instr::Instr) -> EditResult<bool> {
+    let record = match ins {
+        instr::Instr::Undo => false,
+        // a discarded line starts over without anything to undo
+        instr::Instr::Common(instr::CommonInstr::DiscardLine) => false,
+        _ => true,
+    };
+    // consecutive single character insertions are undone as one unit
+    let coalesce = match ins {
+        instr::Instr::InsertAtCursor(ref text) => text.chars().count() == 1,
+        _ => false,
+    };
+    let before = ctx.state.buf.snapshot();
+    let res = handle(ctx, ins);
+    if record {
+        if before.as_str() != ctx.state.buf.as_str() {
+            ctx.state.undo.push(before, coalesce);
+        } else {
+            ctx.state.undo.seal();
+        }
+    }
+    res
+}
+
+/// Handle the next token in the input sequence, if it is complete.
+///
+/// If enabled, repetitions of a movement key that are already buffered
+/// are handled along with it, so they are rendered only once.
+fn step<'a>(ctx: &mut EditCtx<'a>) -> Option<EditResult<bool>> {
+    let mut clear = false;
+    loop {
+        match parse(ctx.state.seq.make_contiguous(), ctx.enc) {
+            Err(ParseError::Error(len)) => {
+                if let Some(ref mut on_parse_error) = ctx.on_parse_error {
+                    on_parse_error(&ctx.state.seq.make_contiguous()[..len]);
+                }
+                ctx.state.seq.drain(..len);
+                return Some(EditResult::Cont(clear))
+            },
+            Err(ParseError::Incomplete) => return None,
+            Ok(ParseSuccess(token, len)) => {
+                // any key other than y discards a paste that awaits confirmation
+                if let Some(text) = ctx.state.pending_paste.take() {
+                    ctx.state.seq.drain(..len);
+                    ctx.state.info = None;
+                    if token == Token::Text("y".to_owned()) {
+                        handle_undoable(ctx, instr::Instr::InsertAtCursor(text));
+                    }
+                    return Some(EditResult::Cont(clear));
+                }
+                if ctx.state.quoted_insert {
+                    ctx.state.quoted_insert = false;
+                    let text = match token {
+                        Token::Text(text) | Token::Paste(text) => text,
+                        // control keys and escape sequences are plain ASCII
+                        _ => ctx.state.seq.iter().take(len).map(|&b| b as char).collect(),
+                    };
+                    ctx.state.seq.drain(..len);
+                    handle_undoable(ctx, instr::Instr::InsertAtCursor(text));
+                    return Some(EditResult::Cont(clear));
+                }
+                let token = match filter_control_chars(token, ctx.cfg.control_char_policy) {
+                    Some(token) => token,
+                    None => {
+                        ctx.state.bell = true;
+                        ctx.state.seq.drain(..len);
+                        return Some(EditResult::Cont(clear));
+                    }
+                };
+                let key = token.clone();
+                let ins = match instr::interpret_token(token, ctx.state.mode_state, ctx.cfg) {
+                    instr::Instr::Complete if ctx.cfg.complete_after_word_only && !ctx.state.buf.is_after_keyword() => {
+                        instr::Instr::InsertAtCursor("    ".to_owned())
+                    }
+                    ins => {
+                        if instr::reverse_char_move(&ins).is_some() {
+                            ctx.state.last_char_move = Some(ins.clone());
+                        }
+                        ins
+                    }
+                };
+                if let instr::Instr::InsertAtCursor(ref text) = ins {
+                    let count = text.chars().count();
+                    if ctx.cfg.paste_warn_threshold.map_or(false, |threshold| count > threshold) {
+                        ctx.state.pending_paste = Some(text.clone());
+                        ctx.state.info = Some(format!("Paste {} characters? y/n", count));
+                        ctx.state.seq.drain(..len);
+                        return Some(EditResult::Cont(clear));
+                    }
+                }
+                // show the busy indicator before invoking the completer
+                if let instr::Instr::Complete = ins {
+                    if !ctx.state.busy && ctx.cfg.busy_indicator.is_some() && ctx.cfg.completer.is_some() {
+                        ctx.state.busy = true;
+                        return Some(EditResult::Cont(clear));
+                    }
+                }
+                let coalesce = ctx.cfg.coalesce_key_repeat && match ins {
+                    instr::Instr::MoveCursor(_) | instr::Instr::History(_) => true,
+                    _ => false,
+                };
+                // a movement that does not move the cursor rings the bell
+                let movement = is_movement(&ins) && !vi_operator_pending(ctx.state.mode_state);
+                let pos = ctx.state.buf.pos();
+                let keys: Vec<u8> = ctx.state.seq.iter().take(len).cloned().collect();
+                let text = if ctx.cfg.complete_on_type { Some(ctx.state.buf.as_str().to_owned()) } else { None };
+                ctx.record(&keys, &ins);
+                let res = handle_undoable(ctx, ins);
+                ctx.finish_recording();
+                if text.map_or(false, |text| text != ctx.state.buf.as_str()) {
+                    ctx.show_candidates();
+                }
+                if movement && ctx.state.buf.pos() == pos {
+                    ctx.state.bell = true;
+                }
+                ctx.state.seq.drain(..len);
+                match res {
+                    EditResult::Cont(c) if coalesce && ctx.state.seq.iter().take(len).eq(keys.iter()) => clear |= c,
+                    EditResult::Cont(c) => return Some(EditResult::Cont(clear || c)),
+                    EditResult::Halt(res) => {
+                        if res.is_ok() {
+                            ctx.state.accepted_by = Some(key);
+                        }
+                        return Some(EditResult::Halt(res))
+                    }
+                }
+            }
+        }
+    }
+}
+
 pub fn edit<'a>(ctx: &mut EditCtx<'a>) -> EditResult<Vec<u8>> {
-    let res = match parse(&ctx.seq, ctx.enc) {
-        Err(ParseError::Error(len)) => {
-            for _ in 0..len {
-                ctx.seq.remove(0);
-            };
-            EditResult::Cont(false)
-        },
-        Err(ParseError::Incomplete) => EditResult::Cont(false),
-        Ok(ParseSuccess(token, len)) => {
-            let ins = instr::interpret_token(token, ctx.mode_state);
-            let res = handle(ctx, ins);
-            for _ in 0..len {
-                ctx.seq.remove(0);
-            };
-            res
+    let mut res = step(ctx).unwrap_or(EditResult::Cont(false));
+    // handle all keys that are already buffered, rendering only once
+    while let EditResult::Cont(clear) = res {
+        if ctx.state.busy || ctx.state.suspend_requested || ctx.state.external_edit_requested {
+            break;
         }
-    };
+        res = match step(ctx) {
+            Some(EditResult::Cont(c)) => EditResult::Cont(clear || c),
+            Some(halt) => halt,
+            None => break,
+        };
+    }
     match res {
-        EditResult::Cont(clear) => EditResult::Cont(ctx.buf.get_line(ctx.prompt, clear)),
+        // the marker is only shown after a line that fits on one row
+        EditResult::Cont(_) if ctx.state.busy && ctx.state.wrapped.is_some() => EditResult::Cont(vec![]),
+        EditResult::Cont(_) if ctx.state.busy => {
+            let marker = ctx.cfg.busy_indicator.as_ref().map(|m| m.as_str()).unwrap_or("");
+            EditResult::Cont(ctx.shown().get_marker(&ctx.prompt(), marker, ctx.window(&ctx.prompt())))
+        },
+        EditResult::Cont(clear) => {
+            let mut line = Builder::new();
+            ctx.update_cursor_shape(&mut line);
+            if ctx.state.bell && ctx.cfg.bell {
+                line.bell();
+            }
+            ctx.state.bell = false;
+            let mut seq = mem::replace(&mut ctx.discarded, vec![]);
+            seq.extend(line.build());
+            ctx.notify_change();
+            if let Some(prompt) = ctx.prompt_fn.as_mut().map(|prompt_fn| prompt_fn()) {
+                ctx.set_prompt(prompt);
+            }
+            let prompt = ctx.search_prompt().map_or(ctx.prompt(), Cow::Owned);
+            let prompt = prompt.as_ref();
+            let window = ctx.window(prompt);
+            let wrapped = ctx.wrap(prompt);
+            let shown = ctx.shown();
+            // neither the selection nor hints would fit text that is not echoed
+            let echoed = ctx.cfg.echo == EchoMode::Normal;
+            let selection = if echoed { ctx.selection() } else { None };
+            match wrapped {
+                Some(ref wrapped) => {
+                    match ctx.state.wrapped {
+                        Some(ref shown) if ctx.state.prompt_shown && !clear => seq.extend(shown.get_first_row()),
+                        _ => {}
+                    }
+                    seq.extend(shown.get_wrapped_line(prompt, clear, !ctx.state.prompt_shown, selection,
+                                                      ctx.cfg.mark_trailing_space, wrapped));
+                }
+                None => seq.extend(shown.get_line(prompt, clear, !ctx.state.prompt_shown, selection,
+                                                  ctx.cfg.mark_trailing_space, window)),
+            }
+            // hints and the right prompt are only shown after a line that
+            // fits on one row
+            let wrapped = wrapped.filter(|wrapped| wrapped.last_row() > 0);
+            let hint = ctx.hinter.filter(|_| echoed && wrapped.is_none() && cfg!(feature = "hints"))
+                .and_then(|hinter| hinter.hint(ctx.state.buf.as_str(), ctx.state.buf.byte_pos()));
+            if let (Some(right_prompt), Some(width), None) = (ctx.cfg.right_prompt.as_ref(), ctx.state.width, wrapped.as_ref()) {
+                let hint = hint.as_ref().map_or("", |h| h.as_str());
+                seq.extend(shown.get_right_prompt(prompt, right_prompt, hint, width, window));
+            }
+            if let Some(hint) = hint {
+                seq.extend(shown.get_hint(prompt, &hint, window));
+            }
+            let info = ctx.state.info.as_ref().map(|info| match wrapped {
+                Some(ref wrapped) => wrapped.get_info(info),
+                None => shown.get_info(prompt, info, window),
+            });
+            // the line stays scrolled while the cursor is within the window
+            ctx.state.scroll = window.map_or(0, |window| window.offset);
+            ctx.state.wrapped = wrapped;
+            match info {
+                Some(info) => {
+                    seq.extend(info);
+                    ctx.state.info_shown = true;
+                }
+                None => seq.extend(ctx.clear_info()),
+            }
+            ctx.state.prompt_shown = true;
+            EditResult::Cont(seq)
+        },
         EditResult::Halt(res) => EditResult::Halt(res)
     }
 }
+
+/// Handle all complete tokens in the input sequence without rendering,
+/// until the line is finished.
+pub fn edit_pending<'a>(ctx: &mut EditCtx<'a>) -> Option<Result<String, Error>> {
+    while let Some(res) = step(ctx) {
+        if let EditResult::Halt(res) = res {
+            return Some(res);
+        }
+    }
+    None
+}
+
+/// Finish editing once the input has ended.
+///
+/// Complete tokens that are still buffered are handled, while an
+/// incomplete sequence at the end of the input is dropped. The line is
+/// then accepted, unless it is empty.
+pub fn edit_eof<'a>(ctx: &mut EditCtx<'a>) -> Result<String, Error> {
+    while !ctx.state.seq.is_empty() {
+        match step(ctx) {
+            Some(EditResult::Halt(res)) => return res,
+            Some(EditResult::Cont(_)) => {},
+            None => break
+        }
+    }
+    ctx.state.input_ended = true;
+    match handle(ctx, instr::Instr::DoneOrEof) {
+        EditResult::Halt(res) => res,
+        EditResult::Cont(_) => unreachable!()
+    }
+}
@@ -10,6 +10,7 @@ pub enum Encoding {
     Hz,
     Ibm866,
     Iso2022Jp,
+    /// Latin-1
     Iso88591,
     Iso885910,
     Iso885913,
@@ -27,7 +28,12 @@ pub enum Encoding {
     Koi8U,
     MacCyrillic,
     MacRoman,
+    /// Shift_JIS, as extended by Microsoft
+    ShiftJis,
+    /// Not usable for terminal input, since keys are recognised by their
+    /// ASCII bytes, which UTF-16 does not keep
     Utf16BE,
+    /// See `Utf16BE`
     Utf16LE,
     Utf8,
     Windows1250,
@@ -71,6 +77,7 @@ pub fn to_encoding_ref(enc: &Encoding) -> EncodingRef {
         Encoding::Koi8U => KOI8_U,
         Encoding::MacCyrillic => MAC_CYRILLIC,
         Encoding::MacRoman => MAC_ROMAN,
+        Encoding::ShiftJis => WINDOWS_31J,
         Encoding::Utf16BE => UTF_16BE,
         Encoding::Utf16LE => UTF_16LE,
         Encoding::Utf8 => UTF_8,
@@ -1,6 +1,8 @@
 use std::error;
 use std::fmt;
+use std::io;
 use nix;
+use nix::errno::Errno;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -40,3 +42,10 @@ impl From<nix::Error> for Error {
         Error::ErrNo(err)
     }
 }
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        let errno = err.raw_os_error().map_or(Errno::EIO, Errno::from_i32);
+        Error::ErrNo(nix::Error::Sys(errno))
+    }
+}
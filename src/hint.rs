@@ -0,0 +1,8 @@
+/// Provides hints shown after the line while it is edited, such as a
+/// suggestion for how the line could continue.
+pub trait Hinter {
+    /// Returns the hint for the line, if any.
+    ///
+    /// `pos` is the byte offset of the cursor in `line`.
+    fn hint(&self, line: &str, pos: usize) -> Option<String>;
+}
@@ -1,4 +1,5 @@
 use std::collections::VecDeque;
+use std::collections::vec_deque::Iter;
 use std::ops::Index;
 
 pub struct Cursor<'a> {
@@ -12,6 +13,19 @@ impl<'a> Cursor<'a> {
         Cursor { history: h, cur: None }
     }
 
+    /// Creates a cursor at a position returned by `position`.
+    pub fn at(h: &'a History, cur: Option<usize>) -> Cursor<'a> {
+        Cursor { history: h, cur: cur }
+    }
+
+    pub fn position(&self) -> Option<usize> {
+        self.cur
+    }
+
+    pub fn history(&self) -> &'a History {
+        self.history
+    }
+
     pub fn incr(&mut self) -> bool {
         let mut flag = false;
         self.cur = match self.cur {
@@ -33,6 +47,43 @@ impl<'a> Cursor<'a> {
         flag
     }
 
+    /// Move to the oldest entry.
+    ///
+    /// Returns true if the cursor left the line being edited.
+    pub fn to_oldest(&mut self) -> bool {
+        let flag = self.cur.is_none() && self.history.len() > 0;
+        if self.history.len() > 0 {
+            self.cur = Some(self.history.len() - 1);
+        }
+        flag
+    }
+
+    /// Move to the entry with the given index, if there is one.
+    ///
+    /// Returns true if the cursor left the line being edited.
+    pub fn to_index(&mut self, idx: usize) -> bool {
+        if idx >= self.history.len() {
+            return false;
+        }
+        let flag = self.cur.is_none();
+        self.cur = Some(idx);
+        flag
+    }
+
+    /// Move back to the line being edited.
+    ///
+    /// Returns true if the cursor was on an entry.
+    pub fn to_newest(&mut self) -> bool {
+        let flag = self.cur.is_some();
+        self.cur = None;
+        flag
+    }
+
+    /// Whether there is no history to browse.
+    pub fn is_empty(&self) -> bool {
+        self.history.len() == 0
+    }
+
     pub fn get(&self) -> Option<&'a String> {
         match self.cur {
             None => None,
@@ -42,6 +93,30 @@ impl<'a> Cursor<'a> {
 
 }
 
+/// Controls which lines are added to the history, similar to bash's
+/// `HISTCONTROL`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HistoryConfig {
+    /// Remove earlier entries identical to the added line, so that
+    /// every line appears only once, at its most recent position.
+    pub ignore_dups: bool,
+    /// Skip lines that begin with a space.
+    pub ignore_space: bool
+}
+
+impl HistoryConfig {
+    pub fn default() -> HistoryConfig {
+        HistoryConfig {
+            ignore_dups: false,
+            ignore_space: false
+        }
+    }
+}
+
+/// The line history, stored newest-first.
+///
+/// Index `0` always refers to the most recently added entry, and
+/// index `len() - 1` to the oldest one.
 pub struct History {
     deque: VecDeque<String>
 }
@@ -58,16 +133,37 @@ impl History {
         self.deque.len()
     }
 
-    pub fn push(&mut self, s: String) {
-        if !s.is_empty() && self.deque.front() != Option::Some(&s) {
-            self.deque.push_front(s)
+    /// Adds a line as the newest entry.
+    ///
+    /// Empty lines and repetitions of the newest entry are never added.
+    /// The history is not capped in length, so removing earlier
+    /// duplicates never causes other entries to be dropped; with
+    /// `ignore_dups` the length is the number of distinct lines.
+    ///
+    /// Returns whether the history changed, which is not the case if the
+    /// line was skipped or already is the newest entry.
+    pub fn push(&mut self, s: String, cfg: &HistoryConfig) -> bool {
+        if s.is_empty() || (cfg.ignore_space && s.starts_with(' ')) {
+            return false;
+        }
+        if self.deque.front() == Option::Some(&s) {
+            return false;
+        }
+        if cfg.ignore_dups {
+            if let Some(idx) = self.deque.iter().position(|e| *e == s) {
+                self.deque.remove(idx);
+            }
         }
+        self.deque.push_front(s);
+        true
     }
 
     pub fn pop(&mut self) -> Option<String> {
         self.deque.pop_front()
     }
 
+    /// Returns the entry at `idx`, counting from the newest entry (`0`)
+    /// towards the oldest one (`len() - 1`).
     pub fn get(&self, idx: usize) -> Option<&String> {
         let len = self.len();
         if len > 0 && idx < len {
@@ -77,6 +173,39 @@ impl History {
         }
     }
 
+    /// Returns the most recently added entry.
+    pub fn newest(&self) -> Option<&str> {
+        self.deque.front().map(|s| s.as_str())
+    }
+
+    /// Returns the least recently added entry.
+    pub fn oldest(&self) -> Option<&str> {
+        self.deque.back().map(|s| s.as_str())
+    }
+
+    /// Iterates over the entries from the newest to the oldest, in the
+    /// same order as `get`.
+    pub fn iter(&self) -> Iter<String> {
+        self.deque.iter()
+    }
+
+    /// Iterates over the indices of the entries that contain the query,
+    /// from the newest to the oldest.
+    pub fn search<'a>(&'a self, query: &'a str) -> impl Iterator<Item=usize> + 'a {
+        self.deque.iter().enumerate().filter(move |&(_, e)| e.contains(query)).map(|(i, _)| i)
+    }
+
+    /// Returns the index of the first entry from index `from` on, going
+    /// from newer to older entries, that contains the query.
+    pub fn find(&self, query: &str, from: usize) -> Option<usize> {
+        self.search(query).find(|&i| i >= from)
+    }
+
+    /// Returns the number of entries that contain the query.
+    pub fn count_matches(&self, query: &str) -> usize {
+        self.search(query).count()
+    }
+
     pub fn remove(&mut self, idx: usize) -> Option<String> {
         self.deque.remove(idx)
     }
@@ -86,3 +215,122 @@ impl History {
     }
 
 }
+
+#[test]
+fn newest_and_oldest_empty() {
+    let h = History::new();
+    assert_eq!(h.newest(), None);
+    assert_eq!(h.oldest(), None);
+}
+
+#[test]
+fn newest_and_oldest_populated() {
+    let mut h = History::new();
+    h.push("first".to_string(), &HistoryConfig::default());
+    h.push("second".to_string(), &HistoryConfig::default());
+    h.push("third".to_string(), &HistoryConfig::default());
+    assert_eq!(h.newest(), Some("third"));
+    assert_eq!(h.oldest(), Some("first"));
+    assert_eq!(h.get(0).map(|s| s.as_str()), h.newest());
+    assert_eq!(h.get(h.len() - 1).map(|s| s.as_str()), h.oldest());
+}
+
+#[test]
+fn iter_newest_first() {
+    let mut h = History::new();
+    assert_eq!(h.iter().next(), None);
+    h.push("first".to_string(), &HistoryConfig::default());
+    h.push("second".to_string(), &HistoryConfig::default());
+    let entries: Vec<&String> = h.iter().collect();
+    assert_eq!(entries, vec!["second", "first"]);
+    assert_eq!(h.iter().nth(1), h.get(1));
+}
+
+#[test]
+fn push_ignore_dups() {
+    let mut h = History::new();
+    let cfg = HistoryConfig { ignore_dups: true, ..HistoryConfig::default() };
+    assert!(h.push("one".to_string(), &cfg));
+    assert!(h.push("two".to_string(), &cfg));
+    assert!(h.push("one".to_string(), &cfg));
+    assert!(!h.push("one".to_string(), &cfg));
+    let entries: Vec<&String> = h.iter().collect();
+    assert_eq!(entries, vec!["one", "two"]);
+    // without the option only repetitions of the newest entry are skipped
+    assert!(h.push("two".to_string(), &HistoryConfig::default()));
+    assert!(!h.push("two".to_string(), &HistoryConfig::default()));
+    let entries: Vec<&String> = h.iter().collect();
+    assert_eq!(entries, vec!["two", "one", "two"]);
+}
+
+#[test]
+fn push_ignore_space() {
+    let mut h = History::new();
+    let cfg = HistoryConfig { ignore_space: true, ..HistoryConfig::default() };
+    assert!(!h.push(" secret".to_string(), &cfg));
+    assert!(h.push("public".to_string(), &cfg));
+    assert!(!h.push("".to_string(), &cfg));
+    assert_eq!(h.len(), 1);
+    h.push(" secret".to_string(), &HistoryConfig::default());
+    assert_eq!(h.newest(), Some(" secret"));
+}
+
+#[test]
+fn cursor_to_oldest_and_newest() {
+    let mut h = History::new();
+    {
+        let mut c = Cursor::new(&h);
+        assert!(!c.to_oldest());
+        assert_eq!(c.get(), None);
+    }
+    h.push("one".to_string(), &HistoryConfig::default());
+    h.push("two".to_string(), &HistoryConfig::default());
+    let mut c = Cursor::new(&h);
+    assert!(!c.to_newest());
+    assert!(c.to_oldest());
+    assert_eq!(c.get().map(|s| s.as_str()), Some("one"));
+    assert!(!c.to_oldest());
+    assert!(c.to_newest());
+    assert_eq!(c.get(), None);
+}
+
+#[test]
+fn find_and_count_matches() {
+    let mut h = History::new();
+    for s in &["foo 1", "bar", "foo 2"] {
+        h.push(s.to_string(), &HistoryConfig::default());
+    }
+    assert_eq!(h.count_matches("foo"), 2);
+    assert_eq!(h.count_matches("o 1"), 1);
+    assert_eq!(h.count_matches("baz"), 0);
+    assert_eq!(h.find("foo", 0), Some(0));
+    assert_eq!(h.find("foo", 1), Some(2));
+    assert_eq!(h.find("foo", 3), None);
+}
+
+#[test]
+fn cursor_to_index() {
+    let mut h = History::new();
+    h.push("one".to_string(), &HistoryConfig::default());
+    h.push("two".to_string(), &HistoryConfig::default());
+    let mut cursor = Cursor::new(&h);
+    assert!(!cursor.to_index(2));
+    assert_eq!(cursor.position(), None);
+    assert!(cursor.to_index(1));
+    assert_eq!(cursor.get().map(|s| s.as_str()), Some("one"));
+    assert!(!cursor.to_index(0));
+    assert_eq!(cursor.get().map(|s| s.as_str()), Some("two"));
+}
+
+#[test]
+fn search() {
+    let mut h = History::new();
+    for s in &["cargo build", "ls", "cargo test", "git status"] {
+        h.push(s.to_string(), &HistoryConfig::default());
+    }
+    assert_eq!(h.search("cargo").collect::<Vec<_>>(), vec![1, 3]);
+    assert_eq!(h.search("").count(), 4);
+    assert_eq!(h.search("make").next(), None);
+    assert_eq!(h.find("cargo", 2), Some(3));
+    assert_eq!(h.count_matches("t"), 2);
+}
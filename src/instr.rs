@@ -1,19 +1,37 @@
+use unicode_segmentation::UnicodeSegmentation;
+
 use parser;
 use edit::ModeState;
-use edit::ViMode;
+use edit::{EmacsMode, ViMode, InterruptAction, EofAction};
+use Config;
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum CommonInstr {
     Done,
     Cancel,
     Clear,
-    Noop
+    Suspend,
+    /// Abandon the line and start over with an empty one below it, as
+    /// `C-c` does in bash.
+    DiscardLine,
+    /// Ring the bell, as for a key without a meaning.
+    Noop,
+    /// Do nothing at all, as for a key that was disabled.
+    Ignore
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum HistoryInstr {
     Next,
-    Prev
+    Prev,
+    First,
+    Last,
+    /// Go to the entry given by the vi count, counting from the oldest,
+    /// or back to the line being edited without a count.
+    Goto
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum MoveCursorInstr {
     Left,
     Right,
@@ -21,6 +39,7 @@ pub enum MoveCursorInstr {
     End
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum Instr {
     Common(CommonInstr),
     History(HistoryInstr),
@@ -31,10 +50,14 @@ pub enum Instr {
     MoveWordWsRight,
     MoveWordLeft,
     MoveWordWsLeft,
-    MoveCharRight(char),
-    MoveCharLeft(char),
-    MoveBeforeCharRight(char),
-    MoveBeforeCharLeft(char),
+    /// Move to the end of the next word, as readline's `forward-word`.
+    EmacsMoveWordRight,
+    /// Move to the start of the previous word, as readline's `backward-word`.
+    EmacsMoveWordLeft,
+    MoveCharRight(String),
+    MoveCharLeft(String),
+    MoveBeforeCharRight(String),
+    MoveBeforeCharLeft(String),
     DeleteCharLeftOfCursor,
     DeleteCharRightOfCursor,
     DeleteCharRightOfCursorOrEOF,
@@ -52,13 +75,61 @@ pub enum Instr {
     NormalMode,
     ReplaceMode,
     MoveCharMode(CharMoveType),
+    /// Repeat the last `f`, `F`, `t` or `T` movement.
+    RepeatCharMove,
+    /// Repeat the last `f`, `F`, `t` or `T` movement in the opposite direction.
+    RepeatCharMoveReverse,
     DeleteMode,
     ChangeMode,
     Digit(u32),
-    DoneOrEof
+    DoneOrEof,
+    Undo,
+    CtrlXMode,
+    /// Edit the line in `$VISUAL` or `$EDITOR`, as bash's
+    /// `edit-and-execute-command` without executing it.
+    EditExternally,
+    /// Insert the next key literally.
+    QuotedInsert,
+    /// Start or multiply the Emacs numeric argument by four, as readline's
+    /// `universal-argument`. It is not bound by default, as `C-u` kills
+    /// the text before the cursor.
+    UniversalArgument,
+    KillToEnd,
+    KillToStart,
+    /// Kill the whole line, as readline's `kill-whole-line`.
+    KillLine,
+    KillWordLeft,
+    Yank,
+    YankPop,
+    YankMode,
+    YankLine,
+    PasteAfter,
+    PasteBefore,
+    RepeatLastChange,
+    VisualMode,
+    /// Start or extend the selection to the left, as Shift-Left.
+    SelectLeft,
+    /// Start or extend the selection to the right, as Shift-Right.
+    SelectRight,
+    VisualDelete,
+    VisualChange,
+    VisualYank,
+    ToggleCase,
+    UppercaseLine,
+    LowercaseLine,
+    GMode,
+    MoveLastNonBlank,
+    Complete,
+    TextObjectMode(TextObjectScope),
+    TextObject(TextObjectScope, TextObject),
+    SearchMode,
+    SearchInsert(String),
+    SearchBackspace,
+    SearchOlder,
+    SearchCancel
 }
 
-#[derive(Copy,Clone,PartialEq)]
+#[derive(Copy,Clone,Debug,PartialEq)]
 pub enum CharMoveType {
     BeforeRight,
     BeforeLeft,
@@ -66,17 +137,148 @@ pub enum CharMoveType {
     Left,
 }
 
-pub fn interpret_token(token: parser::Token, edit_mode_state: ModeState) -> Instr {
+/// The modes that key bindings can be made in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Keymap {
+    Emacs,
+    ViInsert,
+    ViNormal
+}
+
+impl Keymap {
+    fn for_mode(mode_state: ModeState) -> Option<Keymap> {
+        match mode_state {
+            ModeState::Emacs(EmacsMode::Normal, _)
+            | ModeState::Emacs(EmacsMode::Argument, _)
+            | ModeState::Emacs(EmacsMode::UniversalArgument, _) => Some(Keymap::Emacs),
+            ModeState::Vi(ViMode::Insert, _) => Some(Keymap::ViInsert),
+            ModeState::Vi(ViMode::Normal, _) => Some(Keymap::ViNormal),
+            _ => None
+        }
+    }
+}
+
+/// Custom key bindings, which take precedence over the built-in ones.
+pub struct KeyBindings {
+    bindings: Vec<(Keymap, parser::Token, Instr)>
+}
+
+impl KeyBindings {
+    pub fn new() -> KeyBindings {
+        KeyBindings { bindings: vec![] }
+    }
+
+    /// Binds a key in a keymap, replacing an earlier binding of the key.
+    pub fn bind(&mut self, keymap: Keymap, token: parser::Token, ins: Instr) {
+        self.bindings.retain(|&(k, ref t, _)| k != keymap || *t != token);
+        self.bindings.push((keymap, token, ins));
+    }
+
+    /// Disables the built-in binding of a key in a keymap, so that it
+    /// has no effect, without ringing the bell.
+    pub fn unbind(&mut self, keymap: Keymap, token: parser::Token) {
+        self.bind(keymap, token, Instr::Common(CommonInstr::Ignore))
+    }
+
+    fn get(&self, keymap: Keymap, token: &parser::Token) -> Option<Instr> {
+        self.bindings.iter()
+            .find(|&&(k, ref t, _)| k == keymap && t == token)
+            .map(|&(_, _, ref ins)| ins.clone())
+    }
+}
+
+/// Whether a text object includes the surrounding whitespace (`a`) or not (`i`).
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum TextObjectScope {
+    Inner,
+    Around,
+}
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum TextObject {
+    Word,
+    WsWord,
+}
+
+fn selects(edit_mode_state: ModeState) -> bool {
+    match edit_mode_state {
+        ModeState::Emacs(EmacsMode::Normal, _)
+        | ModeState::Vi(ViMode::Normal, _)
+        | ModeState::Vi(ViMode::Insert, _)
+        | ModeState::Vi(ViMode::Visual, _) => true,
+        _ => false,
+    }
+}
+
+pub fn interpret_token(token: parser::Token, edit_mode_state: ModeState, cfg: &Config) -> Instr {
+    let bound = Keymap::for_mode(edit_mode_state).and_then(|keymap| cfg.key_bindings.get(keymap, &token));
+    if let Some(ins) = bound {
+        return ins;
+    }
+    let token = match token {
+        parser::Token::CtrlH if !cfg.ctrl_h_erases => return Instr::Common(CommonInstr::Ignore),
+        parser::Token::CtrlZ if cfg.enable_suspend => return Instr::Common(CommonInstr::Suspend),
+        parser::Token::CtrlC => match cfg.interrupt {
+            InterruptAction::Cancel => return Instr::Common(CommonInstr::Cancel),
+            InterruptAction::ClearLine => return Instr::Common(CommonInstr::DiscardLine),
+            InterruptAction::Ignore => return Instr::Common(CommonInstr::Ignore)
+        },
+        parser::Token::CtrlD if cfg.eof == EofAction::Delete => return Instr::DeleteCharRightOfCursor,
+        // behave like the delete key
+        parser::Token::Backspace if cfg.backspace_erases_forward => parser::Token::EscBracket3T,
+        // C-Left and C-Right move by words
+        parser::Token::EscBracketModified(mods, 'C') if mods.ctrl => return match edit_mode_state {
+            ModeState::Emacs(_, _) => Instr::EmacsMoveWordRight,
+            ModeState::Vi(_, _) => Instr::MoveWordRight
+        },
+        parser::Token::EscBracketModified(mods, 'D') if mods.ctrl => return match edit_mode_state {
+            ModeState::Emacs(_, _) => Instr::EmacsMoveWordLeft,
+            ModeState::Vi(_, _) => Instr::MoveWordLeft
+        },
+        // Shift-Left and Shift-Right select text where there is no pending
+        // command to complete
+        parser::Token::EscBracketModified(mods, key @ 'C')
+        | parser::Token::EscBracketModified(mods, key @ 'D') if mods.shift && selects(edit_mode_state) => {
+            return if key == 'C' { Instr::SelectRight } else { Instr::SelectLeft };
+        }
+        // other modifiers do not change the meaning of a key
+        parser::Token::EscBracketModified(_, 'A') => parser::Token::EscBracketA,
+        parser::Token::EscBracketModified(_, 'B') => parser::Token::EscBracketB,
+        parser::Token::EscBracketModified(_, 'C') => parser::Token::EscBracketC,
+        parser::Token::EscBracketModified(_, 'D') => parser::Token::EscBracketD,
+        parser::Token::EscBracketModified(_, 'F') => parser::Token::EscBracketF,
+        parser::Token::EscBracketModified(_, 'H') => parser::Token::EscBracketH,
+        token => token
+    };
     match edit_mode_state {
-        ModeState::Emacs => emacs_mode(token),
+        ModeState::Emacs(EmacsMode::Normal, _) => match token {
+            parser::Token::CtrlU if cfg.ctrl_u_kills_whole_line => Instr::KillLine,
+            token => emacs_mode(token)
+        },
+        ModeState::Emacs(EmacsMode::CtrlX, _) => emacs_ctrl_x_mode(token),
+        ModeState::Emacs(EmacsMode::Search, _) => emacs_search_mode(token),
+        ModeState::Emacs(EmacsMode::Argument, _) => emacs_argument_mode(token),
+        ModeState::Emacs(EmacsMode::UniversalArgument, _) => emacs_argument_mode(token),
         ModeState::Vi(ViMode::Insert, _) => vi_insert_mode(token),
         ModeState::Vi(ViMode::Normal, _) => vi_normal_mode(token),
         ModeState::Vi(ViMode::Replace, _) => vi_replace_mode(token),
         ModeState::Vi(ViMode::MoveChar(move_type), _) => vi_move_char_mode(move_type, token),
         ModeState::Vi(ViMode::DeleteMoveChar(move_type), _) => vi_move_char_mode(move_type, token),
         ModeState::Vi(ViMode::ChangeMoveChar(move_type), _) => vi_move_char_mode(move_type, token),
+        ModeState::Vi(ViMode::YankMoveChar(move_type), _) => vi_move_char_mode(move_type, token),
         ModeState::Vi(ViMode::Delete, _) => vi_delete_mode(token),
         ModeState::Vi(ViMode::Change, _) => vi_change_mode(token),
+        ModeState::Vi(ViMode::Yank, _) => vi_yank_mode(token),
+        ModeState::Vi(ViMode::Visual, _) => vi_visual_mode(token),
+        ModeState::Vi(ViMode::VisualMoveChar(move_type), _) => vi_move_char_mode(move_type, token),
+        ModeState::Vi(ViMode::G, _) => vi_normal_g_mode(token),
+        ModeState::Vi(ViMode::DeleteG, _) => vi_g_mode(token),
+        ModeState::Vi(ViMode::ChangeG, _) => vi_g_mode(token),
+        ModeState::Vi(ViMode::YankG, _) => vi_g_mode(token),
+        ModeState::Vi(ViMode::VisualG, _) => vi_g_mode(token),
+        ModeState::Vi(ViMode::DeleteTextObject(scope), _) => vi_text_object_mode(scope, token),
+        ModeState::Vi(ViMode::ChangeTextObject(scope), _) => vi_text_object_mode(scope, token),
+        ModeState::Vi(ViMode::YankTextObject(scope), _) => vi_text_object_mode(scope, token),
     }
 }
 
@@ -100,15 +302,64 @@ fn emacs_mode(token: parser::Token) -> Instr {
         parser::Token::CtrlE        => Instr::MoveCursor(MoveCursorInstr::End),
         parser::Token::EscBracketF  => Instr::MoveCursor(MoveCursorInstr::End),
         parser::Token::Text(text)   => Instr::InsertAtCursor(text),
+        parser::Token::Paste(text)  => Instr::InsertAtCursor(text),
         parser::Token::CtrlJ        => Instr::Common(CommonInstr::Done),
         parser::Token::CtrlC        => Instr::Common(CommonInstr::Cancel),
         parser::Token::CtrlL        => Instr::Common(CommonInstr::Clear),
+        parser::Token::CtrlUnderscore => Instr::Undo,
+        parser::Token::CtrlX        => Instr::CtrlXMode,
+        parser::Token::CtrlV        => Instr::QuotedInsert,
+        parser::Token::CtrlR        => Instr::SearchMode,
+        parser::Token::Tab          => Instr::Complete,
+        parser::Token::CtrlK        => Instr::KillToEnd,
+        parser::Token::CtrlU        => Instr::KillToStart,
+        parser::Token::CtrlW        => Instr::KillWordLeft,
+        parser::Token::CtrlY        => Instr::Yank,
+        parser::Token::Meta('f')    => Instr::EmacsMoveWordRight,
+        parser::Token::Meta('b')    => Instr::EmacsMoveWordLeft,
+        parser::Token::Meta('y')    => Instr::YankPop,
+        parser::Token::Meta('<')    => Instr::History(HistoryInstr::First),
+        parser::Token::Meta('>')    => Instr::History(HistoryInstr::Last),
+        parser::Token::Meta(c) if c.is_digit(10) => Instr::Digit(c.to_digit(10).unwrap()),
         _                           => Instr::Common(CommonInstr::Noop)
     }
 }
 
+/// Digits are added to the numeric argument, other keys are handled as usual.
+fn emacs_argument_mode(token: parser::Token) -> Instr {
+    let digit = match token {
+        parser::Token::Text(ref text) if text.len() == 1 => text.chars().next().and_then(|c| c.to_digit(10)),
+        _ => None
+    };
+    match digit {
+        Some(digit)                 => Instr::Digit(digit),
+        None                        => emacs_mode(token)
+    }
+}
+
+fn emacs_ctrl_x_mode(token: parser::Token) -> Instr {
+    match token {
+        parser::Token::CtrlU        => Instr::Undo,
+        parser::Token::CtrlE        => Instr::EditExternally,
+        _                           => Instr::Common(CommonInstr::Noop)
+    }
+}
+
+/// Keys that do not belong to the search end it, and are then handled as usual.
+fn emacs_search_mode(token: parser::Token) -> Instr {
+    match token {
+        parser::Token::Text(text)   => Instr::SearchInsert(text),
+        parser::Token::Backspace    => Instr::SearchBackspace,
+        parser::Token::CtrlH        => Instr::SearchBackspace,
+        parser::Token::CtrlR        => Instr::SearchOlder,
+        parser::Token::CtrlG        => Instr::SearchCancel,
+        _                           => emacs_mode(token)
+    }
+}
+
 fn vi_common(token: &parser::Token) -> Instr {
     match *token {
+        parser::Token::Paste(ref text) => Instr::InsertAtCursor(text.clone()),
         parser::Token::Enter        => Instr::Common(CommonInstr::Done),
         parser::Token::CtrlJ        => Instr::Common(CommonInstr::Done),
         parser::Token::CtrlD        => Instr::DoneOrEof,
@@ -133,7 +384,11 @@ fn vi_common(token: &parser::Token) -> Instr {
 fn vi_insert_mode(token: parser::Token) -> Instr {
     match token {
         parser::Token::Text(text)   => Instr::InsertAtCursor(text),
+        // an escape within pasted text does not leave insert mode
+        parser::Token::Paste(text)  => Instr::InsertAtCursor(text),
         parser::Token::CtrlH        => Instr::DeleteCharLeftOfCursor,
+        parser::Token::Tab          => Instr::Complete,
+        parser::Token::CtrlV        => Instr::QuotedInsert,
         _                           => vi_common(&token),
     }
 }
@@ -148,12 +403,23 @@ fn vi_normal_mode(token: parser::Token) -> Instr {
             "$"                     => Instr::MoveCursor(MoveCursorInstr::End),
 
             "x"                     => Instr::DeleteCharRightOfCursor,
+            "~"                     => Instr::ToggleCase,
+            "g"                     => Instr::GMode,
             "s"                     => Instr::Substitute,
+            "S"                     => Instr::ChangeLine,
             "r"                     => Instr::ReplaceMode,
             "c"                     => Instr::ChangeMode,
             "C"                     => Instr::ChangeToEnd,
             "d"                     => Instr::DeleteMode,
             "D"                     => Instr::DeleteToEnd,
+            "y"                     => Instr::YankMode,
+            "Y"                     => Instr::YankLine,
+            "p"                     => Instr::PasteAfter,
+            "P"                     => Instr::PasteBefore,
+            "."                     => Instr::RepeatLastChange,
+            "u"                     => Instr::Undo,
+            "G"                     => Instr::History(HistoryInstr::Goto),
+            "v"                     => Instr::VisualMode,
 
             "e"                     => Instr::MoveEndOfWordRight,
             "E"                     => Instr::MoveEndOfWordWsRight,
@@ -165,6 +431,8 @@ fn vi_normal_mode(token: parser::Token) -> Instr {
             "T"                     => Instr::MoveCharMode(CharMoveType::BeforeLeft),
             "f"                     => Instr::MoveCharMode(CharMoveType::Right),
             "F"                     => Instr::MoveCharMode(CharMoveType::Left),
+            ";"                     => Instr::RepeatCharMove,
+            ","                     => Instr::RepeatCharMoveReverse,
 
             "a"                     => Instr::Append,
             "A"                     => Instr::AppendEnd,
@@ -194,16 +462,27 @@ fn vi_replace_mode(token: parser::Token) -> Instr {
 }
 fn vi_move_char_mode(move_type: CharMoveType, token: parser::Token) -> Instr {
     match token {
-        parser::Token::Text(ref text) => match (move_type, text.chars().next()) {
-            (CharMoveType::BeforeLeft, Some(c))  => Instr::MoveBeforeCharLeft(c),
-            (CharMoveType::BeforeRight, Some(c)) => Instr::MoveBeforeCharRight(c),
-            (CharMoveType::Left, Some(c))        => Instr::MoveCharLeft(c),
-            (CharMoveType::Right, Some(c))       => Instr::MoveCharRight(c),
+        // the target may be a grapheme made of several code points
+        parser::Token::Text(ref text) => match (move_type, text.graphemes(true).next()) {
+            (CharMoveType::BeforeLeft, Some(g))  => Instr::MoveBeforeCharLeft(g.to_owned()),
+            (CharMoveType::BeforeRight, Some(g)) => Instr::MoveBeforeCharRight(g.to_owned()),
+            (CharMoveType::Left, Some(g))        => Instr::MoveCharLeft(g.to_owned()),
+            (CharMoveType::Right, Some(g))       => Instr::MoveCharRight(g.to_owned()),
             (_, None)                            => Instr::NormalMode, // this is probably unreachable!()
         },
         _                           => Instr::NormalMode,
     }
 }
+/// The movement of the given character movement in the opposite direction.
+pub fn reverse_char_move(ins: &Instr) -> Option<Instr> {
+    match *ins {
+        Instr::MoveCharRight(ref g)       => Some(Instr::MoveCharLeft(g.clone())),
+        Instr::MoveCharLeft(ref g)        => Some(Instr::MoveCharRight(g.clone())),
+        Instr::MoveBeforeCharRight(ref g) => Some(Instr::MoveBeforeCharLeft(g.clone())),
+        Instr::MoveBeforeCharLeft(ref g)  => Some(Instr::MoveBeforeCharRight(g.clone())),
+        _                                 => None,
+    }
+}
 fn vi_change_delete_common(token: &parser::Token) -> Instr {
     match *token {
         parser::Token::Text(ref text) => match text.as_ref() {
@@ -222,6 +501,9 @@ fn vi_change_delete_common(token: &parser::Token) -> Instr {
             "T"                     => Instr::MoveCharMode(CharMoveType::BeforeLeft),
             "f"                     => Instr::MoveCharMode(CharMoveType::Right),
             "F"                     => Instr::MoveCharMode(CharMoveType::Left),
+            ";"                     => Instr::RepeatCharMove,
+            ","                     => Instr::RepeatCharMoveReverse,
+            "g"                     => Instr::GMode,
 
             "1"                     => Instr::Digit(1),
             "2"                     => Instr::Digit(2),
@@ -241,6 +523,8 @@ fn vi_change_mode(token: parser::Token) -> Instr {
     match token {
         parser::Token::Text(ref text) => match text.as_ref() {
             "c"                     => Instr::ChangeLine,
+            "i"                     => Instr::TextObjectMode(TextObjectScope::Inner),
+            "a"                     => Instr::TextObjectMode(TextObjectScope::Around),
             _                       => vi_change_delete_common(&token),
         },
         _                           => Instr::NormalMode,
@@ -250,8 +534,80 @@ fn vi_delete_mode(token: parser::Token) -> Instr {
     match token {
         parser::Token::Text(ref text) => match text.as_ref() {
             "d"                     => Instr::DeleteLine,
+            "i"                     => Instr::TextObjectMode(TextObjectScope::Inner),
+            "a"                     => Instr::TextObjectMode(TextObjectScope::Around),
+            _                       => vi_change_delete_common(&token),
+        },
+        _                           => Instr::NormalMode,
+    }
+}
+fn vi_yank_mode(token: parser::Token) -> Instr {
+    match token {
+        parser::Token::Text(ref text) => match text.as_ref() {
+            "y"                     => Instr::YankLine,
+            "i"                     => Instr::TextObjectMode(TextObjectScope::Inner),
+            "a"                     => Instr::TextObjectMode(TextObjectScope::Around),
             _                       => vi_change_delete_common(&token),
         },
         _                           => Instr::NormalMode,
     }
 }
+fn vi_visual_mode(token: parser::Token) -> Instr {
+    match token {
+        parser::Token::Text(ref text) => match text.as_ref() {
+            "d" | "x"               => Instr::VisualDelete,
+            "c" | "s"               => Instr::VisualChange,
+            "y"                     => Instr::VisualYank,
+            "v"                     => Instr::NormalMode,
+            _                       => vi_change_delete_common(&token),
+        },
+        parser::Token::EscBracket3T => Instr::VisualDelete,
+        parser::Token::Backspace    => Instr::MoveCursor(MoveCursorInstr::Left),
+        // the selection does not carry over to other history entries
+        parser::Token::EscBracketA  => Instr::Common(CommonInstr::Noop),
+        parser::Token::EscBracketB  => Instr::Common(CommonInstr::Noop),
+        _                           => vi_common(&token),
+    }
+}
+fn vi_normal_g_mode(token: parser::Token) -> Instr {
+    match token {
+        parser::Token::Text(ref text) if text == "g" => Instr::History(HistoryInstr::First),
+        _                           => vi_g_mode(token),
+    }
+}
+fn vi_g_mode(token: parser::Token) -> Instr {
+    match token {
+        parser::Token::Text(ref text) => match text.as_ref() {
+            "_"                     => Instr::MoveLastNonBlank,
+            _                       => Instr::NormalMode,
+        },
+        _                           => Instr::NormalMode,
+    }
+}
+fn vi_text_object_mode(scope: TextObjectScope, token: parser::Token) -> Instr {
+    match token {
+        parser::Token::Text(ref text) => match text.as_ref() {
+            "w"                     => Instr::TextObject(scope, TextObject::Word),
+            "W"                     => Instr::TextObject(scope, TextObject::WsWord),
+            _                       => Instr::NormalMode,
+        },
+        _                           => Instr::NormalMode,
+    }
+}
+
+#[test]
+fn text_object_after_operator() {
+    let cfg = Config::default();
+    for &mode in &[ViMode::Delete, ViMode::Change, ViMode::Yank] {
+        let ins = interpret_token(parser::Token::Text("i".to_owned()), ModeState::Vi(mode, 0), &cfg);
+        assert_eq!(ins, Instr::TextObjectMode(TextObjectScope::Inner));
+        let ins = interpret_token(parser::Token::Text("a".to_owned()), ModeState::Vi(mode, 0), &cfg);
+        assert_eq!(ins, Instr::TextObjectMode(TextObjectScope::Around));
+    }
+    let state = ModeState::Vi(ViMode::DeleteTextObject(TextObjectScope::Around), 0);
+    let ins = interpret_token(parser::Token::Text("W".to_owned()), state, &cfg);
+    assert_eq!(ins, Instr::TextObject(TextObjectScope::Around, TextObject::WsWord));
+    // without an operator, i still enters insert mode
+    let ins = interpret_token(parser::Token::Text("i".to_owned()), ModeState::Vi(ViMode::Normal, 0), &cfg);
+    assert_eq!(ins, Instr::Insert);
+}
@@ -7,8 +7,16 @@
 //! Features that are currently supported:
 //!
 //! - Cursor movement and text insertion
-//! - Jumps (via `C-a` and `C-e`)
-//! - History browsing (via `C-p` and `C-n`)
+//! - Jumps (via `C-a`, `C-e`, `M-f` and `M-b`)
+//! - History browsing (via `C-p`, `C-n`, `M-<` and `M->`)
+//! - Incremental history search (via `C-r`)
+//! - Undo (via `C-_` and `C-x C-u`)
+//! - Killing and yanking (via `C-k`, `C-u`, `C-w`, `C-y` and `M-y`)
+//! - Completion (via `Tab`)
+//! - Numeric arguments (via `M-<digit>`)
+//! - Editing the line in `$EDITOR` (via `C-x C-e`)
+//! - Input spanning several lines (via `Copperline::set_validator`)
+//! - Reading passwords without echo (via `Copperline::read_password`)
 //!
 //! It has a clean, hackable codebase, which I hope will foster
 //! contributions so that the Rust ecosystem will soon be able to utilise
@@ -24,41 +32,597 @@ mod enc;
 mod error;
 mod builder;
 mod buffer;
+mod completion;
+mod hint;
+mod validate;
 mod history;
+mod undo;
+mod clipboard;
 mod parser;
 mod instr;
 mod edit;
 mod run;
 mod term;
 
-use std::mem::drop;
+use std::cell::RefCell;
+use std::collections::vec_deque;
 use std::os::unix::io::{RawFd, AsRawFd};
 
+use encoding::types::DecoderTrap;
+
 pub use enc::Encoding;
 pub use error::Error;
 use history::History;
+pub use history::HistoryConfig;
+pub use completion::{Completer, Completion, NoMatchHandling};
+pub use hint::Hinter;
+pub use validate::Validator;
+pub use parser::{Token, Modifiers};
+pub use instr::{Instr, CommonInstr, HistoryInstr, MoveCursorInstr, CharMoveType, Keymap, KeyBindings};
 use term::Term;
-use edit::EditCtx;
-pub use edit::EditMode;
-use run::RunIO;
+pub use term::TermSupport;
+use edit::{EditCtx, EditState};
+pub use edit::{EditMode, EmptyBackspace, ControlCharPolicy, InterruptAction, EofAction, EchoMode, WrapMode};
+pub use builder::CursorShape;
+pub use run::{PartialLineHandling, LineEditor, EditStep, RunIO, BufferedIO};
+
+/// Runs the line editor on input as if it was typed at a terminal, and
+/// returns the accepted line.
+///
+/// Each byte of the input is delivered separately, the way a user would
+/// type it, and nothing is written to the terminal. This is useful for
+/// testing and replaying editing sessions.
+///
+/// ```
+/// use copperline::{Config, EditMode};
+///
+/// let cfg = Config::builder().mode(EditMode::Vi).build();
+/// let line = copperline::simulate(b"hello world\x1bbcwthere\r", "> ", &cfg);
+/// assert_eq!(line, Ok("hello there".to_string()));
+/// ```
+pub fn simulate(input: &[u8], prompt: &str, cfg: &Config) -> Result<String, Error> {
+    let history = History::new();
+    let mut ctx = EditCtx::new(prompt, &history, cfg);
+    let mut io = run::ByteIO::new(input);
+    run::run_edit(&mut ctx, &mut io)
+}
+
+/// How reading a line ended, see `Copperline::read_line_outcome`.
+#[derive(Debug, PartialEq)]
+pub enum ReadOutcome {
+    /// A line was accepted.
+    Line(String),
+    /// Editing was cancelled, usually by `C-c`.
+    Interrupted,
+    /// The end of the input was requested by a key, usually `C-d` on an
+    /// empty line.
+    Eof
+}
+
+/// The capabilities of this version of the library, see
+/// `Copperline::features`.
+///
+/// Each is available if the cargo feature of the same name, with dashes
+/// instead of underscores, is enabled, as they all are by default. More
+/// may be added, so a `FeatureSet` can only be obtained from
+/// `Copperline::features`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct FeatureSet {
+    /// The version of the library.
+    pub version: &'static str,
+    /// Completion via `Config::completer`.
+    pub completion: bool,
+    /// Hints via `Copperline::set_hinter`.
+    pub hints: bool,
+    /// Input spanning several lines via `Copperline::set_validator`.
+    pub validation: bool,
+    /// Incremental history search.
+    pub history_search: bool,
+    /// Editing in vi mode.
+    pub vi_mode: bool,
+    /// The vi text objects `iw`, `aw`, `iW` and `aW`.
+    pub vi_text_objects: bool,
+    /// Bracketed paste mode.
+    pub bracketed_paste: bool
+}
 
 pub struct Copperline {
     term: Term,
-    history: History
+    history: History,
+    accepted_by: Option<Token>,
+    /// Whether the last line was finished by the end of the input.
+    input_ended: bool,
+    /// The line being edited through `feed`.
+    fed: Option<EditState>,
+    on_line_accepted: Option<Box<FnMut(&str) + Send>>,
+    hinter: Option<Box<Hinter + Send>>,
+    validator: Option<Box<Validator + Send>>,
+    prompt_fn: Option<Box<FnMut() -> String + Send>>,
+    on_parse_error: Option<Box<FnMut(&[u8]) + Send>>
 }
 
 pub struct Config {
-    pub encoding: Encoding,
-    pub mode: EditMode
+    encoding: Encoding,
+    mode: EditMode,
+    kill_ring_size: usize,
+    vi_normal_cursor: Option<CursorShape>,
+    vi_insert_cursor: Option<CursorShape>,
+    coalesce_key_repeat: bool,
+    partial_line: PartialLineHandling,
+    bell: bool,
+    bell_on_empty_history: bool,
+    bracketed_paste: bool,
+    term_support: TermSupport,
+    history: HistoryConfig,
+    history_filter: Option<Box<Fn(&str) -> Option<String> + Send>>,
+    on_change: Option<RefCell<Box<FnMut(&str, usize) + Send>>>,
+    completer: Option<Box<Completer + Send>>,
+    busy_indicator: Option<String>,
+    key_bindings: KeyBindings,
+    mark_trailing_space: bool,
+    word_chars: String,
+    ctrl_h_erases: bool,
+    backspace_erases_forward: bool,
+    horizontal_scroll: bool,
+    wrap_mode: WrapMode,
+    enable_suspend: bool,
+    initial_capacity: usize,
+    complete_after_word_only: bool,
+    right_prompt: Option<String>,
+    echo_cancel: bool,
+    cancel_marker: String,
+    complete_on_type: bool,
+    paste_warn_threshold: Option<usize>,
+    no_match: NoMatchHandling,
+    empty_backspace: EmptyBackspace,
+    fallback_to_plain: bool,
+    control_char_policy: ControlCharPolicy,
+    prompt_on_separate_line: bool,
+    redraw_on_accept: bool,
+    ctrl_u_kills_whole_line: bool,
+    continuation_prompt: String,
+    show_line_numbers: bool,
+    echo: EchoMode,
+    interrupt: InterruptAction,
+    eof: EofAction,
+    cursor_query_timeout: u32
 }
 
 impl Config {
+    /// Returns a builder that starts from the default config.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder { cfg: Config::default() }
+    }
+
     pub fn default() -> Config {
         Config {
             encoding: Encoding::Utf8,
-            mode: EditMode::Emacs
+            mode: EditMode::Emacs,
+            kill_ring_size: 10,
+            vi_normal_cursor: None,
+            vi_insert_cursor: None,
+            coalesce_key_repeat: true,
+            partial_line: PartialLineHandling::Marker,
+            bell: true,
+            bell_on_empty_history: false,
+            bracketed_paste: true,
+            term_support: TermSupport::Detect,
+            history: HistoryConfig::default(),
+            history_filter: None,
+            on_change: None,
+            completer: None,
+            busy_indicator: None,
+            key_bindings: KeyBindings::new(),
+            mark_trailing_space: false,
+            word_chars: String::new(),
+            ctrl_h_erases: true,
+            backspace_erases_forward: false,
+            horizontal_scroll: false,
+            wrap_mode: WrapMode::Char,
+            enable_suspend: false,
+            initial_capacity: 0,
+            complete_after_word_only: false,
+            right_prompt: None,
+            echo_cancel: false,
+            cancel_marker: "^C".to_owned(),
+            complete_on_type: false,
+            paste_warn_threshold: None,
+            no_match: NoMatchHandling::Bell,
+            empty_backspace: EmptyBackspace::Ignore,
+            fallback_to_plain: false,
+            control_char_policy: ControlCharPolicy::Keep,
+            prompt_on_separate_line: false,
+            redraw_on_accept: false,
+            ctrl_u_kills_whole_line: false,
+            continuation_prompt: "... ".to_owned(),
+            show_line_numbers: false,
+            echo: EchoMode::Normal,
+            interrupt: InterruptAction::Cancel,
+            eof: EofAction::Eof,
+            cursor_query_timeout: 100
         }
     }
+
+    /// The custom key bindings, to bind keys in other keymaps than the
+    /// one editing starts in.
+    pub fn key_bindings(&mut self) -> &mut KeyBindings {
+        &mut self.key_bindings
+    }
+
+    /// Adds characters that vi word movements treat as part of words.
+    pub fn word_chars(&mut self, extra: &str) {
+        self.word_chars.push_str(extra)
+    }
+
+    /// Binds a key in the keymap that editing starts in, which is vi
+    /// insert mode for vi.
+    ///
+    /// Use `key_bindings` to bind keys in vi normal mode.
+    pub fn bind(&mut self, token: Token, ins: Instr) {
+        let keymap = match self.mode {
+            EditMode::Emacs => Keymap::Emacs,
+            EditMode::Vi => Keymap::ViInsert
+        };
+        self.key_bindings.bind(keymap, token, ins)
+    }
+
+    /// Disables the built-in binding of a key in the keymap that editing
+    /// starts in, such as `C-l` when the application uses it itself.
+    pub fn unbind(&mut self, token: Token) {
+        let keymap = match self.mode {
+            EditMode::Emacs => Keymap::Emacs,
+            EditMode::Vi => Keymap::ViInsert
+        };
+        self.key_bindings.unbind(keymap, token)
+    }
+}
+
+/// Builds a `Config`, starting from the defaults.
+///
+/// ```
+/// use copperline::{Config, EditMode};
+///
+/// let cfg = Config::builder().mode(EditMode::Vi).bell(false).build();
+/// ```
+pub struct ConfigBuilder {
+    cfg: Config
+}
+
+impl ConfigBuilder {
+    /// The encoding of the input and output, UTF-8 by default.
+    pub fn encoding(mut self, encoding: Encoding) -> ConfigBuilder {
+        self.cfg.encoding = encoding;
+        self
+    }
+
+    /// Whether keys are interpreted as in Emacs (the default) or vi.
+    pub fn mode(mut self, mode: EditMode) -> ConfigBuilder {
+        self.cfg.mode = mode;
+        self
+    }
+
+    /// The number of killed strings that are retained for yanking, where
+    /// 0 disables killing. Text deleted or yanked in vi mode is kept in
+    /// the vi register instead, but the newest text cut in either mode is
+    /// what both `C-y` and `p` paste.
+    pub fn kill_ring_size(mut self, kill_ring_size: usize) -> ConfigBuilder {
+        self.cfg.kill_ring_size = kill_ring_size;
+        self
+    }
+
+    /// The cursor shape to use outside of vi insert mode.
+    pub fn vi_normal_cursor(mut self, vi_normal_cursor: CursorShape) -> ConfigBuilder {
+        self.cfg.vi_normal_cursor = Some(vi_normal_cursor);
+        self
+    }
+
+    /// The cursor shape to use in vi insert mode.
+    pub fn vi_insert_cursor(mut self, vi_insert_cursor: CursorShape) -> ConfigBuilder {
+        self.cfg.vi_insert_cursor = Some(vi_insert_cursor);
+        self
+    }
+
+    /// Whether a burst of the same movement key is applied at once,
+    /// rendering the line only for the final position.
+    pub fn coalesce_key_repeat(mut self, coalesce_key_repeat: bool) -> ConfigBuilder {
+        self.cfg.coalesce_key_repeat = coalesce_key_repeat;
+        self
+    }
+
+    /// What to do when the cursor is not at the first column before the prompt is shown.
+    pub fn partial_line(mut self, partial_line: PartialLineHandling) -> ConfigBuilder {
+        self.cfg.partial_line = partial_line;
+        self
+    }
+
+    /// Whether to make sure the prompt starts on a line of its own, as
+    /// described for `partial_line`, which needs the terminal to report
+    /// the cursor position. Disabling it is the same as
+    /// `PartialLineHandling::Ignore`.
+    pub fn protect_newline(mut self, protect_newline: bool) -> ConfigBuilder {
+        self.cfg.partial_line = match (protect_newline, self.cfg.partial_line) {
+            (false, _) => PartialLineHandling::Ignore,
+            (true, PartialLineHandling::Ignore) => PartialLineHandling::Marker,
+            (true, handling) => handling
+        };
+        self
+    }
+
+    /// How many milliseconds to wait for the terminal to report the cursor
+    /// position for `protect_newline`. Terminals that do not answer in
+    /// time, as happens with some multiplexers and over slow connections,
+    /// get no protection.
+    pub fn cursor_query_timeout(mut self, cursor_query_timeout: u32) -> ConfigBuilder {
+        self.cfg.cursor_query_timeout = cursor_query_timeout;
+        self
+    }
+
+    /// Whether to ring the bell when a key has no effect.
+    pub fn bell(mut self, bell: bool) -> ConfigBuilder {
+        self.cfg.bell = bell;
+        self
+    }
+
+    /// Whether to ring the bell when browsing back through an empty history.
+    pub fn bell_on_empty_history(mut self, bell_on_empty_history: bool) -> ConfigBuilder {
+        self.cfg.bell_on_empty_history = bell_on_empty_history;
+        self
+    }
+
+    /// Whether pasted text is inserted as is, rather than interpreted as keys.
+    pub fn bracketed_paste(mut self, bracketed_paste: bool) -> ConfigBuilder {
+        self.cfg.bracketed_paste = bracketed_paste;
+        self
+    }
+
+    /// Overrides the detection of terminals that do not support line editing.
+    pub fn term_support(mut self, term_support: TermSupport) -> ConfigBuilder {
+        self.cfg.term_support = term_support;
+        self
+    }
+
+    /// Controls which lines are added to the history.
+    pub fn history(mut self, history: HistoryConfig) -> ConfigBuilder {
+        self.cfg.history = history;
+        self
+    }
+
+    /// Transforms lines before they are added to the history, or drops
+    /// them by returning `None`. The options of `history` apply to the
+    /// transformed line.
+    pub fn history_filter<F: Fn(&str) -> Option<String> + Send + 'static>(mut self, f: F) -> ConfigBuilder {
+        self.cfg.history_filter = Some(Box::new(f));
+        self
+    }
+
+    /// Called with the line and the byte offset of the cursor after every
+    /// instruction that changes the line, before it is rendered, for
+    /// example to preview the effect of the line.
+    pub fn on_change<F: FnMut(&str, usize) + Send + 'static>(mut self, f: F) -> ConfigBuilder {
+        self.cfg.on_change = Some(RefCell::new(Box::new(f)));
+        self
+    }
+
+    /// Provides the completions inserted by `Tab`.
+    pub fn completer<C: Completer + Send + 'static>(mut self, completer: C) -> ConfigBuilder {
+        self.cfg.completer = Some(Box::new(completer));
+        self
+    }
+
+    /// A marker shown after the line while the completer runs, for
+    /// completers that may take a while.
+    pub fn busy_indicator(mut self, busy_indicator: &str) -> ConfigBuilder {
+        self.cfg.busy_indicator = Some(busy_indicator.to_owned());
+        self
+    }
+
+    /// Whether whitespace at the end of the line is shown inverted, so it
+    /// can be told apart from the empty space after the line.
+    pub fn mark_trailing_space(mut self, mark_trailing_space: bool) -> ConfigBuilder {
+        self.cfg.mark_trailing_space = mark_trailing_space;
+        self
+    }
+
+    /// Whether `C-h` deletes the character left of the cursor. Otherwise
+    /// it has no effect unless it is bound to an instruction.
+    pub fn ctrl_h_erases(mut self, ctrl_h_erases: bool) -> ConfigBuilder {
+        self.cfg.ctrl_h_erases = ctrl_h_erases;
+        self
+    }
+
+    /// Whether the backspace key deletes the character right of the
+    /// cursor, for terminals that send it for the delete key.
+    pub fn backspace_erases_forward(mut self, backspace_erases_forward: bool) -> ConfigBuilder {
+        self.cfg.backspace_erases_forward = backspace_erases_forward;
+        self
+    }
+
+    /// Whether lines that do not fit into the terminal scroll horizontally
+    /// to keep the cursor in view, instead of wrapping.
+    pub fn horizontal_scroll(mut self, horizontal_scroll: bool) -> ConfigBuilder {
+        self.cfg.horizontal_scroll = horizontal_scroll;
+        self
+    }
+
+    /// Where lines that do not fit into the terminal are broken onto the
+    /// next row, unless they scroll horizontally.
+    pub fn wrap_mode(mut self, wrap_mode: WrapMode) -> ConfigBuilder {
+        self.cfg.wrap_mode = wrap_mode;
+        self
+    }
+
+    /// Whether `C-z` suspends the process, as it would outside of raw
+    /// mode. The line is redrawn once the process is resumed.
+    pub fn enable_suspend(mut self, enable_suspend: bool) -> ConfigBuilder {
+        self.cfg.enable_suspend = enable_suspend;
+        self
+    }
+
+    /// The number of bytes the line can grow to before the buffer is
+    /// reallocated, for input of a predictable size.
+    pub fn initial_capacity(mut self, initial_capacity: usize) -> ConfigBuilder {
+        self.cfg.initial_capacity = initial_capacity;
+        self
+    }
+
+    /// Whether `Tab` completes only when the cursor is right after a word
+    /// character. Otherwise it inserts four spaces.
+    pub fn complete_after_word_only(mut self, complete_after_word_only: bool) -> ConfigBuilder {
+        self.cfg.complete_after_word_only = complete_after_word_only;
+        self
+    }
+
+    /// A prompt shown flush to the right edge of the terminal, as long as
+    /// the line does not reach it.
+    pub fn right_prompt(mut self, right_prompt: &str) -> ConfigBuilder {
+        self.cfg.right_prompt = Some(right_prompt.to_owned());
+        self
+    }
+
+    /// Whether `cancel_marker` is shown after the line when editing is
+    /// cancelled, before moving to the next line.
+    pub fn echo_cancel(mut self, echo_cancel: bool) -> ConfigBuilder {
+        self.cfg.echo_cancel = echo_cancel;
+        self
+    }
+
+    /// The marker shown when editing is cancelled, `^C` by default.
+    pub fn cancel_marker(mut self, cancel_marker: &str) -> ConfigBuilder {
+        self.cfg.cancel_marker = cancel_marker.to_owned();
+        self
+    }
+
+    /// Whether the completions of the text before the cursor are listed
+    /// below the line whenever it changes. They are still only inserted
+    /// by `Tab`.
+    pub fn complete_on_type(mut self, complete_on_type: bool) -> ConfigBuilder {
+        self.cfg.complete_on_type = complete_on_type;
+        self
+    }
+
+    /// The number of characters above which inserting pasted text has to
+    /// be confirmed with `y` first. Without bracketed paste, more
+    /// characters than that arriving at once, without any escape
+    /// sequences, are taken to be pasted.
+    pub fn paste_warn_threshold(mut self, paste_warn_threshold: usize) -> ConfigBuilder {
+        self.cfg.paste_warn_threshold = Some(paste_warn_threshold);
+        self
+    }
+
+    /// What `Tab` does when the completer has no candidates.
+    pub fn no_match(mut self, no_match: NoMatchHandling) -> ConfigBuilder {
+        self.cfg.no_match = no_match;
+        self
+    }
+
+    /// What `Backspace` does on an empty line, nothing by default.
+    pub fn empty_backspace(mut self, empty_backspace: EmptyBackspace) -> ConfigBuilder {
+        self.cfg.empty_backspace = empty_backspace;
+        self
+    }
+
+    /// Whether `read_line` reads a line without editing when the input or
+    /// output is not a terminal, such as when input is piped, instead of
+    /// failing with `Error::UnsupportedTerm`.
+    pub fn fallback_to_plain(mut self, fallback_to_plain: bool) -> ConfigBuilder {
+        self.cfg.fallback_to_plain = fallback_to_plain;
+        self
+    }
+
+    /// What happens to control characters within typed or pasted text,
+    /// which are kept by default. Text inserted with `C-v` is kept as is.
+    pub fn control_char_policy(mut self, control_char_policy: ControlCharPolicy) -> ConfigBuilder {
+        self.cfg.control_char_policy = control_char_policy;
+        self
+    }
+
+    /// Whether the prompt is shown on a line of its own, with the input
+    /// on the line below it.
+    pub fn prompt_on_separate_line(mut self, prompt_on_separate_line: bool) -> ConfigBuilder {
+        self.cfg.prompt_on_separate_line = prompt_on_separate_line;
+        self
+    }
+
+    /// Whether the accepted line is rendered once more before moving to
+    /// the next line, leaving out hints and the right prompt, so that only
+    /// the accepted text remains on the screen.
+    pub fn redraw_on_accept(mut self, redraw_on_accept: bool) -> ConfigBuilder {
+        self.cfg.redraw_on_accept = redraw_on_accept;
+        self
+    }
+
+    /// Whether `C-u` kills the whole line, instead of only the text
+    /// before the cursor like readline's `unix-line-discard`.
+    pub fn ctrl_u_kills_whole_line(mut self, ctrl_u_kills_whole_line: bool) -> ConfigBuilder {
+        self.cfg.ctrl_u_kills_whole_line = ctrl_u_kills_whole_line;
+        self
+    }
+
+    /// The prompt shown before the lines that continue incomplete input,
+    /// see `Copperline::set_validator`.
+    pub fn continuation_prompt(mut self, continuation_prompt: &str) -> ConfigBuilder {
+        self.cfg.continuation_prompt = continuation_prompt.to_owned();
+        self
+    }
+
+    /// Whether the number of each line of the input is shown after the
+    /// prompt, counting the lines that continue incomplete input.
+    pub fn show_line_numbers(mut self, show_line_numbers: bool) -> ConfigBuilder {
+        self.cfg.show_line_numbers = show_line_numbers;
+        self
+    }
+
+    /// How the typed text is shown, such as masked for secrets. Hints are
+    /// only shown if the text is echoed as it is.
+    pub fn echo(mut self, echo: EchoMode) -> ConfigBuilder {
+        self.cfg.echo = echo;
+        self
+    }
+
+    /// What `C-c` does, which is to cancel with `Error::Cancel` by
+    /// default. Abandoning the line shows `cancel_marker` after it.
+    pub fn interrupt(mut self, interrupt: InterruptAction) -> ConfigBuilder {
+        self.cfg.interrupt = interrupt;
+        self
+    }
+
+    /// What `C-d` does, which is to end the input on an empty line by
+    /// default.
+    pub fn eof(mut self, eof: EofAction) -> ConfigBuilder {
+        self.cfg.eof = eof;
+        self
+    }
+
+    /// Adds characters that vi word movements treat as part of words, in
+    /// addition to letters, digits and `_`.
+    pub fn word_chars(mut self, extra: &str) -> ConfigBuilder {
+        self.cfg.word_chars(extra);
+        self
+    }
+
+    /// Binds a key in the keymap that editing starts in, see `Config::bind`.
+    pub fn bind(mut self, token: Token, ins: Instr) -> ConfigBuilder {
+        self.cfg.bind(token, ins);
+        self
+    }
+
+    /// Binds a key in a keymap.
+    pub fn bind_in(mut self, keymap: Keymap, token: Token, ins: Instr) -> ConfigBuilder {
+        self.cfg.key_bindings.bind(keymap, token, ins);
+        self
+    }
+
+    /// Disables the built-in binding of a key, see `Config::unbind`.
+    pub fn unbind(mut self, token: Token) -> ConfigBuilder {
+        self.cfg.unbind(token);
+        self
+    }
+
+    /// Returns the config with the options that were set.
+    pub fn build(self) -> Config {
+        self.cfg
+    }
 }
 
 impl Copperline {
@@ -77,24 +641,260 @@ impl Copperline {
     pub fn new_from_raw_fds(ifd: RawFd, ofd: RawFd) -> Copperline {
         Copperline {
             term: Term::new(ifd, ofd),
-            history: History::new()
+            history: History::new(),
+            accepted_by: None,
+            input_ended: false,
+            fed: None,
+            on_line_accepted: None,
+            hinter: None,
+            validator: None,
+            prompt_fn: None,
+            on_parse_error: None
+        }
+    }
+
+    /// The capabilities of this version of the library, for applications
+    /// that degrade gracefully without some of them.
+    pub fn features() -> FeatureSet {
+        FeatureSet {
+            version: env!("CARGO_PKG_VERSION"),
+            completion: cfg!(feature = "completion"),
+            hints: cfg!(feature = "hints"),
+            validation: cfg!(feature = "validation"),
+            history_search: cfg!(feature = "history-search"),
+            vi_mode: cfg!(feature = "vi-mode"),
+            vi_text_objects: cfg!(feature = "vi-text-objects"),
+            bracketed_paste: cfg!(feature = "bracketed-paste")
+        }
+    }
+
+    /// Sets the hinter that provides hints shown dimmed after the line
+    /// while it is edited. Hints are not part of the returned line.
+    pub fn set_hinter<H: Hinter + Send + 'static>(&mut self, hinter: H) {
+        self.hinter = Some(Box::new(hinter));
+    }
+
+    /// Sets the validator that decides whether the input is complete when
+    /// Enter is pressed. Incomplete input continues on a new line after
+    /// `Config::continuation_prompt`, and the lines are returned joined by
+    /// `\n` once the input is complete. When such input is recalled from
+    /// the history, its lines are joined by spaces instead, as only a
+    /// single line can be edited.
+    pub fn set_validator<V: Validator + Send + 'static>(&mut self, validator: V) {
+        self.validator = Some(Box::new(validator));
+    }
+
+    /// Sets a function that is called with every line accepted by
+    /// `read_line` or `feed`, before it is returned.
+    ///
+    /// It is not called when editing is cancelled or the input ends.
+    pub fn set_on_line_accepted<F: FnMut(&str) + Send + 'static>(&mut self, f: F) {
+        self.on_line_accepted = Some(Box::new(f));
+    }
+
+    /// Sets a function that provides the prompt of `read_line` anew for
+    /// every render, such as a prompt showing the elapsed time, in place of
+    /// the prompt passed to `read_line`.
+    ///
+    /// A `LineEditor` replaces its prompt by `refresh_prompt` instead.
+    pub fn set_prompt_fn<F: FnMut() -> String + Send + 'static>(&mut self, f: F) {
+        self.prompt_fn = Some(Box::new(f));
+    }
+
+    /// Sets a function that is called with input that `read_line` or
+    /// `feed` drops because it cannot be parsed, such as an escape sequence
+    /// of an unsupported key.
+    pub fn set_on_parse_error<F: FnMut(&[u8]) + Send + 'static>(&mut self, f: F) {
+        self.on_parse_error = Some(Box::new(f));
+    }
+
+    fn notify_accepted(&mut self, res: &Result<String, Error>) {
+        if let (&Ok(ref line), Some(ref mut f)) = (res, self.on_line_accepted.as_mut()) {
+            f(line);
         }
     }
 
     /// Reads a line from the input using the specified prompt and config.
     pub fn read_line(&mut self, prompt: &str, cfg: &Config) -> Result<String, Error> {
-        let enc = enc::to_encoding_ref(&cfg.encoding);
-        if Term::is_unsupported_term() || !self.term.is_a_tty() {
+        self.read_line_with_initial(prompt, cfg, "")
+    }
+
+    /// Reads a line like `read_line`, starting with the initial text in
+    /// the line and the cursor after it, such as a default answer or a
+    /// command to edit.
+    ///
+    /// A line read without editing by `Config::fallback_to_plain` does
+    /// not include the initial text.
+    pub fn read_line_with_initial(&mut self, prompt: &str, cfg: &Config, initial: &str) -> Result<String, Error> {
+        if cfg.fallback_to_plain && !(self.term.is_a_tty() && self.term.is_input_a_tty()) {
+            let res = self.read_plain_line(cfg);
+            self.notify_accepted(&res);
+            return res;
+        }
+        if Term::is_unsupported_term(cfg.term_support) || !self.term.is_a_tty() {
             return Err(Error::UnsupportedTerm);
         }
-        let mut io = try!(self.term.acquire_io());
-        let ctx = EditCtx::new(prompt, &self.history, enc, cfg.mode);
-        let res = run::run(ctx, &mut io);
-        drop(io);
+        let width = self.term.width();
+        let mut term = self.term.clone();
+        let mut io = try!(term.acquire_io());
+        let res = self.run_with_io(prompt, cfg, initial, width, &mut io);
+        let restored = io.restore();
+        println!("");
+        let res = res.and_then(|line| restored.map(|_| line));
+        self.notify_accepted(&res);
+        res
+    }
+
+    /// Reads a secret such as a password without showing what is typed.
+    ///
+    /// The line is neither taken from nor passed to the history, and the
+    /// function set by `set_on_line_accepted` is not called with it. Use
+    /// `read_line` with `Config::echo` for other settings.
+    pub fn read_password(&mut self, prompt: &str) -> Result<String, Error> {
+        let cfg = Config { echo: EchoMode::None, ..Config::default() };
+        if Term::is_unsupported_term(cfg.term_support) || !self.term.is_a_tty() {
+            return Err(Error::UnsupportedTerm);
+        }
+        let history = History::new();
+        let mut ctx = EditCtx::new(prompt, &history, &cfg);
+        ctx.set_width(self.term.width());
+        let mut term = self.term.clone();
+        let mut io = try!(term.acquire_io());
+        let res = run::run(&mut ctx, &mut io);
+        let restored = io.restore();
         println!("");
+        res.and_then(|line| restored.map(|_| line))
+    }
+
+    /// Reads a line like `read_line`, reading input from and writing
+    /// output to `io` instead of the terminal, such as to replay a scripted
+    /// session and capture what is rendered.
+    ///
+    /// Unless the config disables them, the output starts with the query
+    /// for the cursor position, which has to be answered by the input.
+    ///
+    /// ```
+    /// use copperline::{BufferedIO, Config, Copperline, PartialLineHandling};
+    ///
+    /// let cfg = Config::builder().partial_line(PartialLineHandling::Ignore).build();
+    /// let mut io = BufferedIO::new(&b"hello\r"[..], vec![]);
+    /// let mut cl = Copperline::new();
+    /// assert_eq!(cl.read_line_with_io("> ", &cfg, &mut io), Ok("hello".to_owned()));
+    /// ```
+    pub fn read_line_with_io(&mut self, prompt: &str, cfg: &Config, io: &mut RunIO) -> Result<String, Error> {
+        let res = self.run_with_io(prompt, cfg, "", None, io);
+        let res = res.and_then(|line| io.write(b"\r\n".to_vec()).and_then(|_| io.flush()).map(|_| line));
+        self.notify_accepted(&res);
+        res
+    }
+
+    fn run_with_io(&mut self, prompt: &str, cfg: &Config, initial: &str, width: Option<usize>,
+                   io: &mut RunIO) -> Result<String, Error> {
+        let (res, accepted_by, input_ended) = {
+            let mut ctx = EditCtx::new(prompt, &self.history, cfg);
+            ctx.insert(initial);
+            ctx.set_width(width);
+            ctx.set_hinter(self.hinter.as_ref().map(|h| &**h as &Hinter));
+            ctx.set_validator(self.validator.as_ref().map(|v| &**v as &Validator));
+            ctx.set_prompt_fn(self.prompt_fn.as_mut().map(|f| &mut **f as &mut FnMut() -> String));
+            ctx.set_on_parse_error(self.on_parse_error.as_mut().map(|f| &mut **f as &mut FnMut(&[u8])));
+            let res = run::run(&mut ctx, io);
+            (res, ctx.accepted_by().cloned(), ctx.input_ended())
+        };
+        self.accepted_by = accepted_by;
+        self.input_ended = input_ended;
+        res
+    }
+
+    /// Reads a line up to the next newline, without editing, prompt or
+    /// escape sequences.
+    fn read_plain_line(&mut self, cfg: &Config) -> Result<String, Error> {
+        let mut bytes = vec![];
+        self.accepted_by = None;
+        self.input_ended = false;
+        loop {
+            match try!(self.term.read_byte()) {
+                Some(b'\n') => break,
+                Some(byte) => bytes.push(byte),
+                None if bytes.is_empty() => return Err(Error::EndOfFile),
+                None => {
+                    self.input_ended = true;
+                    break;
+                }
+            }
+        }
+        if bytes.last() == Some(&b'\r') {
+            bytes.pop();
+        }
+        let line = enc::to_encoding_ref(&cfg.encoding).decode(&bytes, DecoderTrap::Replace);
+        Ok(line.unwrap_or_else(|err| err.into_owned()))
+    }
+
+    /// Reads a line like `read_line`, telling apart how editing ended.
+    ///
+    /// Cancelling and ending the input by a key are not errors, while the
+    /// end of the input itself is still reported as `Error::EndOfFile`.
+    pub fn read_line_outcome(&mut self, prompt: &str, cfg: &Config) -> Result<ReadOutcome, Error> {
+        let res = self.read_line(prompt, cfg);
+        outcome(res, self.input_ended)
+    }
+
+    /// Returns a line editor that renders the specified prompt and uses the
+    /// history of this Copperline, without accessing the terminal.
+    pub fn line_editor<'a>(&'a self, prompt: &'a str, cfg: &'a Config) -> LineEditor<'a> {
+        let mut ctx = EditCtx::new(prompt, &self.history, cfg);
+        ctx.set_hinter(self.hinter.as_ref().map(|h| &**h as &Hinter));
+        ctx.set_validator(self.validator.as_ref().map(|v| &**v as &Validator));
+        LineEditor::new(ctx)
+    }
+
+    /// Edits a line with input that is read by the caller, for example
+    /// from an event loop.
+    ///
+    /// Returns the line once it is finished. Input beyond the end of the
+    /// line is kept for the next line, which can be edited by feeding
+    /// further input, or no input at all. Nothing is written to the
+    /// terminal.
+    pub fn feed(&mut self, bytes: &[u8], cfg: &Config) -> Option<Result<String, Error>> {
+        let (res, state) = {
+            let mut ctx = match self.fed.take() {
+                Some(state) => EditCtx::resume(state, "", &self.history, cfg),
+                None => EditCtx::new("", &self.history, cfg)
+            };
+            ctx.set_validator(self.validator.as_ref().map(|v| &**v as &Validator));
+            ctx.set_on_parse_error(self.on_parse_error.as_mut().map(|f| &mut **f as &mut FnMut(&[u8])));
+            ctx.fill(bytes.iter().cloned());
+            match edit::edit_pending(&mut ctx) {
+                None => (None, Some(ctx.suspend())),
+                Some(res) => {
+                    self.accepted_by = ctx.accepted_by().cloned();
+                    let rest = ctx.take_input();
+                    if rest.is_empty() {
+                        (Some(res), None)
+                    } else {
+                        let mut next = EditCtx::new("", &self.history, cfg);
+                        next.fill(rest);
+                        (Some(res), Some(next.suspend()))
+                    }
+                }
+            }
+        };
+        self.fed = state;
+        if let Some(ref res) = res {
+            self.notify_accepted(res);
+        }
         res
     }
 
+    /// Returns the key that accepted the line returned by the last call to
+    /// `read_line` or `feed`.
+    ///
+    /// This is `None` if no line was returned, or if the line was
+    /// accepted because the input ended.
+    pub fn accepted_by(&self) -> Option<&Token> {
+        self.accepted_by.as_ref()
+    }
+
     /// Reads a line from the input using the specified prompt and the default config.
     pub fn read_line_default(&mut self, prompt: &str) -> Result<String, Error> {
         self.read_line(prompt, &Config::default())
@@ -105,17 +905,58 @@ impl Copperline {
         self.history.len()
     }
 
-    /// Adds a line to the history.
-    pub fn add_history(&mut self, line: String) {
-        self.history.push(line)
+    /// Adds a line to the history, returning whether it was stored.
+    ///
+    /// Empty lines and repetitions of the newest entry are not stored.
+    pub fn add_history(&mut self, line: String) -> bool {
+        self.add_history_with_config(line, &Config::default())
+    }
+
+    /// Adds a line to the history, following the history options of the
+    /// config, and returns whether it was stored.
+    pub fn add_history_with_config(&mut self, line: String, cfg: &Config) -> bool {
+        let line = match cfg.history_filter {
+            Some(ref filter) => match filter(&line) {
+                Some(line) => line,
+                None => return false
+            },
+            None => line
+        };
+        self.history.push(line, &cfg.history)
     }
 
     /// Retrieves a line from the history by index.
+    ///
+    /// The history is ordered newest-first, so index `0` is the most
+    /// recently added line.
     pub fn get_history_item(&self, idx: usize) -> Option<&String> {
         self.history.get(idx)
     }
 
+    /// Retrieves the most recently added line from the history.
+    pub fn get_newest_history_item(&self) -> Option<&str> {
+        self.history.newest()
+    }
+
+    /// Retrieves the least recently added line from the history.
+    pub fn get_oldest_history_item(&self) -> Option<&str> {
+        self.history.oldest()
+    }
+
+    /// Iterates over the history from the newest to the oldest line.
+    pub fn history_iter(&self) -> vec_deque::Iter<String> {
+        self.history.iter()
+    }
+
+    /// Returns the indices of the history items that contain the term,
+    /// newest first, as searched by `C-r`.
+    pub fn history_search(&self, term: &str) -> Vec<usize> {
+        self.history.search(term).collect()
+    }
+
     /// Removes an item from the history by index and returns it.
+    ///
+    /// Indices follow the same newest-first order as `get_history_item`.
     pub fn remove_history_item(&mut self, idx: usize) -> Option<String> {
         self.history.remove(idx)
     }
@@ -134,3 +975,359 @@ impl Copperline {
     }
 
 }
+
+fn outcome(res: Result<String, Error>, input_ended: bool) -> Result<ReadOutcome, Error> {
+    match res {
+        Ok(line) => Ok(ReadOutcome::Line(line)),
+        Err(Error::Cancel) => Ok(ReadOutcome::Interrupted),
+        Err(Error::EndOfFile) if !input_ended => Ok(ReadOutcome::Eof),
+        Err(err) => Err(err)
+    }
+}
+
+#[test]
+fn history_filter() {
+    let mut cl = Copperline::new();
+    let cfg = Config {
+        history_filter: Some(Box::new(|line: &str| {
+            if line.starts_with("secret") {
+                None
+            } else {
+                Some(line.replace("hunter2", "***"))
+            }
+        })),
+        history: HistoryConfig { ignore_dups: true, ..HistoryConfig::default() },
+        ..Config::default()
+    };
+    assert!(cl.add_history_with_config("login hunter2".to_owned(), &cfg));
+    assert!(!cl.add_history_with_config("secret stuff".to_owned(), &cfg));
+    assert!(cl.add_history_with_config("ls".to_owned(), &cfg));
+    // the transformed line is deduplicated against the stored one
+    assert!(cl.add_history_with_config("login hunter2".to_owned(), &cfg));
+    assert!(!cl.add_history_with_config("login hunter2".to_owned(), &cfg));
+    let entries: Vec<&String> = cl.history_iter().collect();
+    assert_eq!(entries, vec!["login ***", "ls"]);
+}
+
+#[test]
+fn feed() {
+    let mut cl = Copperline::new();
+    let cfg = Config { mode: EditMode::Vi, ..Config::default() };
+    cl.add_history("foo".to_owned());
+    assert_eq!(cl.feed(b"hel", &cfg), None);
+    assert_eq!(cl.feed(b"lo\x1b", &cfg), None);
+    assert_eq!(cl.feed(b"0", &cfg), None);
+    assert_eq!(cl.feed(b"x\rwor", &cfg), Some(Ok("ello".to_owned())));
+    assert_eq!(cl.accepted_by(), Some(&Token::Enter));
+    assert_eq!(cl.feed(b"ld\r", &cfg), Some(Ok("world".to_owned())));
+    // the history position is kept between calls
+    assert_eq!(cl.feed(b"\x1b[A", &cfg), None);
+    assert_eq!(cl.feed(b"\r", &cfg), Some(Ok("foo".to_owned())));
+    // several lines fed at once are returned one by one
+    assert_eq!(cl.feed(b"a\rb\r", &cfg), Some(Ok("a".to_owned())));
+    assert_eq!(cl.feed(b"", &cfg), Some(Ok("b".to_owned())));
+    assert_eq!(cl.feed(b"", &cfg), None);
+}
+
+#[test]
+fn line_editor() {
+    let cl = Copperline::new();
+    let cfg = Config::default();
+    let mut editor = cl.line_editor("> ", &cfg);
+    assert_eq!(editor.step(b""), EditStep::Output(b"\r> \x1b[0K\r\x1b[2C".to_vec()));
+    // keys delivered at once are all handled
+    assert_eq!(editor.step(b"ab\x1b[D"), EditStep::Output(b"\r> ab\x1b[0K\r\x1b[3C".to_vec()));
+    assert_eq!(editor.step(b"\x1b["), EditStep::Output(b"\r> ab\x1b[0K\r\x1b[3C".to_vec()));
+    assert_eq!(editor.step(b"Dc\r"), EditStep::Done(Ok("cab".to_owned())));
+    let mut editor = cl.line_editor("> ", &cfg);
+    editor.step(b"ab");
+    assert_eq!(editor.finish(), Ok("ab".to_owned()));
+}
+
+#[test]
+fn line_editor_len() {
+    let cl = Copperline::new();
+    let cfg = Config::default();
+    let mut editor = cl.line_editor("> ", &cfg);
+    assert_eq!((editor.byte_len(), editor.grapheme_len()), (0, 0));
+    editor.step("a\u{e9}e\u{301}\u{1f600}".as_bytes());
+    assert_eq!((editor.byte_len(), editor.grapheme_len()), (10, 4));
+    editor.step(b"\x7f");
+    assert_eq!((editor.byte_len(), editor.grapheme_len()), (6, 3));
+}
+
+#[test]
+fn send() {
+    fn assert_send<T: Send>() {}
+    // the callbacks do not keep the editor on one thread
+    assert_send::<Copperline>();
+    assert_send::<Config>();
+}
+
+#[test]
+fn on_line_accepted() {
+    use std::sync::{Arc, Mutex};
+    let mut cl = Copperline::new();
+    let cfg = Config::default();
+    let accepted = Arc::new(Mutex::new(vec![]));
+    let sink = accepted.clone();
+    cl.set_on_line_accepted(move |line| sink.lock().unwrap().push(line.to_owned()));
+    assert_eq!(cl.feed(b"ab", &cfg), None);
+    assert_eq!(cl.feed(b"c\r", &cfg), Some(Ok("abc".to_owned())));
+    assert_eq!(cl.feed(b"d\x03", &cfg), Some(Err(Error::Cancel)));
+    assert_eq!(cl.feed(b"\x04", &cfg), Some(Err(Error::EndOfFile)));
+    assert_eq!(*accepted.lock().unwrap(), vec!["abc".to_owned()]);
+}
+
+#[test]
+fn hinter() {
+    struct HistoryHinter(Vec<&'static str>);
+    impl Hinter for HistoryHinter {
+        fn hint(&self, line: &str, pos: usize) -> Option<String> {
+            if line.is_empty() || pos < line.len() {
+                return None;
+            }
+            self.0.iter().find(|e| e.starts_with(line)).map(|e| e[line.len()..].to_owned())
+        }
+    }
+    let mut cl = Copperline::new();
+    cl.set_hinter(HistoryHinter(vec!["hello world"]));
+    let cfg = Config::default();
+    let mut editor = cl.line_editor("> ", &cfg);
+    assert_eq!(editor.step(b"hel"), EditStep::Output(b"\r> hel\x1b[0K\r\x1b[5C\r\x1b[5C\x1b[2mlo world\x1b[0m\r\x1b[5C".to_vec()));
+    // no hint while the cursor is within the line
+    assert_eq!(editor.step(b"\x02"), EditStep::Output(b"\r> hel\x1b[0K\r\x1b[4C".to_vec()));
+    assert_eq!(editor.step(b"\r"), EditStep::Done(Ok("hel".to_owned())));
+}
+
+#[test]
+fn read_outcome() {
+    let cfg = Config::default();
+    let history = History::new();
+    for (input, expected) in vec![
+        (&b"ab\r"[..], Ok(ReadOutcome::Line("ab".to_owned()))),
+        (&b"ab\x03"[..], Ok(ReadOutcome::Interrupted)),
+        (&b"\x04"[..], Ok(ReadOutcome::Eof)),
+        (&b""[..], Err(Error::EndOfFile)),
+        (&b"ab"[..], Ok(ReadOutcome::Line("ab".to_owned()))),
+    ] {
+        let mut ctx = EditCtx::new("> ", &history, &cfg);
+        let mut io = run::ByteIO::new(input);
+        let res = run::run_edit(&mut ctx, &mut io);
+        assert_eq!(outcome(res, ctx.input_ended()), expected);
+    }
+}
+
+#[test]
+fn refresh_prompt() {
+    let cl = Copperline::new();
+    let cfg = Config::default();
+    let mut editor = cl.line_editor("> ", &cfg);
+    assert_eq!(editor.step(b"ab"), EditStep::Output(b"\r> ab\x1b[0K\r\x1b[4C".to_vec()));
+    assert_eq!(editor.refresh_prompt("[12:01]> "), b"\r[12:01]> ab\x1b[0K\r\x1b[11C".to_vec());
+    assert_eq!(editor.step(b"c"), EditStep::Output(b"\r[12:01]> abc\x1b[0K\r\x1b[12C".to_vec()));
+    assert_eq!(editor.step(b"\r"), EditStep::Done(Ok("abc".to_owned())));
+}
+
+#[test]
+fn cursor_col() {
+    let cl = Copperline::new();
+    let cfg = Config::default();
+    let mut editor = cl.line_editor("> ", &cfg);
+    assert_eq!(editor.cursor_col(), 2);
+    editor.step(b"abc\x02");
+    assert_eq!(editor.cursor_col(), 4);
+    // wide characters take up two columns
+    let mut editor = cl.line_editor("> ", &cfg);
+    editor.step("中文x\x02".as_bytes());
+    assert_eq!(editor.cursor_col(), 6);
+    // colors in the prompt take up no space
+    let mut editor = cl.line_editor("\x1b[1m>\x1b[0m ", &cfg);
+    editor.step(b"ab");
+    assert_eq!(editor.cursor_col(), 4);
+    // a long line is scrolled to keep the cursor on the terminal
+    let cfg = Config::builder().horizontal_scroll(true).build();
+    let mut editor = cl.line_editor("> ", &cfg);
+    editor.set_width(10);
+    editor.step(b"abcdefghijkl");
+    assert_eq!(editor.cursor_col(), 9);
+    // a masked character takes up one column, however wide it is
+    let cfg = Config::builder().echo(EchoMode::Mask('*')).build();
+    let mut editor = cl.line_editor("> ", &cfg);
+    editor.step("中文x\x02".as_bytes());
+    assert_eq!(editor.cursor_col(), 4);
+    // and text that is not echoed leaves the cursor after the prompt
+    let cfg = Config::builder().echo(EchoMode::None).build();
+    let mut editor = cl.line_editor("> ", &cfg);
+    editor.step("中文x\x02".as_bytes());
+    assert_eq!(editor.cursor_col(), 2);
+}
+
+#[test]
+fn fallback_to_plain() {
+    use std::fs::{self, File};
+    use std::io::Write;
+    let path = std::env::temp_dir().join(format!("copperline-plain-{}", std::process::id()));
+    File::create(&path).unwrap().write_all(b"first\r\n\x1b[Dsecond").unwrap();
+    let input = File::open(&path).unwrap();
+    let output = File::create("/dev/null").unwrap();
+    let mut cl = Copperline::new_from_io(&input, &output);
+    let cfg = Config::default();
+    assert_eq!(cl.read_line("> ", &cfg), Err(Error::UnsupportedTerm));
+    let cfg = Config::builder().fallback_to_plain(true).build();
+    assert_eq!(cl.read_line("> ", &cfg), Ok("first".to_owned()));
+    // escape sequences are not interpreted
+    assert_eq!(cl.read_line_outcome("> ", &cfg), Ok(ReadOutcome::Line("\x1b[Dsecond".to_owned())));
+    assert_eq!(cl.read_line_outcome("> ", &cfg), Ok(ReadOutcome::Eof));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn prompt_on_separate_line() {
+    let cl = Copperline::new();
+    let cfg = Config::builder().prompt_on_separate_line(true).build();
+    let mut editor = cl.line_editor("~/src (main)", &cfg);
+    // the prompt is shown once, and the input starts the next line
+    assert_eq!(editor.step(b"ab"), EditStep::Output(b"~/src (main)\r\n\rab\x1b[0K\r\x1b[2C".to_vec()));
+    assert_eq!(editor.cursor_col(), 2);
+    assert_eq!(editor.step(b"c"), EditStep::Output(b"\rabc\x1b[0K\r\x1b[3C".to_vec()));
+    assert_eq!(editor.step(b"\r"), EditStep::Done(Ok("abc".to_owned())));
+}
+
+#[test]
+fn read_line_with_io() {
+    let mut cl = Copperline::new();
+    let cfg = Config::builder().bracketed_paste(false).build();
+    // the cursor position is queried first
+    let mut io = BufferedIO::new(&b"\x1b[1;1Rab\x02c\r"[..], vec![]);
+    assert_eq!(cl.read_line_with_io("> ", &cfg, &mut io), Ok("acb".to_owned()));
+    let (_, output) = io.into_inner();
+    // the input that is read at once is handled before rendering again
+    assert_eq!(output, b"\x1b[6n\r> \x1b[0K\r\x1b[2C\r\n".to_vec());
+    assert_eq!(cl.accepted_by(), Some(&Token::Enter));
+    let mut io = BufferedIO::new(&b"\x1b[1;1R"[..], vec![]);
+    assert_eq!(cl.read_line_with_io("> ", &cfg, &mut io), Err(Error::EndOfFile));
+}
+
+#[test]
+fn validator() {
+    struct Parens;
+    impl Validator for Parens {
+        fn is_complete(&self, input: &str) -> bool {
+            input.matches('(').count() <= input.matches(')').count()
+        }
+    }
+    let mut cl = Copperline::new();
+    cl.set_validator(Parens);
+    let cfg = Config::default();
+    assert_eq!(cl.feed(b"(foo\r", &cfg), None);
+    assert_eq!(cl.feed(b" bar\r)\r", &cfg), Some(Ok("(foo\n bar\n)".to_owned())));
+    assert_eq!(cl.feed(b"baz\r", &cfg), Some(Ok("baz".to_owned())));
+    // the end of the input finishes incomplete input as it is
+    let mut io = BufferedIO::new(&b"\x1b[1;1R(foo\rbar"[..], vec![]);
+    assert_eq!(cl.read_line_with_io("> ", &cfg, &mut io), Ok("(foo\nbar".to_owned()));
+    let mut io = BufferedIO::new(&b"\x1b[1;1R(foo\r"[..], vec![]);
+    assert_eq!(cl.read_line_with_io("> ", &cfg, &mut io), Ok("(foo".to_owned()));
+    // and so does C-d on an empty line
+    let mut io = BufferedIO::new(&b"\x1b[1;1R(foo\r\x04"[..], vec![]);
+    assert_eq!(cl.read_line_with_io("> ", &cfg, &mut io), Ok("(foo".to_owned()));
+}
+
+#[test]
+fn line_numbers() {
+    struct Backslash;
+    impl Validator for Backslash {
+        fn is_complete(&self, input: &str) -> bool {
+            !input.ends_with('\\')
+        }
+    }
+    let mut cl = Copperline::new();
+    cl.set_validator(Backslash);
+    let cfg = Config::builder().continuation_prompt("> ").show_line_numbers(true).build();
+    let mut editor = cl.line_editor("$ ", &cfg);
+    let mut output = vec![];
+    for input in &["one \\", "\r", "two \\", "\r", "three"] {
+        match editor.step(input.as_bytes()) {
+            EditStep::Output(bytes) => output.extend(bytes),
+            step => panic!("unexpected {:?}", step)
+        }
+    }
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("\r$  1 one \\"));
+    assert!(output.contains("\r>  2 two \\"));
+    assert!(output.ends_with("\r>  3 three\x1b[0K\r\x1b[10C"));
+    // the gutter counts towards the column of the cursor
+    assert_eq!(editor.cursor_col(), 10);
+    assert_eq!(editor.step(b"\r"), EditStep::Done(Ok("one \\\ntwo \\\nthree".to_owned())));
+}
+
+#[test]
+fn history_search() {
+    let mut cl = Copperline::new();
+    cl.add_history("echo one".to_owned());
+    cl.add_history("ls".to_owned());
+    cl.add_history("echo two".to_owned());
+    assert_eq!(cl.history_search("echo"), vec![0, 2]);
+    assert_eq!(cl.get_history_item(2).map(|s| s.as_str()), Some("echo one"));
+    assert_eq!(cl.history_search("cd"), Vec::<usize>::new());
+}
+
+#[test]
+fn on_parse_error() {
+    use std::sync::{Arc, Mutex};
+    let mut cl = Copperline::new();
+    let cfg = Config::default();
+    let dropped = Arc::new(Mutex::new(vec![]));
+    let sink = dropped.clone();
+    cl.set_on_parse_error(move |bytes| sink.lock().unwrap().push(bytes.to_vec()));
+    assert_eq!(cl.feed(b"a\x1b[5~b", &cfg), None);
+    // only the bytes up to the unknown final byte are dropped
+    assert_eq!(cl.feed(b"\x1b[Zc\r", &cfg), Some(Ok("abZc".to_owned())));
+    assert_eq!(*dropped.lock().unwrap(), vec![b"\x1b[5~".to_vec(), b"\x1b[".to_vec()]);
+}
+
+#[test]
+#[cfg(all(feature = "completion", feature = "hints", feature = "validation", feature = "history-search",
+          feature = "vi-mode", feature = "vi-text-objects", feature = "bracketed-paste"))]
+fn default_features() {
+    assert_eq!(Copperline::features(), FeatureSet {
+        version: env!("CARGO_PKG_VERSION"),
+        completion: true,
+        hints: true,
+        validation: true,
+        history_search: true,
+        vi_mode: true,
+        vi_text_objects: true,
+        bracketed_paste: true
+    });
+}
+
+#[test]
+fn config_builder() {
+    let cfg = Config::builder()
+        .mode(EditMode::Vi)
+        .bell(false)
+        .cancel_marker("^X")
+        .paste_warn_threshold(100)
+        .build();
+    assert!(!cfg.bell);
+    assert_eq!(cfg.cancel_marker, "^X");
+    assert_eq!(cfg.paste_warn_threshold, Some(100));
+    let cfg = Config::builder()
+        .bind(Token::CtrlT, Instr::MoveCursor(MoveCursorInstr::End))
+        .build();
+    assert_eq!(simulate(b"ab\x01x\x14y\r", "> ", &cfg), Ok("xaby".to_owned()));
+    // the defaults are kept otherwise
+    assert_eq!(cfg.kill_ring_size, Config::default().kill_ring_size);
+}
+
+#[test]
+fn protect_newline() {
+    let cfg = Config::builder().protect_newline(false).build();
+    assert_eq!(cfg.partial_line, PartialLineHandling::Ignore);
+    let cfg = Config::builder().protect_newline(false).protect_newline(true).build();
+    assert_eq!(cfg.partial_line, PartialLineHandling::Marker);
+    // the chosen handling is kept
+    let cfg = Config::builder().partial_line(PartialLineHandling::Newline).protect_newline(true).build();
+    assert_eq!(cfg.partial_line, PartialLineHandling::Newline);
+}
@@ -1,8 +1,9 @@
 use std::clone::Clone;
+use std::cmp;
 
 use encoding::types::{EncodingRef, RawDecoder};
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     Null,
     CtrlA,
@@ -32,6 +33,10 @@ pub enum Token {
     CtrlY,
     CtrlZ,
     Esc,
+    CtrlBackslash,
+    CtrlRightBracket,
+    CtrlCaret,
+    CtrlUnderscore,
     Backspace,
     EscBracket3T,
     EscBracketA,
@@ -40,9 +45,31 @@ pub enum Token {
     EscBracketD,
     EscBracketH,
     EscBracketF,
+    /// One of the keys above that ends in a letter, such as an arrow key,
+    /// pressed together with modifiers, as in `ESC [ 1 ; 5 C` for `C-Right`.
+    EscBracketModified(Modifiers, char),
+    Meta(char),
+    Paste(String),
     Text(String)
 }
 
+/// The modifier keys held down together with a key.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub alt: bool,
+    pub ctrl: bool
+}
+
+impl Modifiers {
+    /// Decode the modifier parameter of xterm, which is one more than the
+    /// sum of 1 for shift, 2 for alt and 4 for ctrl.
+    fn from_param(param: u64) -> Modifiers {
+        let bits = param.saturating_sub(1);
+        Modifiers { shift: bits & 1 != 0, alt: bits & 2 != 0, ctrl: bits & 4 != 0 }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ParseError {
     Error(usize),
@@ -103,6 +130,10 @@ fn match_head(i: u8) -> Option<Token> {
         25  => Some(Token::CtrlY),
         26  => Some(Token::CtrlZ),
         27  => Some(Token::Esc),
+        28  => Some(Token::CtrlBackslash),
+        29  => Some(Token::CtrlRightBracket),
+        30  => Some(Token::CtrlCaret),
+        31  => Some(Token::CtrlUnderscore),
         127 => Some(Token::Backspace),
         _   => None
     }
@@ -124,7 +155,13 @@ fn parse_esc_bracket(vec: &[u8]) -> ParseResult<Token> {
         let d = try!(parse_char(vec, 3)).0 as char;
         match (c, d) {
             ('3', '~') => Ok(ParseSuccess(Token::EscBracket3T, 4)),
-            _ => Err(ParseError::Error(4))
+            ('1', ';') => parse_modified_key(vec),
+            // drop a cursor position report that arrived too late
+            _ => match parse_cursor_pos(vec) {
+                Ok(ParseSuccess(_, len)) => Err(ParseError::Error(len)),
+                Err(ParseError::Incomplete) => Err(ParseError::Incomplete),
+                Err(ParseError::Error(_)) => Err(ParseError::Error(4))
+            }
         }
     } else {
         match c {
@@ -139,28 +176,76 @@ fn parse_esc_bracket(vec: &[u8]) -> ParseResult<Token> {
     }
 }
 
+/// Parse the rest of `ESC [ 1 ; <modifiers> <letter>`.
+fn parse_modified_key(vec: &[u8]) -> ParseResult<Token> {
+    let (param, len) = parse_number(vec, 4);
+    let off = 4 + len;
+    let key = try!(parse_char(vec, off)).0 as char;
+    match key {
+        'A' | 'B' | 'C' | 'D' | 'F' | 'H' if len > 0 => {
+            Ok(ParseSuccess(Token::EscBracketModified(Modifiers::from_param(param), key), off + 1))
+        }
+        _ => Err(ParseError::Error(off + 1))
+    }
+}
+
 fn parse_esc(vec: &[u8]) -> ParseResult<Token> {
     let c = try!(parse_char(vec, 1)).0 as char;
     if c == '[' {
         parse_esc_bracket(vec)
     } else if c == '0' {
         Err(ParseError::Error(2)) // TODO: implement
+    } else if c.is_ascii() && !c.is_control() {
+        Ok(ParseSuccess(Token::Meta(c), 2))
     } else {
         Err(ParseError::Error(2))
     }
 }
 
+pub const PASTE_START: &'static [u8] = b"\x1b[200~";
+pub const PASTE_END: &'static [u8] = b"\x1b[201~";
+
+/// Parse text pasted while bracketed paste mode is enabled.
+fn parse_paste(vec: &[u8], enc: EncodingRef) -> ParseResult<Token> {
+    let body = &vec[PASTE_START.len()..];
+    let end = match body.windows(PASTE_END.len()).position(|w| w == PASTE_END) {
+        Some(end) => end,
+        None => return Err(ParseError::Incomplete)
+    };
+    let len = PASTE_START.len() + end + PASTE_END.len();
+    let mut dec = enc.raw_decoder();
+    let mut text = String::new();
+    match dec.raw_feed(&body[..end], &mut text) {
+        (_, None) => Ok(ParseSuccess(Token::Paste(text), len)),
+        (_, Some(_)) => Err(ParseError::Error(len))
+    }
+}
+
 pub fn parse(vec: &[u8], enc: EncodingRef) -> ParseResult<Token> {
     let i = try!(parse_char(vec, 0)).0;
+    if vec.starts_with(PASTE_START) {
+        return parse_paste(vec, enc);
+    } else if vec.len() > 3 && PASTE_START.starts_with(vec) {
+        return Err(ParseError::Incomplete);
+    }
     match match_head(i) {
         Some(Token::Esc) if vec.len() > 1 => parse_esc(vec),
         Some(t) => Ok(ParseSuccess(t, 1)),
         None => {
+            // text ends where the next control character starts
+            let end = vec.iter().position(|&b| match_head(b).is_some()).unwrap_or(vec.len());
             let mut dec = enc.raw_decoder();
             let mut text = String::new();
-            match dec.raw_feed(vec, &mut text) {
+            match dec.raw_feed(&vec[..end], &mut text) {
+                // the decoder holds back the start of a multibyte character
+                (0, None) if end == vec.len() => Err(ParseError::Incomplete),
+                // which can no longer be completed after a control character
+                (0, None) => Err(ParseError::Error(end)),
                 (offset, None) => Ok(ParseSuccess(Token::Text(text), offset)),
-                (offset, Some(_)) => Err(ParseError::Error(offset))
+                // the text before an invalid sequence is still valid
+                (offset, Some(_)) if offset > 0 => Ok(ParseSuccess(Token::Text(text), offset)),
+                // while only the invalid sequence itself is dropped
+                (_, Some(err)) => Err(ParseError::Error(cmp::max(err.upto, 1) as usize))
             }
         }
     }
@@ -220,3 +305,90 @@ fn parse_cursor_pos_invalid() {
     let v = vec![27, 91, 48 + 4, 48 + 10, 59, 48 + 6];
     assert_eq!(parse_cursor_pos(&v), Err(ParseError::Error(4)));
 }
+
+#[test]
+fn parse_late_cursor_pos() {
+    use encoding::all::ASCII;
+    assert_eq!(parse(b"\x1b[24;80Rx", ASCII), Err(ParseError::Error(8)));
+    assert_eq!(parse(b"\x1b[24;8", ASCII), Err(ParseError::Incomplete));
+    assert_eq!(parse(b"\x1b[1;1R", ASCII), Err(ParseError::Error(6)));
+}
+
+#[test]
+fn parse_incomplete_multibyte() {
+    use encoding::all::{UTF_8, WINDOWS_31J};
+    assert_eq!(parse(b"\xc3", UTF_8), Err(ParseError::Incomplete));
+    assert_eq!(parse(b"\xe2\x82", UTF_8), Err(ParseError::Incomplete));
+    assert_eq!(parse(b"\xe2\x82\xac", UTF_8), Ok(ParseSuccess(Token::Text("\u{20ac}".to_owned()), 3)));
+    // complete characters are parsed first
+    assert_eq!(parse(b"a\xc3", UTF_8), Ok(ParseSuccess(Token::Text("a".to_owned()), 1)));
+    assert_eq!(parse(b"\xc3\x03", UTF_8), Err(ParseError::Error(1)));
+    // Shift_JIS
+    assert_eq!(parse(b"\x82", WINDOWS_31J), Err(ParseError::Incomplete));
+    assert_eq!(parse(b"\x82\xa0", WINDOWS_31J), Ok(ParseSuccess(Token::Text("\u{3042}".to_owned()), 2)));
+}
+
+#[test]
+fn parse_emoji_byte_by_byte() {
+    use encoding::all::UTF_8;
+    let bytes = "\u{1f600}".as_bytes();
+    for n in 1..bytes.len() {
+        assert_eq!(parse(&bytes[..n], UTF_8), Err(ParseError::Incomplete));
+    }
+    assert_eq!(parse(bytes, UTF_8), Ok(ParseSuccess(Token::Text("\u{1f600}".to_owned()), 4)));
+}
+
+#[test]
+fn parse_invalid_text() {
+    use encoding::all::{ASCII, UTF_8};
+    assert_eq!(parse(b"\xffa", UTF_8), Err(ParseError::Error(1)));
+    assert_eq!(parse(b"a\xffb", UTF_8), Ok(ParseSuccess(Token::Text("a".to_owned()), 1)));
+    assert_eq!(parse(b"\xc3a", UTF_8), Err(ParseError::Error(1)));
+    assert_eq!(parse(b"\xe9", ASCII), Err(ParseError::Error(1)));
+}
+
+#[test]
+fn parse_meta() {
+    use encoding::all::ASCII;
+    assert_eq!(parse(b"\x1by", ASCII), Ok(ParseSuccess(Token::Meta('y'), 2)));
+    assert_eq!(parse(b"\x1b\x01", ASCII), Err(ParseError::Error(2)));
+}
+
+#[test]
+fn parse_text_before_control() {
+    use encoding::all::ASCII;
+    assert_eq!(parse(b"ab\x1b[D", ASCII), Ok(ParseSuccess(Token::Text("ab".to_owned()), 2)));
+}
+
+#[test]
+fn parse_bracketed_paste() {
+    use encoding::all::ASCII;
+    assert_eq!(parse(b"\x1b[200~a\rb\x1b[201~c", ASCII), Ok(ParseSuccess(Token::Paste("a\rb".to_owned()), 15)));
+    assert_eq!(parse(b"\x1b[200~a\rb", ASCII), Err(ParseError::Incomplete));
+    assert_eq!(parse(b"\x1b[20", ASCII), Err(ParseError::Incomplete));
+}
+
+#[test]
+fn parse_single_byte_encodings() {
+    use encoding::all::{ISO_8859_1, WINDOWS_1252};
+    assert_eq!(parse(b"caf\xe9", ISO_8859_1), Ok(ParseSuccess(Token::Text("café".to_owned()), 4)));
+    assert_eq!(parse(b"caf\xe9", WINDOWS_1252), Ok(ParseSuccess(Token::Text("café".to_owned()), 4)));
+    // the C1 range holds printable characters in windows-1252
+    assert_eq!(parse(b"\x80 5\x0d", WINDOWS_1252), Ok(ParseSuccess(Token::Text("€ 5".to_owned()), 3)));
+}
+
+#[test]
+fn parse_modified_keys() {
+    use encoding::all::ASCII;
+    let ctrl = Modifiers { shift: false, alt: false, ctrl: true };
+    let shift = Modifiers { shift: true, alt: false, ctrl: false };
+    assert_eq!(parse(b"\x1b[1;5Cx", ASCII), Ok(ParseSuccess(Token::EscBracketModified(ctrl, 'C'), 6)));
+    assert_eq!(parse(b"\x1b[1;2D", ASCII), Ok(ParseSuccess(Token::EscBracketModified(shift, 'D'), 6)));
+    let all = Modifiers { shift: true, alt: true, ctrl: true };
+    assert_eq!(parse(b"\x1b[1;8H", ASCII), Ok(ParseSuccess(Token::EscBracketModified(all, 'H'), 6)));
+    assert_eq!(parse(b"\x1b[1;5", ASCII), Err(ParseError::Incomplete));
+    assert_eq!(parse(b"\x1b[1;", ASCII), Err(ParseError::Incomplete));
+    // the whole unknown sequence is dropped
+    assert_eq!(parse(b"\x1b[1;5Zx", ASCII), Err(ParseError::Error(6)));
+    assert_eq!(parse(b"\x1b[1;Cx", ASCII), Err(ParseError::Error(5)));
+}
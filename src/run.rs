@@ -1,80 +1,399 @@
+use std::io::{Read, Write};
+
 use error::Error;
-use edit::{EditCtx, EditResult, edit};
+use edit::{EditCtx, EditResult, edit, edit_eof};
 use builder::Builder;
 use parser::{parse_cursor_pos, ParseError, ParseSuccess};
 
+/// The input and output of the line editor, usually the terminal.
 pub trait RunIO {
 
+    /// Writes output, which may be buffered until `flush` is called.
     fn write(&mut self, Vec<u8>) -> Result<(), Error>;
+    /// Reads a single byte of input, such as the reply to a query.
     fn read_byte(&mut self) -> Result<u8, Error>;
+    /// Reads a single byte like `read_byte`, giving up with `None` if none
+    /// arrives within the timeout in milliseconds.
+    ///
+    /// This is used for replies that the terminal may never send, so an
+    /// implementation that cannot wait for a limited time should return
+    /// `None` rather than block. By default it reads with `read_byte`.
+    fn read_byte_timeout(&mut self, _timeout_ms: u32) -> Result<Option<u8>, Error> {
+        self.read_byte().map(Some)
+    }
+    /// Reads all input that is available, waiting for at least one byte.
+    /// `Error::EndOfFile` signals the end of the input.
     fn read_seq(&mut self) -> Result<Vec<u8>, Error>;
 
+    /// Writes out the output that was buffered by `write`.
+    ///
+    /// Output is flushed before waiting for input and once the line is
+    /// finished, so an implementation that writes directly does not need
+    /// to do anything.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
     fn prompt(&mut self, w: Vec<u8>) -> Result<Vec<u8>, Error> {
         try!(self.write(w));
+        try!(self.flush());
         self.read_seq()
     }
 
+    /// Suspends the process, returning once it is resumed.
+    fn suspend(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Lets the user edit the line in an external editor, returning the
+    /// edited text, or `None` to keep the line as it is.
+    fn edit_externally(&mut self, _line: &str) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
 }
 
-fn query_cursor_pos(io: &mut RunIO) -> Result<(u64, u64), Error> {
+/// Delivers a fixed input one byte per read, as if it was typed, and
+/// collects everything written to it.
+pub struct ByteIO<'a> {
+    input: &'a [u8],
+    output: Vec<u8>
+}
+
+impl<'a> ByteIO<'a> {
+    pub fn new(input: &'a [u8]) -> ByteIO<'a> {
+        ByteIO { input: input, output: vec![] }
+    }
+}
+
+impl<'a> RunIO for ByteIO<'a> {
+    fn write(&mut self, w: Vec<u8>) -> Result<(), Error> {
+        self.output.extend(w);
+        Ok(())
+    }
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        match self.input.split_first() {
+            Some((&byte, rest)) => {
+                self.input = rest;
+                Ok(byte)
+            },
+            None => Err(Error::EndOfFile)
+        }
+    }
+    fn read_seq(&mut self) -> Result<Vec<u8>, Error> {
+        self.read_byte().map(|byte| vec![byte])
+    }
+}
+
+/// Reads input from any reader and writes output to any writer, for
+/// example to replay a scripted session and capture the output.
+pub struct BufferedIO<R, W> {
+    input: R,
+    output: W
+}
+
+impl<R: Read, W: Write> BufferedIO<R, W> {
+    pub fn new(input: R, output: W) -> BufferedIO<R, W> {
+        BufferedIO { input: input, output: output }
+    }
+
+    /// Returns the reader and writer, such as to inspect the output.
+    pub fn into_inner(self) -> (R, W) {
+        (self.input, self.output)
+    }
+}
+
+impl<R: Read, W: Write> RunIO for BufferedIO<R, W> {
+    fn write(&mut self, w: Vec<u8>) -> Result<(), Error> {
+        try!(self.output.write_all(&w));
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), Error> {
+        try!(self.output.flush());
+        Ok(())
+    }
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        let mut input = [0; 1];
+        match try!(self.input.read(&mut input)) {
+            0 => Err(Error::EndOfFile),
+            _ => Ok(input[0])
+        }
+    }
+    fn read_seq(&mut self) -> Result<Vec<u8>, Error> {
+        let mut input = vec![0; 1024];
+        let n = try!(self.input.read(&mut input));
+        if n == 0 {
+            return Err(Error::EndOfFile);
+        }
+        input.truncate(n);
+        Ok(input)
+    }
+}
+
+/// Asks the terminal for the cursor position, which is `None` if it did
+/// not answer within the timeout in milliseconds.
+fn query_cursor_pos(io: &mut RunIO, timeout_ms: u32) -> Result<Option<(u64, u64)>, Error> {
     let mut line = Builder::new();
     line.ask_cursor_pos();
     try!(io.write(line.build()));
+    try!(io.flush());
     let mut seq = vec![];
     loop {
         match parse_cursor_pos(&seq) {
             Ok(ParseSuccess(pos, _)) => {
-                return Ok(pos);
+                return Ok(Some(pos));
             },
             Err(ParseError::Error(_)) => {
                 return Err(Error::ParseError);
             },
             Err(ParseError::Incomplete) => {
-                let byte = try!(io.read_byte());
-                seq.push(byte);
+                match try!(io.read_byte_timeout(timeout_ms)) {
+                    Some(byte) => seq.push(byte),
+                    None => return Ok(None)
+                }
             }
         }
     }
 }
 
-fn protect_newline(io: &mut RunIO) -> Result<(), Error> {
-    let (x, _) = try!(query_cursor_pos(io));
+/// What to do when the prompt would not start at the first column,
+/// because the previous output did not end with a newline.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PartialLineHandling {
+    /// Mark the end of the partial line with an inverted `%` and move to the next line.
+    Marker,
+    /// Move to the next line.
+    Newline,
+    /// Start the prompt where the cursor is.
+    Ignore
+}
+
+fn protect_newline(io: &mut RunIO, handling: PartialLineHandling, timeout_ms: u32) -> Result<(), Error> {
+    if handling == PartialLineHandling::Ignore {
+        return Ok(());
+    }
+    // without an answer the prompt starts where the cursor is
+    let x = match try!(query_cursor_pos(io, timeout_ms)) {
+        Some((x, _)) => x,
+        None => return Ok(())
+    };
     if x > 1 {
         let mut line = Builder::new();
-        line.invert_color();
-        line.append("%\n");
-        line.reset_color();
+        if handling == PartialLineHandling::Marker {
+            line.invert_color();
+            line.append("%\n");
+            line.reset_color();
+        } else {
+            line.append("\n");
+        }
         try!(io.write(line.build()));
     }
     Ok(())
 }
 
-fn run_edit<'a>(mut ctx: EditCtx<'a>, io: &mut RunIO) -> Result<String, Error> {
-    loop {
-        match edit(&mut ctx) {
+pub fn run_edit<'a>(ctx: &mut EditCtx<'a>, io: &mut RunIO) -> Result<String, Error> {
+    // a render identical to the last one written would not change the screen
+    let mut last: Option<Vec<u8>> = None;
+    let res = loop {
+        match edit(ctx) {
+            EditResult::Cont(line) if ctx.is_busy() => {
+                if last.as_ref() != Some(&line) {
+                    last = Some(line.clone());
+                    try!(io.write(line));
+                    // the indicator has to be shown while the completer runs
+                    try!(io.flush());
+                }
+            },
             EditResult::Cont(line) => {
-                let bytes = try!(io.prompt(line));
-                ctx.fill(bytes);
+                if ctx.take_suspend_request() {
+                    try!(io.suspend());
+                    last = None;
+                }
+                if let Some(text) = ctx.take_external_edit_request() {
+                    if let Some(edited) = try!(io.edit_externally(&text)) {
+                        ctx.finish_external_edit(&edited);
+                    }
+                    // render the line as edited
+                    last = None;
+                    continue;
+                }
+                let read = if last.as_ref() == Some(&line) {
+                    io.read_seq()
+                } else {
+                    last = Some(line.clone());
+                    io.prompt(line)
+                };
+                match read {
+                    Ok(bytes) => ctx.fill(bytes),
+                    // the input ended, possibly in the middle of a sequence
+                    Err(Error::EndOfFile) => break edit_eof(ctx),
+                    Err(err) => return Err(err)
+                }
             },
-            EditResult::Halt(res) => { return res; }
+            EditResult::Halt(res) => break res
+        }
+    };
+    let mut info = ctx.clear_info();
+    info.extend(ctx.leave_rows());
+    if !info.is_empty() {
+        try!(io.write(info));
+    }
+    if let Err(Error::Cancel) = res {
+        let marker = ctx.cancel_marker();
+        if !marker.is_empty() {
+            try!(io.write(marker));
         }
     }
+    if let Ok(ref line) = res {
+        let final_line = ctx.final_line(line);
+        if !final_line.is_empty() {
+            try!(io.write(final_line));
+        }
+    }
+    let restore = ctx.restore_cursor_shape();
+    if !restore.is_empty() {
+        try!(io.write(restore));
+    }
+    try!(io.flush());
+    res
 }
 
 
-pub fn run<'a>(ctx: EditCtx<'a>, io: &mut RunIO) -> Result<String, Error> {
-    try!(protect_newline(io));
-    run_edit(ctx, io)
+/// The outcome of `LineEditor::step`.
+#[derive(Debug, PartialEq)]
+pub enum EditStep {
+    /// The line is still being edited, and the output should be written
+    /// to the terminal.
+    Output(Vec<u8>),
+    /// The line is finished.
+    Done(Result<String, Error>)
+}
+
+/// Edits a line with input that is read by the caller, rendering to
+/// output that is written by the caller.
+///
+/// Unlike `Copperline::read_line`, this does not touch the terminal, so
+/// it can be used with any kind of terminal emulation.
+pub struct LineEditor<'a> {
+    ctx: EditCtx<'a>
+}
+
+impl<'a> LineEditor<'a> {
+    pub fn new(ctx: EditCtx<'a>) -> LineEditor<'a> {
+        LineEditor { ctx: ctx }
+    }
+
+    /// Sets the number of columns of the terminal, which is needed to
+    /// scroll or wrap long lines.
+    pub fn set_width(&mut self, width: usize) {
+        self.ctx.set_width(Some(width))
+    }
+
+    /// The column of the terminal that the output places the cursor at,
+    /// counting from 0, for coordinating with other drawing.
+    pub fn cursor_col(&self) -> usize {
+        self.ctx.cursor_col()
+    }
+
+    /// The length of the line in bytes, without copying it.
+    pub fn byte_len(&self) -> usize {
+        self.ctx.byte_len()
+    }
+
+    /// The length of the line in graphemes, such as for a character
+    /// counter.
+    pub fn grapheme_len(&self) -> usize {
+        self.ctx.grapheme_len()
+    }
+
+    /// The start and end byte offsets of the word the cursor is on or
+    /// directly after, along with the word, for example for highlighting.
+    pub fn current_word(&self) -> Option<(usize, usize, &str)> {
+        self.ctx.current_word()
+    }
+
+    /// Replaces the prompt, returning the output that renders the line
+    /// with it, for example to update a clock in the prompt on a timeout.
+    pub fn refresh_prompt(&mut self, prompt: &str) -> Vec<u8> {
+        self.ctx.set_prompt(prompt.to_owned());
+        match self.step(&[]) {
+            EditStep::Output(output) => output,
+            EditStep::Done(_) => vec![]
+        }
+    }
+
+    /// Handles the input, which may be empty to render the prompt.
+    ///
+    /// Once the line is finished, the output for the remaining input is
+    /// not returned.
+    pub fn step(&mut self, input: &[u8]) -> EditStep {
+        self.ctx.fill(input.iter().cloned());
+        let mut output = vec![];
+        loop {
+            let pending = self.ctx.pending_input();
+            match edit(&mut self.ctx) {
+                EditResult::Cont(line) => output.extend(line),
+                EditResult::Halt(res) => return EditStep::Done(res)
+            }
+            // stop once the input is handled, or only an incomplete sequence is left
+            let left = self.ctx.pending_input();
+            if !self.ctx.is_busy() && (left == 0 || left == pending) {
+                break;
+            }
+        }
+        EditStep::Output(output)
+    }
+
+    /// Finishes the line once the input has ended.
+    pub fn finish(mut self) -> Result<String, Error> {
+        edit_eof(&mut self.ctx)
+    }
+
+    /// Returns the sequence that undoes changes to the terminal made by
+    /// the output, to be written once the line is finished.
+    pub fn restore(&self) -> Vec<u8> {
+        self.ctx.restore_cursor_shape()
+    }
+}
+
+pub fn run<'a>(ctx: &mut EditCtx<'a>, io: &mut RunIO) -> Result<String, Error> {
+    try!(protect_newline(io, ctx.config().partial_line, ctx.config().cursor_query_timeout));
+    if !ctx.config().bracketed_paste || !cfg!(feature = "bracketed-paste") {
+        return run_edit(ctx, io);
+    }
+    let mut line = Builder::new();
+    line.enable_bracketed_paste();
+    try!(io.write(line.build()));
+    let res = run_edit(ctx, io);
+    let mut line = Builder::new();
+    line.disable_bracketed_paste();
+    try!(io.write(line.build()));
+    try!(io.flush());
+    res
 }
 
 #[cfg(test)]
 mod test {
-    use encoding::all::ASCII;
     use super::super::error::Error;
     use super::super::edit::EditCtx;
-    use super::super::history::History;
-    use super::{RunIO, run_edit};
-    use edit::EditMode;
+    use super::super::history::{History, HistoryConfig};
+    use super::{RunIO, PartialLineHandling, protect_newline, run, run_edit};
+    use edit::{EditMode, EmptyBackspace, ControlCharPolicy, InterruptAction, EofAction, EchoMode, WrapMode};
+    use hint::Hinter;
+    use validate::Validator;
+    use enc::Encoding;
+    use completion::{Completer, Completion, NoMatchHandling};
+    use instr::{Instr, CommonInstr, Keymap, MoveCursorInstr};
+    use parser::Token;
+    use Config;
+
+    fn config(mode: EditMode) -> Config {
+        Config {
+            encoding: Encoding::Ascii,
+            mode: mode,
+            ..Config::default()
+        }
+    }
 
     pub struct TestIO {
         input: Vec<u8>,
@@ -104,28 +423,192 @@ mod test {
         }
     }
 
+    /// Delivers its input one chunk per read, the way a terminal delivers
+    /// escape sequences.
+    pub struct ChunkIO {
+        chunks: Vec<Vec<u8>>,
+        output: Vec<u8>,
+        writes: usize,
+        suspended: usize,
+        edited: Vec<String>
+    }
+
+    impl ChunkIO {
+        fn new(chunks: &[&str]) -> ChunkIO {
+            ChunkIO {
+                chunks: chunks.iter().map(|c| c.as_bytes().to_vec()).collect(),
+                output: vec![],
+                writes: 0,
+                suspended: 0,
+                edited: vec![]
+            }
+        }
+    }
+
+    impl RunIO for ChunkIO {
+        fn write(&mut self, w: Vec<u8>) -> Result<(), Error> {
+            self.writes += 1;
+            self.output.extend(w);
+            Ok(())
+        }
+        fn read_byte(&mut self) -> Result<u8, Error> {
+            if self.chunks.len() > 0 {
+                let byte = self.chunks[0].remove(0);
+                if self.chunks[0].len() == 0 {
+                    self.chunks.remove(0);
+                }
+                Ok(byte)
+            } else {
+                Err(Error::EndOfFile)
+            }
+        }
+        fn read_seq(&mut self) -> Result<Vec<u8>, Error> {
+            if self.chunks.len() > 0 {
+                Ok(self.chunks.remove(0))
+            } else {
+                Err(Error::EndOfFile)
+            }
+        }
+        fn suspend(&mut self) -> Result<(), Error> {
+            self.suspended += 1;
+            Ok(())
+        }
+        fn edit_externally(&mut self, line: &str) -> Result<Option<String>, Error> {
+            self.edited.push(line.to_owned());
+            Ok(Some(format!("{}\nsecond line\n", line.to_uppercase())))
+        }
+    }
+
     #[test]
     fn error_eof_on_empty_input() {
         let mut io = TestIO { input: vec![], output: vec![] };
         let h = History::new();
-        let ctx = EditCtx::new("foo> ", &h, ASCII, EditMode::Emacs);
-        assert_eq!(run_edit(ctx, &mut io), Err(Error::EndOfFile));
+        let cfg = config(EditMode::Emacs);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Err(Error::EndOfFile));
     }
 
     #[test]
     fn ok_empty_after_return() {
         let mut io = TestIO { input: vec![13], output: vec![] };
         let h = History::new();
-        let ctx = EditCtx::new("foo> ", &h, ASCII, EditMode::Emacs);
-        assert_eq!(run_edit(ctx, &mut io), Ok("".to_string()));
+        let cfg = config(EditMode::Emacs);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("".to_string()));
     }
 
     #[test]
     fn ok_ascii_after_return() {
         let mut io = TestIO { input: vec![65, 66, 67, 13], output: vec![] };
         let h = History::new();
-        let ctx = EditCtx::new("foo> ", &h, ASCII, EditMode::Emacs);
-        assert_eq!(run_edit(ctx, &mut io), Ok("ABC".to_string()));
+        let cfg = config(EditMode::Emacs);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ABC".to_string()));
+    }
+
+    /// A terminal that never answers queries.
+    struct SilentIO {
+        output: Vec<u8>,
+        timeouts: Vec<u32>
+    }
+
+    impl RunIO for SilentIO {
+        fn write(&mut self, w: Vec<u8>) -> Result<(), Error> {
+            self.output.extend(w);
+            Ok(())
+        }
+        fn read_byte(&mut self) -> Result<u8, Error> {
+            panic!("blocked waiting for input")
+        }
+        fn read_byte_timeout(&mut self, timeout_ms: u32) -> Result<Option<u8>, Error> {
+            self.timeouts.push(timeout_ms);
+            Ok(None)
+        }
+        fn read_seq(&mut self) -> Result<Vec<u8>, Error> {
+            Err(Error::EndOfFile)
+        }
+    }
+
+    #[test]
+    fn partial_line_handling() {
+        let cases = [
+            (PartialLineHandling::Marker, "\x1b[6n\x1b[7m%\n\x1b[0m"),
+            (PartialLineHandling::Newline, "\x1b[6n\n"),
+            (PartialLineHandling::Ignore, ""),
+        ];
+        for &(handling, expected) in &cases {
+            let mut io = TestIO { input: b"\x1b[1;5R".to_vec(), output: vec![] };
+            assert_eq!(protect_newline(&mut io, handling, 100), Ok(()));
+            assert_eq!(String::from_utf8(io.output).unwrap(), expected);
+        }
+        // nothing is emitted at the first column
+        for &handling in &[PartialLineHandling::Marker, PartialLineHandling::Newline] {
+            let mut io = TestIO { input: b"\x1b[1;1R".to_vec(), output: vec![] };
+            assert_eq!(protect_newline(&mut io, handling, 100), Ok(()));
+            assert_eq!(String::from_utf8(io.output).unwrap(), "\x1b[6n");
+        }
+        // nothing is emitted if the terminal does not answer
+        for &handling in &[PartialLineHandling::Marker, PartialLineHandling::Newline] {
+            let mut io = SilentIO { output: vec![], timeouts: vec![] };
+            assert_eq!(protect_newline(&mut io, handling, 100), Ok(()));
+            assert_eq!(String::from_utf8(io.output).unwrap(), "\x1b[6n");
+        }
+    }
+
+    #[test]
+    fn multi_line_prompt() {
+        let mut io = TestIO { input: b"ab\x0c\x0d".to_vec(), output: vec![] };
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        let mut ctx = EditCtx::new("user@host\n$ ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab".to_string()));
+        let output = String::from_utf8(io.output).unwrap();
+        // the first line is shown initially and after clearing the screen
+        assert!(output.starts_with("user@host\r\n\r$ \x1b[0K\r\x1b[2C"));
+        assert_eq!(output.matches("user@host").count(), 2);
+        assert!(output.contains("\x1b[H\x1b[2Juser@host\r\n\r$ ab\x1b[0K\r\x1b[4C"));
+    }
+
+    #[test]
+    fn coalesce_key_repeat() {
+        let burst = "\x1b[D".repeat(10);
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        let mut io = ChunkIO::new(&["abc", &burst, "X", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("Xabc".to_string()));
+        let output = String::from_utf8(io.output).unwrap();
+        // before any input, after "abc", after the burst and after "X"
+        assert_eq!(output.matches("foo> ").count(), 4);
+        assert!(output.contains("foo> abc\x1b[0K\r\x1b[5C"));
+    }
+
+    #[test]
+    fn eof_after_lone_escape() {
+        let mut io = TestIO { input: vec![27], output: vec![] };
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Err(Error::EndOfFile));
+        let cfg = config(EditMode::Vi);
+        let mut io = TestIO { input: vec![97, 98, 27], output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab".to_string()));
+    }
+
+    #[test]
+    fn eof_mid_sequence_keeps_buffer() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        for seq in &["\x1b[", "\x1b[3"] {
+            let mut io = ChunkIO::new(&["AB", seq]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("AB".to_string()));
+        }
+        // complete tokens are still handled
+        let mut io = ChunkIO::new(&["AB", "\x1b[DC\x1b["]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ACB".to_string()));
     }
 
     /// Make sure integers don't overflow in vi mode when using large command counts.
@@ -138,8 +621,9 @@ mod test {
         input_vec.push(13);
         let mut io = TestIO { input: input_vec, output: vec![] };
         let h = History::new();
-        let ctx = EditCtx::new("foo> ", &h, ASCII, EditMode::Vi);
-        assert_eq!(run_edit(ctx, &mut io), Ok("".to_string()));
+        let cfg = config(EditMode::Vi);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("".to_string()));
     }
 
     macro_rules! vi_cmd_vec {
@@ -155,11 +639,73 @@ mod test {
         ($cmd_str:expr, $result:expr) => {{
             let mut io = TestIO { input: vi_cmd_vec!($cmd_str), output: vec![] };
             let h = History::new();
-            let ctx = EditCtx::new("foo> ", &h, ASCII, EditMode::Vi);
-            assert_eq!(run_edit(ctx, &mut io), Ok($result.to_owned()));
+            let cfg = config(EditMode::Vi);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok($result.to_owned()));
         }}
     }
 
+    macro_rules! test_emacs_cmds {
+        ($cmd_str:expr, $result:expr) => {{
+            let mut io = TestIO { input: vi_cmd_vec!($cmd_str), output: vec![] };
+            let h = History::new();
+            let cfg = config(EditMode::Emacs);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok($result.to_owned()));
+        }}
+    }
+
+    #[test]
+    fn emacs_undo() {
+        // consecutive insertions are undone as a single unit
+        test_emacs_cmds!("abc\x1f\x0d", "");
+        test_emacs_cmds!("ab\x02c\x1f\x0d", "ab");
+        test_emacs_cmds!("ab\x02c\x1f\x1f\x0d", "");
+        test_emacs_cmds!("abc\x08\x1f\x0d", "abc");
+        test_emacs_cmds!("abc\x08\x18\x15\x0d", "abc");
+        // undo with nothing to undo is a no-op
+        test_emacs_cmds!("\x1fabc\x0d", "abc");
+    }
+
+    #[test]
+    fn emacs_kill_and_yank() {
+        test_emacs_cmds!("foo bar\x17\x19\x19\x0d", "foo barbar");
+        test_emacs_cmds!("foo bar\x01\x0b\x19\x19\x0d", "foo barfoo bar");
+        test_emacs_cmds!("foo bar\x02\x02\x15baz\x05\x19\x0d", "bazarfoo b");
+        test_emacs_cmds!("foo bar\x02\x02\x0b\x0d", "foo b");
+        // C-k at the end of the line leaves the kill ring alone
+        test_emacs_cmds!("ab\x15cd\x0b\x19\x0d", "cdab");
+    }
+
+    #[test]
+    fn emacs_yank_pop() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        let mut io = ChunkIO::new(&["a", "\x15", "b", "\x15", "c", "\x15",
+                                    "\x19", "\x1by", "\x1by", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("a".to_owned()));
+        // rotating is only possible directly after yanking
+        let mut io = ChunkIO::new(&["a", "\x15", "b", "\x15", "\x19", "\x02", "\x1by", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("b".to_owned()));
+    }
+
+    #[test]
+    fn emacs_kill_ring_size() {
+        let h = History::new();
+        let cfg = Config { kill_ring_size: 2, ..config(EditMode::Emacs) };
+        let mut io = ChunkIO::new(&["a", "\x15", "b", "\x15", "c", "\x15",
+                                    "\x19", "\x1by", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("b".to_owned()));
+        // the oldest kill has been dropped, so rotating wraps around
+        let mut io = ChunkIO::new(&["a", "\x15", "b", "\x15", "c", "\x15",
+                                    "\x19", "\x1by", "\x1by", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("c".to_owned()));
+    }
+
     /// Test some vi commands.
     #[test]
     fn vi_commands() {
@@ -192,4 +738,1259 @@ mod test {
         test_vi_cmds!("delete everything\x1bccchange everything\x0d", "change everything");
         test_vi_cmds!("this is a test\x1bbc2T some \x0d", "this is some test");
     }
+
+    /// Test repeating the last change with `.`.
+    #[test]
+    fn vi_repeat_last_change() {
+        test_vi_cmds!("abcdef\x1b0x..\x0d", "def");
+        test_vi_cmds!("abcdef\x1b02x.\x0d", "ef");
+        test_vi_cmds!("abcdef\x1b0x3.\x0d", "ef");
+        test_vi_cmds!("one two three\x1b0dw.\x0d", "three");
+        test_vi_cmds!("one two three\x1b0cwfoo\x1bw.\x0d", "foo foo three");
+        test_vi_cmds!("one two three\x1b0cwfoo\x1bw2.\x0d", "foo foo");
+        test_vi_cmds!("ab\x1bix\x1b.\x0d", "axxb");
+        test_vi_cmds!("a-b-c\x1b0f-r+f-.\x0d", "a+b+c");
+        // movements do not replace the last change
+        test_vi_cmds!("abcdef\x1b0xl.\x0d", "bdef");
+        // nothing to repeat
+        test_vi_cmds!("abc\x1b.\x0d", "abc");
+    }
+
+    #[test]
+    fn vi_cursor_shape() {
+        use builder::CursorShape;
+        let h = History::new();
+        let cfg = Config {
+            vi_normal_cursor: Some(CursorShape::Block),
+            vi_insert_cursor: Some(CursorShape::Bar),
+            ..config(EditMode::Vi)
+        };
+        let mut io = TestIO { input: vi_cmd_vec!("a\x1b\x0d"), output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("a".to_owned()));
+        let output = String::from_utf8(io.output).unwrap();
+        let insert = output.find("\x1b[6 q").unwrap();
+        let normal = output.find("\x1b[2 q").unwrap();
+        assert!(insert < normal);
+        assert_eq!(output.matches("\x1b[6 q").count(), 1);
+        // the default shape is restored on exit
+        assert!(output.ends_with("\x1b[0 q"));
+    }
+
+    #[test]
+    fn emacs_cursor_shape() {
+        use builder::CursorShape;
+        let h = History::new();
+        let cfg = Config {
+            vi_normal_cursor: Some(CursorShape::Block),
+            ..config(EditMode::Emacs)
+        };
+        let mut io = TestIO { input: vi_cmd_vec!("a\x0d"), output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("a".to_owned()));
+        assert!(!String::from_utf8(io.output).unwrap().contains(" q"));
+    }
+
+    #[test]
+    fn vi_toggle_case() {
+        test_vi_cmds!("foo Bar\x1b0~\x0d", "Foo Bar");
+        test_vi_cmds!("foo Bar\x1b03~\x0d", "FOO Bar");
+        test_vi_cmds!("foo Bar\x1b04~x\x0d", "FOO ar");
+        // a count past the end of the line stops there
+        test_vi_cmds!("foo Bar\x1b0w9~x\x0d", "foo bA");
+    }
+
+    #[test]
+    fn vi_repeat_char_move() {
+        test_vi_cmds!("a.b.c.d\x1b0f.;x\x0d", "a.bc.d");
+        test_vi_cmds!("a.b.c.d\x1b0f.;;,x\x0d", "a.bc.d");
+        test_vi_cmds!("a.b.c.d\x1b$F.;x\x0d", "a.bc.d");
+        test_vi_cmds!("a.b.c.d\x1b0f.2;x\x0d", "a.b.cd");
+        test_vi_cmds!("a.b.c\x1b0f.d;\x0d", "ac");
+        test_vi_cmds!("a.b.c.d\x1b0f.;d,\x0d", "a.c.d");
+        test_vi_cmds!("a.b.c.d.e\x1b0f.d;.\x0d", "ad.e");
+        // without a previous movement, the operator is cancelled
+        test_vi_cmds!("abc\x1b0d;x\x0d", "bc");
+    }
+
+    #[test]
+    fn vi_visual_mode() {
+        test_vi_cmds!("foo bar baz\x1b0wvld\x0d", "foo r baz");
+        test_vi_cmds!("foo bar baz\x1b0wvecxyz\x1b\x0d", "foo xyz baz");
+        test_vi_cmds!("foo bar\x1b0vey$p\x0d", "foo barfoo");
+        test_vi_cmds!("foo bar\x1bvbd\x0d", "foo ");
+        test_vi_cmds!("foo bar baz\x1b0vfrd\x0d", " baz");
+        test_vi_cmds!("foo bar\x1b0v$d\x0d", "");
+        // escape leaves visual mode without changing the buffer
+        test_vi_cmds!("foo\x1bvh\x1bx\x0d", "fo");
+    }
+
+    #[test]
+    fn vi_visual_mode_highlight() {
+        let h = History::new();
+        let cfg = config(EditMode::Vi);
+        let mut io = TestIO { input: vi_cmd_vec!("foo bar\x1b0vl\x1b\x0d"), output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("foo bar".to_owned()));
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.contains("foo> \x1b[7mfo\x1b[0mo bar"));
+    }
+
+    /// Test yanking and pasting through the unnamed register.
+    #[test]
+    fn vi_yank_and_paste() {
+        test_vi_cmds!("foo bar\x1b0ywP\x0d", "foo foo bar");
+        test_vi_cmds!("foo bar\x1b0dwp\x0d", "bfoo ar");
+        test_vi_cmds!("abc\x1b0xp\x0d", "bac");
+        test_vi_cmds!("abc\x1b0y$A \x1bp\x0d", "abc abc");
+        test_vi_cmds!("ab\x1b0yl3p\x0d", "aaaab");
+        test_vi_cmds!("foo bar\x1b0yfrP\x0d", "foo barfoo bar");
+        test_vi_cmds!("foo bar\x1byb$p\x0d", "foo barba");
+        // linewise text is pasted at the start or the end of the line
+        test_vi_cmds!("foo\x1byyPix\x1b\x0d", "xfoofoo");
+        test_vi_cmds!("foo\x1b0yypix\x1b\x0d", "fooxfoo");
+        test_vi_cmds!("foo\x1bddp\x0d", "foo");
+        // pasting an empty register does nothing
+        test_vi_cmds!("foo\x1bp\x0d", "foo");
+    }
+
+    #[test]
+    fn vi_last_non_blank() {
+        test_vi_cmds!("foo bar   \x1b0g_x\x0d", "foo ba   ");
+        test_vi_cmds!("foo bar   \x1b0dg_\x0d", "   ");
+        test_vi_cmds!("foo bar  \x1b0wcg_baz\x1b\x0d", "foo baz  ");
+        test_vi_cmds!("foo bar  \x1b0wyg_$p\x0d", "foo bar  bar");
+        test_vi_cmds!("foo bar  \x1b0wvg_d\x0d", "foo   ");
+    }
+
+    #[test]
+    fn bell() {
+        let h = History::new();
+        let cases: [(&[&str], bool); 4] = [
+            (&["\x1b[D", "\x0d"], true),
+            (&["a", "\x1b[C", "\x0d"], true),
+            (&["\x0f", "\x0d"], true),
+            (&["a", "\x1b[D", "\x0d"], false),
+        ];
+        for &(input, rings) in &cases {
+            for &bell in &[true, false] {
+                let cfg = Config { bell: bell, ..config(EditMode::Emacs) };
+                let mut io = ChunkIO::new(input);
+                let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+                assert!(run_edit(&mut ctx, &mut io).is_ok());
+                assert_eq!(io.output.contains(&7), rings && bell);
+            }
+        }
+    }
+
+    #[test]
+    fn bell_on_empty_history() {
+        let mut h = History::new();
+        for &(on, rings) in &[(true, true), (false, false)] {
+            let cfg = Config { bell_on_empty_history: on, ..config(EditMode::Emacs) };
+            let mut io = ChunkIO::new(&["\x1b[A", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("".to_string()));
+            assert_eq!(io.output.contains(&7), rings);
+        }
+        // there is no bell when the history has entries
+        h.push("foo".to_string(), &HistoryConfig::default());
+        let cfg = Config { bell_on_empty_history: true, ..config(EditMode::Emacs) };
+        let mut io = ChunkIO::new(&["\x1b[A", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("foo".to_string()));
+        assert!(!io.output.contains(&7));
+    }
+
+    #[test]
+    fn bracketed_paste() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        let mut io = ChunkIO::new(&["x", "\x1b[200~a\rb\x1b[201~", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("xa\rb".to_string()));
+        let cfg = config(EditMode::Vi);
+        let mut io = ChunkIO::new(&["x", "\x1b[200~a\rb\x1b[201~", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("xa\rb".to_string()));
+        // escapes are inserted as well, staying in insert mode, even if the
+        // paste arrives in several reads
+        let mut io = ChunkIO::new(&["x", "\x1b[200~a\x1bdd", "b\x1b[2", "01~", "c", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("xa\x1bddbc".to_string()));
+    }
+
+    #[test]
+    fn bracketed_paste_mode() {
+        let h = History::new();
+        for &enabled in &[true, false] {
+            let cfg = Config { bracketed_paste: enabled, ..config(EditMode::Emacs) };
+            let mut io = TestIO { input: vi_cmd_vec!("\x1b[1;1Rab\x0d"), output: vec![] };
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run(&mut ctx, &mut io), Ok("ab".to_string()));
+            let output = String::from_utf8(io.output).unwrap();
+            assert_eq!(output.contains("\x1b[?2004h"), enabled);
+            assert_eq!(output.ends_with("\x1b[?2004l"), enabled);
+        }
+    }
+
+    #[test]
+    fn vi_move_to_grapheme() {
+        let h = History::new();
+        let cfg = Config { encoding: Encoding::Utf8, ..config(EditMode::Vi) };
+        // the target of f arrives together, the way a terminal sends it
+        let mut io = ChunkIO::new(&["ae be\u{301} c", "\x1b", "0", "f", "e\u{301}", "x", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ae b c".to_string()));
+        let mut io = ChunkIO::new(&["ae be\u{301} c", "\x1b", "0", "d", "t", "e\u{301}", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("e\u{301} c".to_string()));
+    }
+
+    /// Completes the word before the cursor from a fixed list.
+    struct WordCompleter(Vec<&'static str>);
+
+    impl Completer for WordCompleter {
+        fn complete(&self, line: &str, pos: usize) -> (usize, Vec<Completion>) {
+            let start = line[..pos].rfind(' ').map(|i| i + 1).unwrap_or(0);
+            let word = &line[start..pos];
+            (start, self.0.iter().filter(|w| w.starts_with(word)).map(|w| Completion::new(w.to_string())).collect())
+        }
+    }
+
+    #[test]
+    fn complete() {
+        let h = History::new();
+        let cfg = Config {
+            completer: Some(Box::new(WordCompleter(vec!["hello", "help", "world"]))),
+            ..config(EditMode::Emacs)
+        };
+        for &(typed, result, rings) in &[
+            ("say he", "say hel", false),
+            ("say hel", "say hel", true),
+            ("say w", "say world", false),
+            ("say x", "say x", true),
+        ] {
+            let mut io = ChunkIO::new(&[typed, "\t", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok(result.to_string()));
+            assert_eq!(io.output.contains(&7), rings);
+        }
+        let cfg = Config {
+            completer: Some(Box::new(WordCompleter(vec!["hello"]))),
+            ..config(EditMode::Vi)
+        };
+        let mut io = ChunkIO::new(&["h", "\t", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("hello".to_string()));
+    }
+
+    #[test]
+    fn complete_no_match() {
+        let h = History::new();
+        for &(no_match, result, rings) in &[
+            (NoMatchHandling::Bell, "say x", true),
+            (NoMatchHandling::Ignore, "say x", false),
+            (NoMatchHandling::InsertTab, "say x\t", false),
+        ] {
+            let cfg = Config {
+                completer: Some(Box::new(WordCompleter(vec!["hello"]))),
+                no_match: no_match,
+                ..config(EditMode::Emacs)
+            };
+            let mut io = ChunkIO::new(&["say x", "\t", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok(result.to_string()));
+            assert_eq!(io.output.contains(&7), rings);
+        }
+    }
+
+    #[test]
+    fn busy_indicator() {
+        let h = History::new();
+        for &enabled in &[true, false] {
+            let cfg = Config {
+                completer: Some(Box::new(WordCompleter(vec!["hello"]))),
+                busy_indicator: if enabled { Some("...".to_owned()) } else { None },
+                ..config(EditMode::Emacs)
+            };
+            let mut io = ChunkIO::new(&["he", "\t", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("hello".to_string()));
+            let output = String::from_utf8(io.output).unwrap();
+            // the marker follows the line before completing, and is erased afterwards
+            let marker = output.find("\r\x1b[7C ...\r\x1b[7C");
+            assert_eq!(marker.is_some(), enabled);
+            if let Some(marker) = marker {
+                let completed = output.find("foo> hello\x1b[0K").unwrap();
+                assert!(marker < completed);
+            }
+        }
+    }
+
+    #[test]
+    fn key_bindings() {
+        let h = History::new();
+        let mut cfg = config(EditMode::Emacs);
+        cfg.bind(Token::CtrlT, Instr::MoveCursor(MoveCursorInstr::End));
+        cfg.bind(Token::CtrlT, Instr::MoveCursor(MoveCursorInstr::Start));
+        cfg.bind(Token::CtrlK, Instr::DeleteToEnd);
+        let mut io = TestIO { input: vi_cmd_vec!("bc\x14a\x0b\x19\x0d"), output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        // the text deleted by C-k is not killed
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("a".to_string()));
+
+        let mut cfg = config(EditMode::Vi);
+        cfg.key_bindings.bind(Keymap::ViNormal, Token::Text("H".to_owned()), Instr::MoveCursor(MoveCursorInstr::Start));
+        let mut io = TestIO { input: vi_cmd_vec!("bc\x1bHiH\x0d"), output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        // the binding does not apply in insert mode
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("Hbc".to_string()));
+    }
+
+    #[test]
+    fn accepted_by() {
+        let h = History::new();
+        let mut cfg = config(EditMode::Emacs);
+        cfg.bind(Token::Tab, Instr::Common(CommonInstr::Done));
+        for &(input, ref key) in &[
+            ("ab\x0d", Some(Token::Enter)),
+            ("ab\x0a", Some(Token::CtrlJ)),
+            ("ab\x09", Some(Token::Tab)),
+            ("ab", None),
+        ] {
+            let mut io = TestIO { input: input.as_bytes().to_vec(), output: vec![] };
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab".to_string()));
+            assert_eq!(ctx.accepted_by(), key.as_ref());
+        }
+        // a cancelled line was not accepted
+        let mut io = TestIO { input: b"ab\x03".to_vec(), output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Err(Error::Cancel));
+        assert_eq!(ctx.accepted_by(), None);
+        let cfg = config(EditMode::Vi);
+        let mut io = TestIO { input: b"ab\x04".to_vec(), output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab".to_string()));
+        assert_eq!(ctx.accepted_by(), Some(&Token::CtrlD));
+    }
+
+    #[test]
+    fn mark_trailing_space() {
+        let h = History::new();
+        let cfg = Config { mark_trailing_space: true, ..config(EditMode::Emacs) };
+        let mut io = ChunkIO::new(&["ab  ", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab  ".to_string()));
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.contains("foo> ab\x1b[7m  \x1b[0m\x1b[0K"));
+    }
+
+    #[test]
+    fn vi_text_objects() {
+        let h = History::new();
+        let cfg = config(EditMode::Vi);
+        for &(keys, result) in &[
+            (&["foo bar baz", "\x1b", "b", "b", "d", "i", "w"][..], "foo  baz"),
+            (&["foo bar baz", "\x1b", "b", "b", "d", "a", "w"][..], "foo baz"),
+            (&["foo bar.x baz", "\x1b", "b", "b", "b", "c", "i", "W", "qux", "\x1b"][..], "foo qux baz"),
+            (&["foo bar", "\x1b", "y", "i", "w", "P"][..], "foo barbar"),
+            (&["foo bar", "\x1b", "d", "a", "w"][..], "foo"),
+        ] {
+            let mut chunks = keys.to_vec();
+            chunks.push("\x0d");
+            let mut io = ChunkIO::new(&chunks);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok(result.to_string()));
+        }
+    }
+
+    #[test]
+    fn erase_keys() {
+        let h = History::new();
+        for &mode in &[EditMode::Emacs, EditMode::Vi] {
+            let cfg = Config { ctrl_h_erases: false, backspace_erases_forward: true, ..config(mode) };
+            let mut io = ChunkIO::new(&["abc", "\x1b[D", "\x1b[D", "\x7f", "\x08", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("ac".to_string()));
+            // C-h has no effect at all
+            assert!(!io.output.contains(&b'\x07'));
+        }
+        let mut cfg = Config { ctrl_h_erases: false, ..config(EditMode::Emacs) };
+        cfg.bind(Token::CtrlH, Instr::MoveCursor(MoveCursorInstr::Start));
+        let mut io = TestIO { input: b"bc\x08a\x7f\x0d".to_vec(), output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("bc".to_string()));
+    }
+
+    #[test]
+    fn history_first_and_last() {
+        let mut h = History::new();
+        h.push("one".to_string(), &HistoryConfig::default());
+        h.push("two".to_string(), &HistoryConfig::default());
+        h.push("three".to_string(), &HistoryConfig::default());
+        let cfg = config(EditMode::Emacs);
+        let mut io = ChunkIO::new(&["x", "\x1b<", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("one".to_string()));
+        // M-> returns to the line being edited
+        let mut io = ChunkIO::new(&["x", "\x1b[A", "\x1b<", "\x1b>", "y", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("xy".to_string()));
+    }
+
+    #[test]
+    fn history_continued_input() {
+        let mut h = History::new();
+        h.push("(foo\n bar\n)".to_string(), &HistoryConfig::default());
+        let cfg = config(EditMode::Emacs);
+        // the lines of an entry are joined into one, both by C-p and C-r
+        for keys in &["\x10", "\x12bar\x1b"] {
+            let mut io = ChunkIO::new(&[keys, "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("(foo  bar )".to_string()));
+            let output = String::from_utf8(io.output).unwrap();
+            assert!(output.contains("foo> (foo  bar )"));
+            assert!(!output[..output.len() - 2].contains('\n'));
+        }
+    }
+
+    /// Completes commands at the start of the line, and paths after them.
+    struct PathCompleter;
+
+    impl Completer for PathCompleter {
+        fn complete(&self, line: &str, pos: usize) -> (usize, Vec<Completion>) {
+            match line[..pos].rfind(' ') {
+                None => (0, ["cat", "cd"].iter()
+                    .filter(|c| c.starts_with(&line[..pos]))
+                    .map(|c| Completion::with_delimiter(c.to_string(), ' '))
+                    .collect()),
+                Some(i) => (i + 1, ["src", "srv", "target"].iter()
+                    .filter(|d| d.starts_with(&line[i + 1..pos]))
+                    .map(|d| Completion::with_delimiter(d.to_string(), '/'))
+                    .collect())
+            }
+        }
+    }
+
+    #[test]
+    fn complete_with_delimiter() {
+        let h = History::new();
+        let cfg = Config { completer: Some(Box::new(PathCompleter)), ..config(EditMode::Emacs) };
+        for &(keys, result) in &[
+            (&["ca", "\t"][..], "cat "),
+            (&["cd t", "\t", "\t"][..], "cd target/"),
+            // the delimiter is not added to a common prefix
+            (&["c", "\t"][..], "c"),
+            (&["cd s", "\t"][..], "cd sr"),
+            (&["cd s", "\t", "c", "\t"][..], "cd src/"),
+        ] {
+            let mut chunks = keys.to_vec();
+            chunks.push("\x0d");
+            let mut io = ChunkIO::new(&chunks);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok(result.to_string()));
+        }
+    }
+
+    #[test]
+    fn search_match_count() {
+        let mut h = History::new();
+        for s in &["foo 1", "bar", "foo 2", "foo 3"] {
+            h.push(s.to_string(), &HistoryConfig::default());
+        }
+        let cfg = config(EditMode::Emacs);
+        let mut io = ChunkIO::new(&["\x12", "foo", "\x12", "\x12", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("foo 1".to_string()));
+        let output = String::from_utf8(io.output.clone()).unwrap();
+        assert!(output.contains("(1/3) (reverse-i-search)`foo': "));
+        assert!(output.contains("(2/3) (reverse-i-search)`foo': "));
+        assert!(output.contains("(3/3) (reverse-i-search)`foo': "));
+        // C-g brings back the line as it was
+        let mut io = ChunkIO::new(&["x", "\x12", "ba", "\x07", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("x".to_string()));
+    }
+
+    #[test]
+    fn skip_identical_renders() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        // C-k at the end of the line leaves the line as it was
+        let mut io = ChunkIO::new(&["a", "\x0b", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("a".to_string()));
+        let mut io2 = ChunkIO::new(&["a", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io2), Ok("a".to_string()));
+        assert_eq!(io.writes, io2.writes);
+        assert_eq!(io.output, io2.output);
+    }
+
+    #[test]
+    fn horizontal_scroll() {
+        let h = History::new();
+        let mut cfg = config(EditMode::Emacs);
+        cfg.horizontal_scroll = true;
+        let mut io = ChunkIO::new(&["0123456789", "\x01", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        ctx.set_width(Some(10));
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("0123456789".to_string()));
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.contains("\rfoo> 6789\x1b[0K\r\x1b[9C"));
+        assert!(output.contains("\rfoo> 01234\x1b[0K\r\x1b[5C"));
+    }
+
+    #[test]
+    fn wrap_long_lines() {
+        let h = History::new();
+        let cfg = Config { wrap_mode: WrapMode::Word, ..config(EditMode::Emacs) };
+        let mut io = ChunkIO::new(&["the quick brown fox", "\x01", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        ctx.set_width(Some(14));
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("the quick brown fox".to_string()));
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.contains("\r\x1b[0Jfoo> the quick\r\nbrown fox\r\x1b[9C"));
+        // moving to the first row redraws the line from there
+        assert!(output.contains("\x1b[1A\r\x1b[0Jfoo> the quick\r\nbrown fox\x1b[1A\r\x1b[5C"));
+        // and the output after the line starts below its last row
+        assert!(output.ends_with("\x1b[1B\r\x1b[9C"));
+    }
+
+    #[test]
+    fn horizontal_scroll_with_hint() {
+        struct FixedHinter;
+        impl Hinter for FixedHinter {
+            fn hint(&self, _line: &str, _pos: usize) -> Option<String> {
+                Some("!!".to_owned())
+            }
+        }
+        let h = History::new();
+        let mut cfg = config(EditMode::Emacs);
+        cfg.horizontal_scroll = true;
+        let mut io = ChunkIO::new(&["0123456789", "\x02\x02", "\x01", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        ctx.set_width(Some(10));
+        ctx.set_hinter(Some(&FixedHinter));
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("0123456789".to_string()));
+        let output = String::from_utf8(io.output).unwrap();
+        // only as much of the hint is shown as fits after the window
+        assert!(output.contains("\rfoo> 6789\x1b[0K\r\x1b[9C\r\x1b[9C\x1b[2m!\x1b[0m\r\x1b[9C"));
+        // moving within the window keeps it in place, along with the hint
+        assert!(output.contains("\rfoo> 6789\x1b[0K\r\x1b[7C\r\x1b[9C\x1b[2m!\x1b[0m\r\x1b[7C"));
+        // and the hint is not shown while the end of the line is not
+        assert!(output.contains("\rfoo> 01234\x1b[0K\r\x1b[5C"));
+        assert!(output.ends_with("\rfoo> 01234\x1b[0K\r\x1b[5C"));
+    }
+
+    #[test]
+    fn suspend() {
+        let h = History::new();
+        let mut cfg = config(EditMode::Emacs);
+        let mut io = ChunkIO::new(&["a", "\x1a", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("a".to_string()));
+        assert_eq!(io.suspended, 0);
+        cfg.enable_suspend = true;
+        let mut io = ChunkIO::new(&["a", "\x1a", "\x0d"]);
+        let mut ctx = EditCtx::new("a\nfoo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("a".to_string()));
+        assert_eq!(io.suspended, 1);
+        // the line is redrawn in full after resuming
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.ends_with("a\r\n\rfoo> a\x1b[0K\r\x1b[6C"));
+    }
+
+    #[test]
+    fn vi_undo_delete_line() {
+        // the line and the cursor are restored
+        test_vi_cmds!("hello world\x1bbddux\x0d", "hello orld");
+        test_vi_cmds!("hello\x1bddu\x0d", "hello");
+        // each change is undone separately
+        test_vi_cmds!("hello\x1bxddu\x0d", "hell");
+        test_vi_cmds!("hello\x1bxdduu\x0d", "hello");
+        test_vi_cmds!("\x1bu\x0d", "");
+    }
+
+    #[test]
+    fn render_once_per_read() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        let keys = "x\x02".repeat(1000);
+        let mut io = ChunkIO::new(&[&keys, "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("x".repeat(1000)));
+        // the empty line, and the line after all keys are handled
+        assert_eq!(io.writes, 2);
+    }
+
+    #[test]
+    fn large_input() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        // 64KB of keys, each of which is handled separately
+        let keys = "x\x02".repeat(32 * 1024);
+        let mut io = ChunkIO::new(&[&keys, "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("x".repeat(32 * 1024)));
+    }
+
+    #[test]
+    fn complete_after_word_only() {
+        let h = History::new();
+        let cfg = Config {
+            completer: Some(Box::new(WordCompleter(vec!["hello", "help", "world"]))),
+            complete_after_word_only: true,
+            ..config(EditMode::Emacs)
+        };
+        for &(keys, result) in &[
+            (&["say w", "\t", "\x0d"][..], "say world"),
+            (&["say ", "\t", "\x0d"][..], "say     "),
+            (&["say w", "\x02", "\t", "\x0d"][..], "say     w"),
+            (&["\t", "\x0d"][..], "    "),
+        ] {
+            let mut io = ChunkIO::new(keys);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok(result.to_string()));
+        }
+    }
+
+    #[test]
+    fn right_prompt() {
+        let h = History::new();
+        let cfg = Config {
+            right_prompt: Some("12:00".to_owned()),
+            ..config(EditMode::Emacs)
+        };
+        let mut io = ChunkIO::new(&["ab", "cdefghij", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        ctx.set_width(Some(20));
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("abcdefghij".to_string()));
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.contains("\rfoo> ab\x1b[0K\r\x1b[7C\r\x1b[7C       12:00\r\x1b[7C"));
+        // omitted once the line reaches it
+        assert_eq!(output.matches("12:00").count(), 2);
+    }
+
+    #[test]
+    fn echo_cancel() {
+        let h = History::new();
+        for &enabled in &[true, false] {
+            let cfg = Config { echo_cancel: enabled, ..config(EditMode::Emacs) };
+            let mut io = ChunkIO::new(&["abc", "\x02", "\x03"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Err(Error::Cancel));
+            let output = String::from_utf8(io.output).unwrap();
+            assert_eq!(output.ends_with("\r\x1b[8C^C"), enabled);
+            assert_eq!(output.contains("^C"), enabled);
+        }
+    }
+
+    #[test]
+    fn complete_on_type() {
+        let h = History::new();
+        let cfg = Config {
+            completer: Some(Box::new(WordCompleter(vec!["hello", "help", "world"]))),
+            complete_on_type: true,
+            ..config(EditMode::Emacs)
+        };
+        let mut io = ChunkIO::new(&["he", "ll", "\t", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("hello".to_string()));
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.contains("\rfoo> he\x1b[0K\r\x1b[7C\r\nhello  help\x1b[0K\x1b[1A\r\x1b[7C"));
+        assert!(output.contains("\rfoo> hell\x1b[0K\r\x1b[9C\r\nhello\x1b[0K\x1b[1A\r\x1b[9C"));
+        // the list is erased once the line is accepted
+        assert!(output.ends_with("\r\n\x1b[0K\x1b[1A\r\x1b[5C"));
+    }
+
+    #[test]
+    fn confirm_large_paste() {
+        let h = History::new();
+        let cfg = Config { paste_warn_threshold: Some(5), ..config(EditMode::Emacs) };
+        for &(answer, result) in &[("y", "<abcdefgh"), ("n", "<")] {
+            let mut io = ChunkIO::new(&["<", "\x1b[200~abcdefgh\x1b[201~", answer, "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok(result.to_string()));
+            let output = String::from_utf8(io.output).unwrap();
+            assert!(output.contains("\r\nPaste 8 characters? y/n\x1b[0K"));
+        }
+        // smaller pastes are inserted right away
+        let mut io = ChunkIO::new(&["\x1b[200~abc\x1b[201~", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("abc".to_string()));
+        // text arriving at once is taken for a paste without bracketed
+        // paste, and so are the newlines within it
+        for &(answer, result) in &[("y", "<abc\rdefg"), ("n", "<")] {
+            let mut io = ChunkIO::new(&["<", "abc\rdefg", answer, "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok(result.to_string()));
+            let output = String::from_utf8(io.output).unwrap();
+            assert!(output.contains("\r\nPaste 8 characters? y/n\x1b[0K"));
+        }
+        // but not keys, nor short bursts
+        let mut io = ChunkIO::new(&["abcd\x1b[Dxy", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("abcxyd".to_string()));
+        let mut io = ChunkIO::new(&["ab\rcd"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab".to_string()));
+    }
+
+    #[test]
+    fn vi_history_goto() {
+        let mut h = History::new();
+        h.push("one".to_string(), &HistoryConfig::default());
+        h.push("two".to_string(), &HistoryConfig::default());
+        h.push("three".to_string(), &HistoryConfig::default());
+        let cfg = config(EditMode::Vi);
+        for &(keys, result) in &[
+            ("x\x1bgg\x0d", "one"),
+            ("x\x1bggG\x0d", "x"),
+            ("x\x1b2G\x0d", "two"),
+            ("x\x1bk3G\x0d", "three"),
+            // out of range
+            ("x\x1b5G\x0d", "x"),
+            // gg leaves the g mode
+            ("x\x1bgg0x\x0d", "ne"),
+        ] {
+            let mut io = TestIO { input: keys.as_bytes().to_vec(), output: vec![] };
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok(result.to_string()));
+        }
+    }
+
+    #[test]
+    fn change_case_of_line() {
+        let h = History::new();
+        let mut cfg = Config { encoding: Encoding::Utf8, ..config(EditMode::Emacs) };
+        cfg.bind(Token::Meta('u'), Instr::UppercaseLine);
+        cfg.bind(Token::Meta('l'), Instr::LowercaseLine);
+        let mut io = ChunkIO::new(&["Grüße", "\x02", "\x1bu", "!", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("GRÜS!SE".to_string()));
+        let mut io = ChunkIO::new(&["ÉCOLE", "\x1bl", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("école".to_string()));
+    }
+
+    #[test]
+    fn emacs_ctrl_d() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        // at the end of a line that is not empty, C-d has no effect
+        let mut io = ChunkIO::new(&["ab", "\x04", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab".to_string()));
+        assert!(io.output.contains(&7));
+        let mut io = ChunkIO::new(&["ab", "\x01", "\x04", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("b".to_string()));
+        let mut io = ChunkIO::new(&["\x04", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Err(Error::EndOfFile));
+    }
+
+    #[test]
+    fn prompt_fn() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        let mut renders = 8;
+        let mut prompt_fn = || { renders += 1; format!("{}> ", renders) };
+        let mut io = ChunkIO::new(&["a", "b", "\x0d"]);
+        let mut ctx = EditCtx::new("> ", &h, &cfg);
+        ctx.set_prompt_fn(Some(&mut prompt_fn));
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab".to_string()));
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.contains("\r9> \x1b[0K\r\x1b[3C"));
+        // the cursor follows the wider prompt
+        assert!(output.contains("\r10> a\x1b[0K\r\x1b[5C"));
+        assert!(output.contains("\r11> ab\x1b[0K\r\x1b[6C"));
+    }
+
+    #[test]
+    fn emacs_numeric_argument() {
+        let h = History::new();
+        let line = |cfg: &Config, input: &[&str]| {
+            let mut io = ChunkIO::new(input);
+            let mut ctx = EditCtx::new("foo> ", &h, cfg);
+            run_edit(&mut ctx, &mut io).unwrap()
+        };
+        let cfg = config(EditMode::Emacs);
+        assert_eq!(line(&cfg, &["\x1b3", "a", "b", "\x0d"]), "aaab");
+        assert_eq!(line(&cfg, &["\x1b1", "\x1b2", "x", "\x0d"]), "x".repeat(12));
+        assert_eq!(line(&cfg, &["\x1b1", "2", "x", "\x0d"]), "x".repeat(12));
+        assert_eq!(line(&cfg, &["abc", "\x1b2", "\x02", "X", "\x0d"]), "aXbc");
+        assert_eq!(line(&cfg, &["abcdef", "\x01", "\x1b3", "\x04", "\x0d"]), "def");
+
+        let mut cfg = config(EditMode::Emacs);
+        cfg.bind(Token::CtrlU, Instr::UniversalArgument);
+        assert_eq!(line(&cfg, &["\x15", "a", "\x0d"]), "aaaa");
+        assert_eq!(line(&cfg, &["\x15", "\x15", "a", "\x0d"]), "a".repeat(16));
+        let input = "x".repeat(25);
+        assert_eq!(line(&cfg, &[&input, "\x01", "\x15", "2", "0", "\x04", "\x0d"]), "x".repeat(5));
+    }
+
+    #[test]
+    fn quoted_insert() {
+        let h = History::new();
+        for &mode in &[EditMode::Emacs, EditMode::Vi] {
+            let cfg = config(mode);
+            let mut io = ChunkIO::new(&["a", "\x16", "\t", "b", "\x16", "\x1b", "\x16", "\x0d", "c", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("a\tb\x1b\x0dc".to_string()));
+        }
+        // escape sequences are inserted as a whole
+        let cfg = config(EditMode::Emacs);
+        let mut io = ChunkIO::new(&["\x16", "\x1b[A", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("\x1b[A".to_string()));
+    }
+
+    #[test]
+    fn unbind() {
+        let h = History::new();
+        for &mode in &[EditMode::Emacs, EditMode::Vi] {
+            let mut cfg = config(mode);
+            cfg.unbind(Token::CtrlL);
+            let mut io = ChunkIO::new(&["ab", "\x0c", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab".to_string()));
+            // no clear screen sequence, and no bell either
+            assert!(!io.output.windows(4).any(|w| w == b"\x1b[2J"));
+            assert!(!io.output.contains(&b'\x07'));
+        }
+    }
+
+    /// Buffers its output until it is flushed.
+    struct DelayedIO {
+        inner: ChunkIO,
+        buffered: Vec<u8>
+    }
+
+    impl RunIO for DelayedIO {
+        fn write(&mut self, w: Vec<u8>) -> Result<(), Error> {
+            self.buffered.extend(w);
+            Ok(())
+        }
+        fn flush(&mut self) -> Result<(), Error> {
+            let buffered = self.buffered.split_off(0);
+            self.inner.write(buffered)
+        }
+        fn read_byte(&mut self) -> Result<u8, Error> {
+            assert!(self.buffered.is_empty());
+            self.inner.read_byte()
+        }
+        fn read_seq(&mut self) -> Result<Vec<u8>, Error> {
+            assert!(self.buffered.is_empty());
+            self.inner.read_seq()
+        }
+    }
+
+    #[test]
+    fn flush_before_reading() {
+        let h = History::new();
+        let cfg = Config {
+            partial_line: PartialLineHandling::Newline,
+            bracketed_paste: true,
+            ..config(EditMode::Emacs)
+        };
+        let mut io = DelayedIO { inner: ChunkIO::new(&["\x1b[1;1R", "ab", "\x0d"]), buffered: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run(&mut ctx, &mut io), Ok("ab".to_string()));
+        assert!(io.buffered.is_empty());
+        let output = String::from_utf8(io.inner.output).unwrap();
+        assert!(output.ends_with("\rfoo> ab\x1b[0K\r\x1b[7C\x1b[?2004l"));
+    }
+
+    #[test]
+    fn on_change() {
+        use std::sync::{Arc, Mutex};
+        let h = History::new();
+        let changes = Arc::new(Mutex::new(vec![]));
+        let sink = changes.clone();
+        let cfg = Config::builder()
+            .on_change(move |line, pos| sink.lock().unwrap().push((line.to_owned(), pos)))
+            .build();
+        let mut io = ChunkIO::new(&["ab", "\x02", "\x0c", "\x06", "c", "\x7f", "\x7f", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("a".to_string()));
+        // neither moving the cursor nor clearing the screen change the line
+        assert_eq!(*changes.lock().unwrap(), vec![("ab".to_owned(), 2), ("abc".to_owned(), 3),
+                                                  ("ab".to_owned(), 2), ("a".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn empty_backspace() {
+        let h = History::new();
+        for &(ref empty_backspace, ref result, rings) in &[
+            (EmptyBackspace::Ignore, Ok("x".to_string()), false),
+            (EmptyBackspace::Bell, Ok("x".to_string()), true),
+            (EmptyBackspace::Run(Instr::Common(CommonInstr::Cancel)), Err(Error::Cancel), false),
+            // running backspace itself does nothing
+            (EmptyBackspace::Run(Instr::DeleteCharLeftOfCursor), Ok("x".to_string()), false),
+        ] {
+            let cfg = Config { empty_backspace: empty_backspace.clone(), ..config(EditMode::Emacs) };
+            let mut io = ChunkIO::new(&["\x7f", "x", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(&run_edit(&mut ctx, &mut io), result);
+            assert_eq!(io.output.contains(&7), rings);
+        }
+        // only an empty line is special
+        let cfg = Config { empty_backspace: EmptyBackspace::Bell, ..config(EditMode::Emacs) };
+        let mut io = ChunkIO::new(&["ab", "\x7f", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("a".to_string()));
+        assert!(!io.output.contains(&7));
+    }
+
+    #[test]
+    fn edit_externally() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        let mut io = ChunkIO::new(&["hello", "\x18", "\x05", "!", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("HELLO!".to_string()));
+        assert_eq!(io.edited, vec!["hello".to_string()]);
+        // the line is redrawn in full after editing
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.contains("\rfoo> HELLO\x1b[0K\r\x1b[10C"));
+        // the edit can be undone
+        let mut io = ChunkIO::new(&["hello", "\x18", "\x05", "\x1f", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("hello".to_string()));
+        // text that is not echoed is never handed to the editor
+        let cfg = Config { echo: EchoMode::None, ..config(EditMode::Emacs) };
+        let mut io = ChunkIO::new(&["secret", "\x18", "\x05", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("secret".to_string()));
+        assert!(io.edited.is_empty());
+    }
+
+    #[test]
+    fn control_char_policy() {
+        let h = History::new();
+        for &(policy, result, rings) in &[
+            // the bell character is echoed as it is
+            (ControlCharPolicy::Keep, "xa\x07b\tc\u{85}y", true),
+            (ControlCharPolicy::Strip, "xab cy", false),
+            (ControlCharPolicy::Reject, "xy", true),
+        ] {
+            let cfg = Config {
+                encoding: Encoding::Utf8,
+                control_char_policy: policy,
+                ..config(EditMode::Emacs)
+            };
+            let mut io = ChunkIO::new(&["x", "\x1b[200~a\x07b\tc\u{85}\x1b[201~", "y", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok(result.to_string()));
+            assert_eq!(io.output.contains(&7), rings);
+        }
+        // a quoted insert is kept regardless
+        let cfg = Config { control_char_policy: ControlCharPolicy::Strip, ..config(EditMode::Emacs) };
+        let mut io = ChunkIO::new(&["x", "\x16", "\x07", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("x\x07".to_string()));
+    }
+
+    #[test]
+    fn redraw_on_accept() {
+        struct FixedHinter;
+        impl Hinter for FixedHinter {
+            fn hint(&self, _line: &str, _pos: usize) -> Option<String> {
+                Some(" world".to_owned())
+            }
+        }
+        let h = History::new();
+        for &enabled in &[true, false] {
+            let cfg = Config {
+                redraw_on_accept: enabled,
+                mark_trailing_space: true,
+                ..config(EditMode::Emacs)
+            };
+            let mut io = ChunkIO::new(&["hello ", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            ctx.set_hinter(Some(&FixedHinter));
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("hello ".to_string()));
+            // the hint is erased, while the trailing space is still marked
+            let output = String::from_utf8(io.output).unwrap();
+            assert_eq!(output.ends_with("\rfoo> hello\x1b[7m \x1b[0m\x1b[0K\r\x1b[11C"), enabled);
+            assert!(output.contains("\x1b[2m world\x1b[0m"));
+        }
+    }
+
+    #[test]
+    fn initial_text() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        let mut io = ChunkIO::new(&[" there", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        ctx.insert("echo hi");
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("echo hi there".to_string()));
+        // the initial text is shown before any input
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.starts_with("\rfoo> echo hi\x1b[0K\r\x1b[12C"));
+    }
+
+    #[test]
+    fn ctrl_u_kills_whole_line() {
+        let h = History::new();
+        for &(whole, result) in &[(false, "arfoo b"), (true, "foo bar")] {
+            let cfg = Config { ctrl_u_kills_whole_line: whole, ..config(EditMode::Emacs) };
+            let mut io = ChunkIO::new(&["foo bar", "\x02", "\x02", "\x15", "\x05", "\x19", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok(result.to_string()));
+        }
+    }
+
+    #[test]
+    fn ctrl_c_clears_line() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        let mut io = ChunkIO::new(&["foo", "\x03", "bar", "\x0d"]);
+        let mut ctx = EditCtx::new("> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Err(Error::Cancel));
+        for &mode in &[EditMode::Emacs, EditMode::Vi] {
+            let cfg = Config { interrupt: InterruptAction::ClearLine, ..config(mode) };
+            let mut io = ChunkIO::new(&["foo", "\x03", "bar", "\x0d"]);
+            let mut ctx = EditCtx::new("> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("bar".to_string()));
+            // the marker is shown after the abandoned line, and the prompt on the next one
+            let output = String::from_utf8(io.output).unwrap();
+            assert!(output.contains("\r> foo\x1b[0K\r\x1b[5C\r\x1b[5C^C\x1b[0K\r\n\r> \x1b[0K\r\x1b[2C"));
+        }
+        // the abandoned line cannot be brought back by undoing
+        let cfg = Config { interrupt: InterruptAction::ClearLine, ..config(EditMode::Emacs) };
+        let mut io = ChunkIO::new(&["foo", "\x03", "bar", "\x1f", "\x1f", "\x0d"]);
+        let mut ctx = EditCtx::new("> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("".to_string()));
+    }
+
+    #[test]
+    fn vi_replace_count() {
+        test_vi_cmds!("abcdef\x1b0rx\x0d", "xbcdef");
+        test_vi_cmds!("abcdef\x1b03rx\x0d", "xxxdef");
+        // the cursor is left on the last replaced character
+        test_vi_cmds!("abcdef\x1b03rxiy\x0d", "xxyxdef");
+        test_vi_cmds!("abcdef\x1b03rx.\x0d", "xxxxxf");
+        // too few characters are left
+        test_vi_cmds!("abcdef\x1b$2rx\x0d", "abcdef");
+        test_vi_cmds!("abcdef\x1b$rx\x0d", "abcdex");
+    }
+
+    #[test]
+    fn vi_line_operators() {
+        test_vi_cmds!("foo bar\x1bddix\x0d", "x");
+        test_vi_cmds!("foo bar\x1b2ddix\x0d", "x");
+        test_vi_cmds!("foo bar\x1bd2dix\x0d", "x");
+        test_vi_cmds!("foo bar\x1b3ccx\x0d", "x");
+        test_vi_cmds!("foo bar\x1bc2cx\x0d", "x");
+        test_vi_cmds!("foo bar\x1bSx\x0d", "x");
+        test_vi_cmds!("foo bar\x1b2Sx\x0d", "x");
+    }
+
+    #[test]
+    fn modified_arrows() {
+        let h = History::new();
+        for &mode in &[EditMode::Emacs, EditMode::Vi] {
+            let cfg = config(mode);
+            // C-Left moves by words, while M-Left moves like Left
+            let mut io = ChunkIO::new(&["foo bar baz", "\x1b[1;5D", "\x1b[1;5D", "x", "\x1b[1;3D", "y", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("foo yxbar baz".to_string()));
+            // C-Right stops at the end of the word in Emacs mode, like M-f
+            let mut io = ChunkIO::new(&["foo bar", "\x1b[H", "\x1b[1;5C", "x", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            let expected = match mode { EditMode::Emacs => "foox bar", EditMode::Vi => "foo xbar" };
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn shift_select() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        // Shift-Left selects up to the cursor, which backspace deletes
+        let mut io = ChunkIO::new(&["foo bar", "\x1b[1;2D", "\x1b[1;2D", "\x1b[1;2D", "\x7f", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("foo ".to_string()));
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.contains("foo> foo \x1b[7mbar\x1b[0m"));
+        // C-w kills the selection, and Shift-Right shrinks it again
+        let mut io = ChunkIO::new(&["foo bar", "\x1b[H", "\x1b[1;2C", "\x1b[1;2C", "\x1b[1;2C", "\x1b[1;2D",
+                                    "\x17", "\x05", "\x19", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("o barfo".to_string()));
+        // any other key ends the selection
+        let mut io = ChunkIO::new(&["foo bar", "\x1b[1;2D", "\x1b[D", "\x7f", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("foo ar".to_string()));
+
+        // in vi mode they enter visual mode, including the cursor
+        let cfg = config(EditMode::Vi);
+        let mut io = ChunkIO::new(&["foo bar", "\x1b", "\x1b[1;2D", "\x1b[1;2D", "d", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("foo ".to_string()));
+        let output = String::from_utf8(io.output).unwrap();
+        assert!(output.contains("foo> foo \x1b[7mbar\x1b[0m"));
+    }
+
+    #[test]
+    fn vi_insert_mode_movement() {
+        let h = History::new();
+        let cfg = config(EditMode::Vi);
+        // moving with the arrows and Home stays in insert mode, like vim
+        let mut io = ChunkIO::new(&["abc", "\x1b[D", "x", "\x1b[H", "y", "\x1b[1;5C", "z", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("yabxcz".to_string()));
+    }
+
+    #[test]
+    fn emacs_meta_word_movement() {
+        let h = History::new();
+        let cfg = config(EditMode::Emacs);
+        let mut io = ChunkIO::new(&["foo.bar baz", "\x01", "\x1bf", "1", "\x1bf", "2", "\x1bb", "3", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("foo1.3bar2 baz".to_string()));
+        // with a numeric argument
+        let mut io = ChunkIO::new(&["foo.bar baz", "\x1b2", "\x1bb", "x", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("foo.xbar baz".to_string()));
+    }
+
+    #[test]
+    fn cursor_query_timeout() {
+        let h = History::new();
+        let cfg = Config { cursor_query_timeout: 250, ..config(EditMode::Emacs) };
+        let mut io = SilentIO { output: vec![], timeouts: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run(&mut ctx, &mut io), Err(Error::EndOfFile));
+        assert_eq!(io.timeouts, vec![250]);
+        assert!(io.output.starts_with(b"\x1b[6n"));
+        assert!(!io.output.contains(&b'%'));
+        // a late answer is not taken as input
+        let mut io = ChunkIO::new(&["a", "\x1b[12;40R", "b", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab".to_string()));
+    }
+
+    #[test]
+    fn interrupt_and_eof_actions() {
+        let h = History::new();
+        for &mode in &[EditMode::Emacs, EditMode::Vi] {
+            let cfg = Config { interrupt: InterruptAction::Ignore, ..config(mode) };
+            let mut io = ChunkIO::new(&["foo", "\x03", "bar", "\x0d"]);
+            let mut ctx = EditCtx::new("> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("foobar".to_string()));
+            assert!(!io.output.contains(&b'\x07'));
+            // C-d on an empty line ends the input by default
+            let cfg = config(mode);
+            let mut io = ChunkIO::new(&["\x04", "foo", "\x0d"]);
+            let mut ctx = EditCtx::new("> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Err(Error::EndOfFile));
+            let cfg = Config { eof: EofAction::Delete, ..config(mode) };
+            let mut io = ChunkIO::new(&["\x04", "foo", "\x1b[D", "\x1b[D", "\x04", "\x0d"]);
+            let mut ctx = EditCtx::new("> ", &h, &cfg);
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("fo".to_string()));
+        }
+    }
+
+    #[test]
+    fn multibyte_split_across_reads() {
+        let h = History::new();
+        // one byte is read at a time
+        let cfg = Config { encoding: Encoding::Utf8, ..config(EditMode::Emacs) };
+        let mut io = TestIO { input: "a\u{e9}\u{20ac}\r".as_bytes().to_vec(), output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("a\u{e9}\u{20ac}".to_string()));
+        let cfg = Config { encoding: Encoding::ShiftJis, ..config(EditMode::Emacs) };
+        let mut io = TestIO { input: b"\x82\xa0\x82\xa2\x02x\r".to_vec(), output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("\u{3042}x\u{3044}".to_string()));
+    }
+
+    #[test]
+    fn invalid_text_is_dropped() {
+        let h = History::new();
+        let cfg = Config { encoding: Encoding::Utf8, ..config(EditMode::Emacs) };
+        let mut io = TestIO { input: b"a\xff\xc3b\xf0\x9f\x98\x80\r".to_vec(), output: vec![] };
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab\u{1f600}".to_string()));
+        // the whole input arrives at once
+        let mut io = ChunkIO::new(&["a\u{e9}b", "\x0d"]);
+        let cfg = config(EditMode::Emacs);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("ab".to_string()));
+    }
+
+    #[test]
+    fn continuation_prompt() {
+        struct Backslash;
+        impl Validator for Backslash {
+            fn is_complete(&self, input: &str) -> bool {
+                !input.ends_with('\\')
+            }
+        }
+        let h = History::new();
+        for &mode in &[EditMode::Emacs, EditMode::Vi] {
+            let cfg = Config { continuation_prompt: "> ".to_owned(), ..config(mode) };
+            let mut io = ChunkIO::new(&["foo \\", "\x0d", "bar", "\x0d"]);
+            let mut ctx = EditCtx::new("$ ", &h, &cfg);
+            ctx.set_validator(Some(&Backslash));
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("foo \\\nbar".to_string()));
+            let output = String::from_utf8(io.output).unwrap();
+            assert!(output.contains("\r$ foo \\\x1b[0K\r\x1b[7C\r\x1b[7C\x1b[0K\r\n\r> \x1b[0K\r\x1b[2C"));
+        }
+        // C-c abandons all lines of the input
+        let cfg = Config { interrupt: InterruptAction::ClearLine, ..config(EditMode::Emacs) };
+        let mut io = ChunkIO::new(&["foo \\", "\x0d", "\x03", "bar", "\x0d"]);
+        let mut ctx = EditCtx::new("$ ", &h, &cfg);
+        ctx.set_validator(Some(&Backslash));
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("bar".to_string()));
+    }
+
+    #[test]
+    fn shared_clipboard() {
+        let h = History::new();
+        // text killed by C-w is pasted by p
+        let mut cfg = config(EditMode::Vi);
+        cfg.bind(Token::CtrlW, Instr::KillWordLeft);
+        let mut io = ChunkIO::new(&["foo bar", "\x17", "\x1b", "p", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("foo bar".to_string()));
+        // and text deleted by dw is yanked by C-y
+        cfg.bind(Token::CtrlY, Instr::Yank);
+        let mut io = ChunkIO::new(&["foo bar", "\x1b", "0", "d", "w", "A", " ", "\x19", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("bar foo ".to_string()));
+        let mut cfg = config(EditMode::Emacs);
+        cfg.bind(Token::Meta('p'), Instr::PasteBefore);
+        let mut io = ChunkIO::new(&["foo bar", "\x17", "\x01", "\x1bp", "\x0d"]);
+        let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+        assert_eq!(run_edit(&mut ctx, &mut io), Ok("barfoo ".to_string()));
+    }
+
+    #[test]
+    fn echo_mode() {
+        struct FixedHinter;
+        impl Hinter for FixedHinter {
+            fn hint(&self, _line: &str, _pos: usize) -> Option<String> {
+                Some(" hint".to_owned())
+            }
+        }
+        let h = History::new();
+        for &(echo, shown) in &[(EchoMode::Mask('*'), "\rfoo> ****\x1b[0K\r\x1b[8C"), (EchoMode::None, "\rfoo> \x1b[0K\r\x1b[5C")] {
+            let cfg = Config { echo: echo, redraw_on_accept: true, ..config(EditMode::Emacs) };
+            let mut io = ChunkIO::new(&["pass", "\x02", "\x02", "\x0d"]);
+            let mut ctx = EditCtx::new("foo> ", &h, &cfg);
+            ctx.set_hinter(Some(&FixedHinter));
+            assert_eq!(run_edit(&mut ctx, &mut io), Ok("pass".to_string()));
+            let output = String::from_utf8(io.output).unwrap();
+            assert!(!output.contains("pa"));
+            assert!(!output.contains("hint"));
+            assert!(output.contains(shown));
+        }
+    }
 }
@@ -1,4 +1,10 @@
 use std::os::unix::io::RawFd;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{self, Command};
 
 use std;
 use libc;
@@ -6,6 +12,8 @@ use nix;
 use nix::errno::Errno;
 use nix::unistd::{read, write};
 use nix::fcntl::{flock, FlockArg};
+use nix::poll;
+use nix::sys::signal;
 use nix::sys::termios;
 use nix::sys::termios::{BRKINT, ICRNL, INPCK, ISTRIP, IXON, OPOST, CS8, ECHO, ICANON, IEXTEN, ISIG, VMIN, VTIME};
 
@@ -17,6 +25,18 @@ pub struct TermIO<'a> {
     out_raw: RawMode
 }
 
+impl<'a> TermIO<'a> {
+    /// Leaves raw mode, reporting any failure to reinstate the original
+    /// terminal settings.
+    ///
+    /// Dropping the `TermIO` restores the terminal as well (including
+    /// while unwinding from a panic), but has to discard such errors.
+    pub fn restore(mut self) -> Result<(), Error> {
+        try!(self.out_raw.restore());
+        Ok(())
+    }
+}
+
 impl<'a> RunIO for TermIO<'a> {
     fn write(&mut self, w: Vec<u8>) -> Result<(), Error> {
         try!(self.out_raw.write(&w));
@@ -26,6 +46,12 @@ impl<'a> RunIO for TermIO<'a> {
         let read = try!(self.in_term.read_byte());
         read.ok_or(Error::EndOfFile)
     }
+    fn read_byte_timeout(&mut self, timeout_ms: u32) -> Result<Option<u8>, Error> {
+        if !try!(self.in_term.poll_input(timeout_ms)) {
+            return Ok(None);
+        }
+        self.read_byte().map(Some)
+    }
     fn read_seq(&mut self) -> Result<Vec<u8>, Error> {
         let read = try!(self.in_term.read_seq());
         if read.len() == 0 {
@@ -35,11 +61,50 @@ impl<'a> RunIO for TermIO<'a> {
             Ok(read)
         }
     }
+    fn suspend(&mut self) -> Result<(), Error> {
+        try!(self.out_raw.suspend());
+        Ok(())
+    }
+    fn edit_externally(&mut self, line: &str) -> Result<Option<String>, Error> {
+        let (path, mut file) = try!(create_temp_file());
+        try!(file.write_all(line.as_bytes()));
+        drop(file);
+        // like bash, $VISUAL is preferred, and may contain arguments
+        let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or("vi".to_owned());
+        let status = try!(self.out_raw.with_original(|| {
+            Command::new("sh").arg("-c").arg(format!("{} \"$1\"", editor)).arg("sh").arg(&path).status()
+        }));
+        let mut edited = String::new();
+        let read = File::open(&path).and_then(|mut file| file.read_to_string(&mut edited));
+        let _ = fs::remove_file(&path);
+        // an editor that fails, such as vim after `:cq`, leaves the line as it was
+        if !try!(status).success() {
+            return Ok(None);
+        }
+        try!(read);
+        Ok(Some(edited))
+    }
+}
+
+/// Creates a new file in the temporary directory that only the user can
+/// read, never opening a file or symlink that is already there.
+fn create_temp_file() -> io::Result<(PathBuf, File)> {
+    let mut attempt = 0;
+    loop {
+        let path = env::temp_dir().join(format!("copperline-{}-{}.txt", process::id(), attempt));
+        match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            Err(err) => return Err(err)
+        }
+    }
 }
 
 pub struct RawMode {
     fd: RawFd,
-    original_termios: termios::Termios
+    original_termios: termios::Termios,
+    raw_termios: termios::Termios,
+    restored: bool
 }
 
 impl RawMode {
@@ -61,23 +126,83 @@ impl RawMode {
 
         Ok(RawMode{
             fd: fd,
-            original_termios: original_termios
+            original_termios: original_termios,
+            raw_termios: raw,
+            restored: false
         })
     }
 
     pub fn write(&mut self, bytes: &[u8]) -> Result<usize, nix::Error> {
-        write(self.fd, bytes)
+        retry_on_eintr(|| write(self.fd, bytes))
+    }
+
+    /// Sends `SIGTSTP` to the process with the original termios in place,
+    /// and reenters raw mode once the process is continued.
+    pub fn suspend(&mut self) -> Result<(), nix::Error> {
+        try!(termios::tcsetattr(self.fd, termios::TCSAFLUSH, &self.original_termios));
+        try!(signal::raise(signal::SIGTSTP));
+        try!(termios::tcsetattr(self.fd, termios::TCSAFLUSH, &self.raw_termios));
+        Ok(())
+    }
+
+    /// Runs `f` with the original termios in place, such as to hand the
+    /// terminal over to another program, and reenters raw mode afterwards.
+    pub fn with_original<F, T>(&mut self, f: F) -> Result<T, nix::Error> where F: FnOnce() -> T {
+        try!(termios::tcsetattr(self.fd, termios::TCSAFLUSH, &self.original_termios));
+        let res = f();
+        try!(termios::tcsetattr(self.fd, termios::TCSAFLUSH, &self.raw_termios));
+        Ok(res)
+    }
+
+    /// Reinstates the original termios and releases the lock.
+    ///
+    /// Both steps are attempted even if the first one fails; the first
+    /// error is returned.
+    pub fn restore(&mut self) -> Result<(), nix::Error> {
+        if self.restored {
+            return Ok(());
+        }
+        self.restored = true;
+        let res = termios::tcsetattr(self.fd, termios::TCSAFLUSH, &self.original_termios);
+        let unlock = flock(self.fd, FlockArg::Unlock);
+        try!(res);
+        try!(unlock);
+        Ok(())
     }
 
 }
 
 impl Drop for RawMode {
     fn drop(&mut self) {
-        let _ = termios::tcsetattr(self.fd, termios::TCSAFLUSH, &self.original_termios);
-        let _ = flock(self.fd, FlockArg::Unlock);
+        let _ = self.restore();
     }
 }
 
+/// Calls `f` again for as long as it is interrupted by a signal, such as
+/// `SIGWINCH` when the terminal is resized.
+fn retry_on_eintr<T, F>(mut f: F) -> Result<T, nix::Error> where F: FnMut() -> Result<T, nix::Error> {
+    loop {
+        match f() {
+            Err(nix::Error::Sys(Errno::EINTR)) => {}
+            res => return res
+        }
+    }
+}
+
+#[repr(C)]
+struct WinSize {
+    ws_row: libc::c_ushort,
+    ws_col: libc::c_ushort,
+    ws_xpixel: libc::c_ushort,
+    ws_ypixel: libc::c_ushort
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const TIOCGWINSZ: libc::c_ulong = 0x5413;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const TIOCGWINSZ: libc::c_ulong = 0x40087468;
+
+#[derive(Clone)]
 pub struct Term {
     in_fd: RawFd,
     out_fd: RawFd
@@ -85,6 +210,30 @@ pub struct Term {
 
 static UNSUPPORTED_TERM: [&'static str; 3] = ["dumb","cons25","emacs"];
 
+/// Whether the terminal named by `$TERM` is not supported, unless the
+/// support is forced either way.
+fn is_unsupported(term: Option<&str>, support: TermSupport) -> bool {
+    match support {
+        TermSupport::Detect => match term {
+            Some(term) => UNSUPPORTED_TERM.contains(&term),
+            None => false
+        },
+        TermSupport::ForceEnable => false,
+        TermSupport::ForceDisable => true
+    }
+}
+
+/// Whether the terminal supports line editing.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TermSupport {
+    /// Decide based on `$TERM`.
+    Detect,
+    /// Assume the terminal supports line editing.
+    ForceEnable,
+    /// Assume the terminal does not support line editing.
+    ForceDisable
+}
+
 impl Term {
 
     pub fn new(in_fd: RawFd, out_fd: RawFd) -> Term {
@@ -94,17 +243,29 @@ impl Term {
         }
     }
 
-    pub fn is_unsupported_term() -> bool {
-        match std::env::var("TERM") {
-            Ok(term) => UNSUPPORTED_TERM.iter().any(|x| &&term == x),
-            Err(_) => false
-        }
+    pub fn is_unsupported_term(support: TermSupport) -> bool {
+        is_unsupported(std::env::var("TERM").ok().as_deref(), support)
     }
 
     pub fn is_a_tty(&self) -> bool {
         unsafe { libc::isatty(self.out_fd) != 0 }
     }
 
+    pub fn is_input_a_tty(&self) -> bool {
+        unsafe { libc::isatty(self.in_fd) != 0 }
+    }
+
+    /// The number of columns of the terminal, if it can be determined.
+    pub fn width(&self) -> Option<usize> {
+        let mut size = WinSize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+        let res = unsafe { nix::sys::ioctl::ioctl(self.out_fd, TIOCGWINSZ, &mut size as *mut WinSize) };
+        if res < 0 || size.ws_col == 0 {
+            None
+        } else {
+            Some(size.ws_col as usize)
+        }
+    }
+
     pub fn acquire_io<'a>(&'a mut self) -> Result<TermIO<'a>, Error> {
         if !self.is_a_tty() {
             return Err(Error::from(nix::Error::from_errno(Errno::ENOTTY)));
@@ -113,19 +274,28 @@ impl Term {
         Ok(TermIO { in_term: self, out_raw: raw })
     }
 
+    /// Wait up to the timeout in milliseconds for input to arrive,
+    /// returning whether there is any.
+    pub fn poll_input(&mut self, timeout_ms: u32) -> Result<bool, nix::Error> {
+        let mut fds = [poll::PollFd { fd: self.in_fd, events: poll::POLLIN, revents: poll::EventFlags::empty() }];
+        let n = try!(retry_on_eintr(|| poll::poll(&mut fds, timeout_ms as libc::c_int)));
+        Ok(n > 0)
+    }
+
     pub fn read_byte(&mut self) -> Result<Option<u8>, nix::Error> {
         let mut input: [u8; 1] = [0; 1];
-        let n = try!(read(self.in_fd, &mut input));
+        let n = try!(retry_on_eintr(|| read(self.in_fd, &mut input)));
         if n == 0 {
             return Ok(None);
         }
         Ok(Some(input[0]))
     }
 
-    /// Attempt to read 3 bytes from the terminal.
+    /// Attempt to read up to 1024 bytes from the terminal, so that pasted
+    /// text arrives in as few reads as possible.
     pub fn read_seq(&mut self) -> Result<Vec<u8>, nix::Error> {
-        let mut input = vec![0u8; 3];
-        let n = try!(read(self.in_fd, &mut input[..]));
+        let mut input = vec![0u8; 1024];
+        let n = try!(retry_on_eintr(|| read(self.in_fd, &mut input[..])));
         unsafe {
             assert!(n <= input.len());
             input.set_len(n);
@@ -134,3 +304,116 @@ impl Term {
     }
 
 }
+
+#[test]
+fn unsupported_term_override() {
+    assert!(is_unsupported(Some("dumb"), TermSupport::Detect));
+    assert!(!is_unsupported(Some("dumb"), TermSupport::ForceEnable));
+    assert!(!is_unsupported(Some("xterm"), TermSupport::Detect));
+    assert!(is_unsupported(Some("xterm"), TermSupport::ForceDisable));
+    assert!(!is_unsupported(None, TermSupport::Detect));
+}
+
+#[cfg(test)]
+fn open_pty() -> (std::fs::File, std::fs::File) {
+    use std::ffi::CStr;
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::io::FromRawFd;
+    extern "C" {
+        fn posix_openpt(flags: libc::c_int) -> libc::c_int;
+        fn grantpt(fd: libc::c_int) -> libc::c_int;
+        fn unlockpt(fd: libc::c_int) -> libc::c_int;
+        fn ptsname(fd: libc::c_int) -> *const libc::c_char;
+    }
+    unsafe {
+        let master = posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        assert!(master >= 0);
+        assert_eq!(grantpt(master), 0);
+        assert_eq!(unlockpt(master), 0);
+        let name = CStr::from_ptr(ptsname(master)).to_str().unwrap().to_owned();
+        let slave = OpenOptions::new().read(true).write(true).open(name).unwrap();
+        (File::from_raw_fd(master), slave)
+    }
+}
+
+#[test]
+fn restore_termios_on_panic() {
+    use std::os::unix::io::AsRawFd;
+    let (_master, slave) = open_pty();
+    let fd = slave.as_raw_fd();
+    let original = termios::tcgetattr(fd).unwrap();
+    let res = std::panic::catch_unwind(move || {
+        let mut term = Term::new(fd, fd);
+        let _io = term.acquire_io().unwrap();
+        let raw = termios::tcgetattr(fd).unwrap();
+        assert!(!raw.c_lflag.contains(ECHO | ICANON));
+        panic!("editing failed");
+    });
+    assert!(res.is_err());
+    let restored = termios::tcgetattr(fd).unwrap();
+    assert_eq!(restored.c_iflag, original.c_iflag);
+    assert_eq!(restored.c_oflag, original.c_oflag);
+    assert_eq!(restored.c_lflag, original.c_lflag);
+    assert_eq!(restored.c_cc, original.c_cc);
+}
+
+#[test]
+fn restore_termios_explicitly() {
+    use std::os::unix::io::AsRawFd;
+    let (_master, slave) = open_pty();
+    let fd = slave.as_raw_fd();
+    let original = termios::tcgetattr(fd).unwrap();
+    let mut term = Term::new(fd, fd);
+    let io = term.acquire_io().unwrap();
+    assert_eq!(io.restore(), Ok(()));
+    assert_eq!(termios::tcgetattr(fd).unwrap().c_lflag, original.c_lflag);
+}
+
+#[test]
+fn terminal_width() {
+    use std::os::unix::io::AsRawFd;
+    let (_master, slave) = open_pty();
+    let fd = slave.as_raw_fd();
+    let size = WinSize { ws_row: 24, ws_col: 100, ws_xpixel: 0, ws_ypixel: 0 };
+    const TIOCSWINSZ: libc::c_ulong = if cfg!(any(target_os = "linux", target_os = "android")) { 0x5414 } else { 0x80087467 };
+    assert_eq!(unsafe { nix::sys::ioctl::ioctl(fd, TIOCSWINSZ, &size as *const WinSize) }, 0);
+    assert_eq!(Term::new(fd, fd).width(), Some(100));
+}
+
+#[test]
+fn retry_interrupted_calls() {
+    let mut calls = 0;
+    let res = retry_on_eintr(|| {
+        calls += 1;
+        if calls < 3 {
+            Err(nix::Error::from_errno(Errno::EINTR))
+        } else {
+            Ok(calls)
+        }
+    });
+    assert_eq!(res, Ok(3));
+    // other errors are returned right away
+    let mut calls = 0;
+    let res: Result<(), _> = retry_on_eintr(|| {
+        calls += 1;
+        Err(nix::Error::from_errno(Errno::EIO))
+    });
+    assert_eq!(res, Err(nix::Error::from_errno(Errno::EIO)));
+    assert_eq!(calls, 1);
+}
+
+#[test]
+fn temp_file_is_private() {
+    use std::os::unix::fs::PermissionsExt;
+    let (taken, mut file) = create_temp_file().unwrap();
+    file.write_all(b"taken").unwrap();
+    // a file that is already there is neither opened nor truncated
+    let (path, _) = create_temp_file().unwrap();
+    assert!(path != taken);
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    let mut content = String::new();
+    File::open(&taken).unwrap().read_to_string(&mut content).unwrap();
+    assert_eq!(content, "taken");
+    let _ = fs::remove_file(&taken);
+    let _ = fs::remove_file(&path);
+}
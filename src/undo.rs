@@ -0,0 +1,80 @@
+use std::collections::VecDeque;
+
+use buffer::Snapshot;
+
+/// The maximum number of changes that can be undone.
+const MAX_DEPTH: usize = 100;
+
+pub struct UndoStack {
+    stack: VecDeque<Snapshot>,
+    coalescing: bool
+}
+
+impl UndoStack {
+
+    pub fn new() -> UndoStack {
+        UndoStack {
+            stack: VecDeque::new(),
+            coalescing: false
+        }
+    }
+
+    /// Records the state of the buffer before a change.
+    ///
+    /// If both this and the previously recorded change are marked as
+    /// `coalesce`, they are merged into a single undo step.
+    pub fn push(&mut self, snapshot: Snapshot, coalesce: bool) {
+        if !(coalesce && self.coalescing) {
+            self.stack.push_back(snapshot);
+            if self.stack.len() > MAX_DEPTH {
+                self.stack.pop_front();
+            }
+        }
+        self.coalescing = coalesce;
+    }
+
+    /// Ends the current run of coalesced changes.
+    pub fn seal(&mut self) {
+        self.coalescing = false;
+    }
+
+    pub fn pop(&mut self) -> Option<Snapshot> {
+        self.coalescing = false;
+        self.stack.pop_back()
+    }
+
+}
+
+#[cfg(test)]
+fn depth(undo: &mut UndoStack) -> usize {
+    let mut n = 0;
+    while undo.pop().is_some() {
+        n += 1;
+    }
+    n
+}
+
+#[test]
+fn coalesce_consecutive() {
+    use buffer::Buffer;
+    let mut undo = UndoStack::new();
+    let buf = Buffer::new();
+    undo.push(buf.snapshot(), true);
+    undo.push(buf.snapshot(), true);
+    undo.push(buf.snapshot(), false);
+    undo.push(buf.snapshot(), true);
+    undo.seal();
+    undo.push(buf.snapshot(), true);
+    assert_eq!(depth(&mut undo), 4);
+}
+
+#[test]
+fn depth_is_capped() {
+    use buffer::Buffer;
+    let mut undo = UndoStack::new();
+    let buf = Buffer::new();
+    for _ in 0..MAX_DEPTH + 10 {
+        undo.push(buf.snapshot(), false);
+    }
+    assert_eq!(depth(&mut undo), MAX_DEPTH);
+}
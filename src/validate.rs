@@ -0,0 +1,9 @@
+/// Decides whether a line is finished when Enter is pressed, so that
+/// input spanning several lines can be read, such as by a REPL.
+pub trait Validator {
+    /// Returns whether the input is complete. If it is not, editing
+    /// continues on a new line after the continuation prompt.
+    ///
+    /// `input` consists of the lines entered so far, joined by `\n`.
+    fn is_complete(&self, input: &str) -> bool;
+}